@@ -1,9 +1,17 @@
-use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
-use kvs::{KvStore, KvsEngine, SledKvsEngine};
+use criterion::measurement::WallTime;
+use criterion::{
+    criterion_group, criterion_main, BatchSize, BenchmarkGroup, BenchmarkId, Criterion, Throughput,
+};
+use kvs::{KvStore, KvsEngine, LockFreeKvsEngine, SledKvsEngine};
 use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
+use std::thread;
 use tempfile::TempDir;
 
+/// Keys each concurrent benchmark reads or writes, split evenly between the
+/// threads.
+const CONCURRENT_KEYS: usize = 1024;
+
 fn write_benches(c: &mut Criterion) {
     let mut group = c.benchmark_group("write_bench");
     group
@@ -16,23 +24,11 @@ fn write_benches(c: &mut Criterion) {
                 let kvs = KvStore::open(TempDir::new().unwrap().path()).unwrap();
                 let keys = (0..100)
                     .map(|_| thread_rng().gen_range(1, 10000))
-                    .map(|i| {
-                        thread_rng()
-                            .sample_iter(&Alphanumeric)
-                            .take(i)
-                            .map(char::from)
-                            .collect()
-                    })
+                    .map(|i| thread_rng().sample_iter(&Alphanumeric).take(i).collect())
                     .collect::<Vec<String>>();
                 let values = (0..100)
                     .map(|_| thread_rng().gen_range(1, 10000))
-                    .map(|i| {
-                        thread_rng()
-                            .sample_iter(&Alphanumeric)
-                            .take(i)
-                            .map(char::from)
-                            .collect()
-                    })
+                    .map(|i| thread_rng().sample_iter(&Alphanumeric).take(i).collect())
                     .collect::<Vec<String>>();
                 (kvs, keys, values)
             },
@@ -52,23 +48,11 @@ fn write_benches(c: &mut Criterion) {
                 let sled = SledKvsEngine::new(sled::open(&path).unwrap());
                 let keys = (0..100)
                     .map(|_| thread_rng().gen_range(1, 10000))
-                    .map(|i| {
-                        thread_rng()
-                            .sample_iter(&Alphanumeric)
-                            .take(i)
-                            .map(char::from)
-                            .collect()
-                    })
+                    .map(|i| thread_rng().sample_iter(&Alphanumeric).take(i).collect())
                     .collect::<Vec<String>>();
                 let values = (0..100)
                     .map(|_| thread_rng().gen_range(1, 10000))
-                    .map(|i| {
-                        thread_rng()
-                            .sample_iter(&Alphanumeric)
-                            .take(i)
-                            .map(char::from)
-                            .collect()
-                    })
+                    .map(|i| thread_rng().sample_iter(&Alphanumeric).take(i).collect())
                     .collect::<Vec<String>>();
                 (sled, keys, values, path)
             },
@@ -97,23 +81,11 @@ fn read_benches(c: &mut Criterion) {
                 let kvs = KvStore::open(path.path()).unwrap();
                 let mut keys = (0..3000)
                     .map(|_| thread_rng().gen_range(1, 10000))
-                    .map(|i| {
-                        thread_rng()
-                            .sample_iter(&Alphanumeric)
-                            .take(i)
-                            .map(char::from)
-                            .collect()
-                    })
+                    .map(|i| thread_rng().sample_iter(&Alphanumeric).take(i).collect())
                     .collect::<Vec<String>>();
                 let values = (0..3000)
                     .map(|_| thread_rng().gen_range(1, 10000))
-                    .map(|i| {
-                        thread_rng()
-                            .sample_iter(&Alphanumeric)
-                            .take(i)
-                            .map(char::from)
-                            .collect()
-                    })
+                    .map(|i| thread_rng().sample_iter(&Alphanumeric).take(i).collect())
                     .collect::<Vec<String>>();
                 for i in 0..3000 {
                     kvs.set(keys[i].clone(), values[i].clone()).unwrap();
@@ -140,23 +112,11 @@ fn read_benches(c: &mut Criterion) {
                 let sled = SledKvsEngine::new(sled::open(&path).unwrap());
                 let mut keys = (0..3000)
                     .map(|_| thread_rng().gen_range(1, 10000))
-                    .map(|i| {
-                        thread_rng()
-                            .sample_iter(&Alphanumeric)
-                            .take(i)
-                            .map(char::from)
-                            .collect()
-                    })
+                    .map(|i| thread_rng().sample_iter(&Alphanumeric).take(i).collect())
                     .collect::<Vec<String>>();
                 let values = (0..3000)
                     .map(|_| thread_rng().gen_range(1, 10000))
-                    .map(|i| {
-                        thread_rng()
-                            .sample_iter(&Alphanumeric)
-                            .take(i)
-                            .map(char::from)
-                            .collect()
-                    })
+                    .map(|i| thread_rng().sample_iter(&Alphanumeric).take(i).collect())
                     .collect::<Vec<String>>();
                 for i in 0..3000 {
                     sled.set(keys[i].clone(), values[i].clone()).unwrap();
@@ -179,5 +139,75 @@ fn read_benches(c: &mut Criterion) {
     group.finish();
 }
 
-criterion_group!(engine, write_benches, read_benches);
+/// The same amount of work spread over more and more threads, to show how
+/// each engine scales.
+fn concurrent_benches(c: &mut Criterion) {
+    let mut group = c.benchmark_group("concurrent_bench");
+    group
+        .significance_level(0.1)
+        .sample_size(20)
+        .throughput(Throughput::Elements(CONCURRENT_KEYS as u64));
+    let dir = TempDir::new().unwrap();
+    bench_concurrent(&mut group, KvStore::open(dir.path().join("kvs")).unwrap());
+    bench_concurrent(
+        &mut group,
+        SledKvsEngine::new(sled::open(dir.path().join("sled")).unwrap()),
+    );
+    bench_concurrent(&mut group, LockFreeKvsEngine::new().unwrap());
+    group.finish();
+}
+
+fn bench_concurrent<E: KvsEngine>(group: &mut BenchmarkGroup<WallTime>, engine: E) {
+    let keys: Vec<String> = (0..CONCURRENT_KEYS).map(|i| format!("key{}", i)).collect();
+    let value = "v".repeat(100);
+    for key in &keys {
+        engine.set(key.clone(), value.clone()).unwrap();
+    }
+    for threads in [1, 2, 4, 8] {
+        group.bench_with_input(
+            BenchmarkId::new(format!("{}_get", E::NAME), threads),
+            &threads,
+            |b, &threads| {
+                b.iter(|| {
+                    run_concurrently(&engine, &keys, threads, |engine, key| {
+                        engine.get(key.clone()).unwrap().unwrap();
+                    })
+                })
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::new(format!("{}_set", E::NAME), threads),
+            &threads,
+            |b, &threads| {
+                b.iter(|| {
+                    run_concurrently(&engine, &keys, threads, |engine, key| {
+                        engine.set(key.clone(), value.clone()).unwrap();
+                    })
+                })
+            },
+        );
+    }
+}
+
+/// Run `op` on every key, with `threads` threads taking an equal share each.
+fn run_concurrently<E: KvsEngine>(
+    engine: &E,
+    keys: &[String],
+    threads: usize,
+    op: impl Fn(&E, &String) + Sync,
+) {
+    let op = &op;
+    thread::scope(|scope| {
+        for chunk in keys.chunks(keys.len() / threads) {
+            let engine = engine.clone();
+            scope.spawn(move || {
+                for key in chunk {
+                    op(&engine, key);
+                }
+            });
+        }
+    });
+}
+
+criterion_group!(engine, write_benches, read_benches, concurrent_benches);
 criterion_main!(engine);
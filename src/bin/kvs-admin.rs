@@ -0,0 +1,370 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, BufWriter},
+    ops::Bound,
+    path::{Path, PathBuf},
+    process::exit,
+};
+
+use clap::{Parser, Subcommand};
+use kvs::{
+    kvs::{
+        backup,
+        dir_lock::DirLock,
+        dump::{self, DumpFormat},
+        metadata::EngineMetadata,
+    },
+    net::client::KvClient,
+    KvStore, KvsEngine, KvsError, KvsSnapshot, Result, SledKvsEngine,
+};
+
+/// Pairs compared at a time when verifying a migration.
+const VERIFY_CHUNK_LEN: usize = 1024;
+
+/// How far apart, in milliseconds, the expiry times of a key and its
+/// migrated copy may be.
+const EXPIRY_TOLERANCE_MS: u64 = 1000;
+
+#[derive(Parser)]
+#[command(version)]
+struct Cli {
+    #[command(subcommand)]
+    command: Commands,
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    /// Have a running server write a backup, while it keeps serving.
+    Backup {
+        /// Where the server writes the backup, relative to its
+        /// `--backup-dir`.
+        path: String,
+        #[arg(long, value_name = "IP-PORT", default_value = "127.0.0.1:4000")]
+        addr: String,
+    },
+    /// Check a backup and load it into a fresh data directory.
+    Restore {
+        backup: PathBuf,
+        /// The data directory of `kvs-server`.
+        #[arg(long = "data-dir", value_name = "DIR", default_value = ".")]
+        dir: PathBuf,
+        /// Engine to restore into. Defaults to the one the backup was taken
+        /// from.
+        #[arg(long, value_name = "ENGINE-NAME")]
+        engine: Option<String>,
+    },
+    /// Write every pair in a data directory to a dump. The server must be
+    /// stopped.
+    Dump {
+        /// The data directory of `kvs-server`.
+        #[arg(long = "data-dir", value_name = "DIR", default_value = ".")]
+        dir: PathBuf,
+        /// json or binary.
+        #[arg(long, value_name = "FORMAT", default_value_t = DumpFormat::default())]
+        format: DumpFormat,
+        /// File to write the dump to, instead of standard output.
+        #[arg(long, value_name = "FILE")]
+        output: Option<PathBuf>,
+    },
+    /// Load a dump, in either format, into a data directory. The server must
+    /// be stopped.
+    Import {
+        input: PathBuf,
+        /// The data directory of `kvs-server`.
+        #[arg(long = "data-dir", value_name = "DIR", default_value = ".")]
+        dir: PathBuf,
+        /// Engine to import into, when the directory holds no data yet.
+        #[arg(long, value_name = "ENGINE-NAME", default_value = "kvs")]
+        engine: String,
+    },
+    /// Convert a data directory from one engine to the other, checking every
+    /// pair before the old data is removed. The server must be stopped.
+    Migrate {
+        #[arg(long, value_name = "ENGINE-NAME")]
+        from: String,
+        #[arg(long, value_name = "ENGINE-NAME")]
+        to: String,
+        /// The data directory of `kvs-server`.
+        #[arg(long = "data-dir", value_name = "DIR", default_value = ".")]
+        dir: PathBuf,
+    },
+}
+
+fn main() {
+    let cli = Cli::parse();
+    match cli.command {
+        Commands::Backup { path, addr } => {
+            match KvClient::new(addr).and_then(|mut client| client.backup(path.clone())) {
+                Ok(pairs) => println!("Backed up {} pairs to {}", pairs, path),
+                Err(e) => fail(e),
+            }
+        }
+        Commands::Restore {
+            backup,
+            dir,
+            engine,
+        } => {
+            let info = match backup::verify(&backup) {
+                Ok(info) => info,
+                Err(e) => fail(e),
+            };
+            let _lock = lock_data_dir(&dir, true);
+            if let Some(existing) = existing_engine(&dir) {
+                eprintln!("{} already holds {} data", dir.display(), existing);
+                exit(1);
+            }
+            let engine = engine.unwrap_or(info.engine);
+            let target = dir.join(&engine);
+            let restored = match engine.as_str() {
+                KvStore::NAME => open_kvs(&target).and_then(|store| restore(&backup, store)),
+                SledKvsEngine::NAME => open_sled(&target).and_then(|db| restore(&backup, db)),
+                _ => unsupported(),
+            }
+            .and_then(|pairs| {
+                EngineMetadata::new(&engine).store(&dir)?;
+                Ok(pairs)
+            });
+            match restored {
+                Ok(pairs) => println!("Restored {} pairs into {}", pairs, target.display()),
+                Err(e) => fail(e),
+            }
+        }
+        Commands::Dump {
+            dir,
+            format,
+            output,
+        } => {
+            let _lock = lock_data_dir(&dir, false);
+            let engine = existing_engine(&dir).unwrap_or_else(|| {
+                eprintln!("{} holds no data", dir.display());
+                exit(1);
+            });
+            let result = match engine.as_str() {
+                KvStore::NAME => open_kvs(&dir.join(&engine))
+                    .and_then(|store| dump_to(&store, format, output.as_deref())),
+                _ => open_sled(&dir.join(&engine))
+                    .and_then(|db| dump_to(&db, format, output.as_deref())),
+            };
+            match result {
+                Ok(pairs) => eprintln!("Dumped {} pairs", pairs),
+                Err(e) => fail(e),
+            }
+        }
+        Commands::Import { input, dir, engine } => {
+            let _lock = lock_data_dir(&dir, true);
+            let existing = existing_engine(&dir);
+            match &existing {
+                Some(existing) if *existing != engine => {
+                    eprintln!("{} already holds {} data", dir.display(), existing);
+                    exit(1);
+                }
+                _ => {}
+            }
+            let target = dir.join(&engine);
+            let imported = File::open(&input)
+                .map_err(KvsError::from)
+                .and_then(|file| match engine.as_str() {
+                    KvStore::NAME => open_kvs(&target)
+                        .and_then(|store| dump::import(&store, BufReader::new(file))),
+                    SledKvsEngine::NAME => {
+                        open_sled(&target).and_then(|db| dump::import(&db, BufReader::new(file)))
+                    }
+                    _ => unsupported(),
+                })
+                .and_then(|pairs| {
+                    if existing.is_none() {
+                        EngineMetadata::new(&engine).store(&dir)?;
+                    }
+                    Ok(pairs)
+                });
+            match imported {
+                Ok(pairs) => println!("Imported {} pairs into {}", pairs, target.display()),
+                Err(e) => fail(e),
+            }
+        }
+        Commands::Migrate { from, to, dir } => {
+            let _lock = lock_data_dir(&dir, false);
+            if ![
+                (KvStore::NAME, SledKvsEngine::NAME),
+                (SledKvsEngine::NAME, KvStore::NAME),
+            ]
+            .contains(&(from.as_str(), to.as_str()))
+            {
+                eprintln!("Can only migrate between kvs and sled");
+                exit(1);
+            }
+            let existing = EngineMetadata::load(&dir).unwrap_or_else(|e| fail(e));
+            if existing.as_ref().map(|metadata| metadata.engine.as_str()) == Some(to.as_str()) {
+                // An earlier run got as far as switching the metadata file.
+                match finish_migration(&dir, &from, &to) {
+                    Ok(()) => {
+                        println!("{} already migrated from {} to {}", dir.display(), from, to)
+                    }
+                    Err(e) => fail(e),
+                }
+                return;
+            }
+            let Some(metadata) = existing.filter(|metadata| metadata.engine == from) else {
+                eprintln!("{} holds no {} data", dir.display(), from);
+                exit(1);
+            };
+            // Anything left in the new engine's place by an interrupted run
+            // is incomplete, and the source is untouched, so start over.
+            let staging = dir.join(format!("{}.migrating", to));
+            let migrated = remove_dir_if_exists(&staging)
+                .and_then(|()| remove_dir_if_exists(&dir.join(&to)))
+                .and_then(|()| match from.as_str() {
+                    KvStore::NAME => open_kvs(&dir.join(&from))
+                        .and_then(|source| migrate(&dir, &metadata, source, open_sled(&staging)?)),
+                    _ => open_sled(&dir.join(&from))
+                        .and_then(|source| migrate(&dir, &metadata, source, open_kvs(&staging)?)),
+                });
+            match migrated {
+                Ok(pairs) => println!("Migrated {} pairs from {} to {}", pairs, from, to),
+                Err(e) => fail(e),
+            }
+        }
+    }
+}
+
+/// Lock the data directory so no server runs on it meanwhile, creating it
+/// first if asked to.
+fn lock_data_dir(dir: &Path, create: bool) -> DirLock {
+    let locked = if create {
+        fs::create_dir_all(dir)
+            .map_err(KvsError::from)
+            .and_then(|()| DirLock::acquire(dir))
+    } else {
+        DirLock::acquire(dir)
+    };
+    locked.unwrap_or_else(|e| fail(e))
+}
+
+/// The engine whose data `dir` holds, according to its metadata file.
+fn existing_engine(dir: &Path) -> Option<String> {
+    match EngineMetadata::load(dir) {
+        Ok(metadata) => metadata.map(|metadata| metadata.engine),
+        Err(e) => fail(e),
+    }
+}
+
+fn open_kvs(path: &Path) -> Result<KvStore> {
+    KvStore::open(path)
+}
+
+fn open_sled(path: &Path) -> Result<SledKvsEngine> {
+    Ok(SledKvsEngine::new(sled::open(path)?))
+}
+
+fn restore<E: KvsEngine>(backup: &Path, engine: E) -> Result<u64> {
+    backup::restore(backup, &engine)
+}
+
+fn dump_to<E: KvsEngine>(engine: &E, format: DumpFormat, output: Option<&Path>) -> Result<u64> {
+    match output {
+        Some(path) => dump::dump(engine, format, BufWriter::new(File::create(path)?)),
+        None => dump::dump(engine, format, BufWriter::new(io::stdout().lock())),
+    }
+}
+
+/// Copy `source` into `target`, which was opened in a staging directory,
+/// through a binary dump, check that they hold the same pairs, and then put
+/// `target` in the place of `source`.
+///
+/// Switching the metadata file is what completes the migration. The source
+/// is left untouched until then, so an interrupted migration can simply be
+/// run again, and a run after the switch only finishes cleaning up.
+fn migrate<A: KvsEngine, B: KvsEngine>(
+    dir: &Path,
+    metadata: &EngineMetadata,
+    source: A,
+    target: B,
+) -> Result<u64> {
+    let dump_path = migration_dump(dir, A::NAME, B::NAME);
+    dump::dump(
+        &source,
+        DumpFormat::Binary,
+        BufWriter::new(File::create(&dump_path)?),
+    )?;
+    dump::import(&target, BufReader::new(File::open(&dump_path)?))?;
+    let pairs = verify(&source, &target)?;
+    drop(source);
+    drop(target);
+
+    fs::rename(
+        dir.join(format!("{}.migrating", B::NAME)),
+        dir.join(B::NAME),
+    )?;
+    // The metadata file decides which engine the server opens, so the old
+    // data is only removed once it points at the new one.
+    metadata.with_engine(B::NAME).store(dir)?;
+    finish_migration(dir, A::NAME, B::NAME)?;
+    Ok(pairs)
+}
+
+/// Remove what a migration from `from` to `to` leaves behind once the
+/// metadata file has been switched.
+fn finish_migration(dir: &Path, from: &str, to: &str) -> Result<()> {
+    remove_dir_if_exists(&dir.join(from))?;
+    match fs::remove_file(migration_dump(dir, from, to)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// The dump a migration from `from` to `to` copies the data through.
+fn migration_dump(dir: &Path, from: &str, to: &str) -> PathBuf {
+    dir.join(format!("{}-to-{}.dump", from, to))
+}
+
+fn remove_dir_if_exists(path: &Path) -> Result<()> {
+    match fs::remove_dir_all(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Check that two engines hold exactly the same pairs, expiring at the same
+/// time, and count them.
+fn verify<A: KvsEngine, B: KvsEngine>(a: &A, b: &B) -> Result<u64> {
+    let (a, b) = (a.snapshot()?, b.snapshot()?);
+    let mut pairs = 0;
+    let mut start = Bound::Unbounded;
+    loop {
+        let left = a.scan_with_expiry_bytes((start.clone(), Bound::Unbounded), VERIFY_CHUNK_LEN)?;
+        let right = b.scan_with_expiry_bytes((start, Bound::Unbounded), VERIFY_CHUNK_LEN)?;
+        let same = left.len() == right.len()
+            && left.iter().zip(&right).all(|(left, right)| {
+                left.0 == right.0 && left.1 == right.1 && same_expiry(left.2, right.2)
+            });
+        if !same {
+            return Err(KvsError::Corruption(
+                "Migrated data does not match the source".to_string(),
+            ));
+        }
+        let Some((last, _, _)) = left.last() else {
+            return Ok(pairs);
+        };
+        pairs += left.len() as u64;
+        start = Bound::Excluded(last.clone());
+    }
+}
+
+/// Whether two expiry times agree. The copy is written with a time to live,
+/// so it can land a little later than the original.
+fn same_expiry(a: Option<u64>, b: Option<u64>) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => a.abs_diff(b) <= EXPIRY_TOLERANCE_MS,
+        (a, b) => a == b,
+    }
+}
+
+fn unsupported<T>() -> T {
+    eprintln!("Engine not supported");
+    exit(1);
+}
+
+fn fail(reason: KvsError) -> ! {
+    eprintln!("{}", reason);
+    exit(1);
+}
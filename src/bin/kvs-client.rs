@@ -1,7 +1,11 @@
-use std::process::exit;
+use std::{ops::Bound, process::exit, time::Duration};
 
 use clap::{Parser, Subcommand};
-use kvs::net::client::KvClient;
+use kvs::{
+    kvs::bytes::prefix_range,
+    net::{client::KvClient, protocol::MAX_SCAN_LIMIT},
+    KvsError,
+};
 
 #[derive(Parser)]
 #[command(version)]
@@ -19,19 +23,58 @@ struct Cli {
 
 #[derive(Subcommand)]
 enum Commands {
-    Set { key: String, value: String },
-    Get { key: String },
-    Rm { key: String },
+    Set {
+        key: String,
+        value: String,
+        /// Remove the key after this many seconds.
+        #[arg(long, value_name = "SECONDS")]
+        ttl: Option<u64>,
+    },
+    Get {
+        key: String,
+    },
+    Rm {
+        key: String,
+    },
+    /// List key/value pairs in key order, one page at a time.
+    Scan {
+        /// Only list keys starting with this prefix.
+        #[arg(long, conflicts_with_all = ["start", "end"])]
+        prefix: Option<String>,
+        /// First key to list.
+        #[arg(long)]
+        start: Option<String>,
+        /// Stop before this key.
+        #[arg(long)]
+        end: Option<String>,
+        /// Continue after this key, as printed at the end of the previous page.
+        #[arg(long)]
+        cursor: Option<String>,
+        /// Largest number of pairs to print. The server sends at most 1000
+        /// at a time.
+        #[arg(long, default_value_t = 100)]
+        limit: usize,
+    },
 }
 
 fn main() {
     let cli = Cli::parse();
-    let mut client = KvClient::new(cli.addr.unwrap_or("127.0.0.1:4000".to_string()));
+    let mut client = match KvClient::new(cli.addr.unwrap_or("127.0.0.1:4000".to_string())) {
+        Ok(client) => client,
+        Err(e) => fail(e),
+    };
     match &cli.command {
-        Commands::Set { key, value } => match client.set(key.to_owned(), value.to_owned()) {
-            Ok(()) => {}
-            Err(e) => fail(e),
-        },
+        Commands::Set { key, value, ttl } => {
+            let result = match ttl {
+                Some(ttl) => {
+                    client.set_with_ttl(key.to_owned(), value.to_owned(), Duration::from_secs(*ttl))
+                }
+                None => client.set(key.to_owned(), value.to_owned()),
+            };
+            if let Err(e) = result {
+                fail(e);
+            }
+        }
         Commands::Get { key } => match client.get(key.to_owned()) {
             Ok(Some(value)) => println!("{}", value),
             Ok(None) => println!("Key not found"),
@@ -41,10 +84,54 @@ fn main() {
             Ok(()) => {}
             Err(e) => fail(e),
         },
+        Commands::Scan {
+            prefix,
+            start,
+            end,
+            cursor,
+            limit,
+        } => {
+            let (start, end) = match prefix {
+                Some(prefix) => prefix_range(prefix.clone().into_bytes()),
+                None => (
+                    start.as_ref().map_or(Bound::Unbounded, |start| {
+                        Bound::Included(start.clone().into_bytes())
+                    }),
+                    end.as_ref().map_or(Bound::Unbounded, |end| {
+                        Bound::Excluded(end.clone().into_bytes())
+                    }),
+                ),
+            };
+            let start = match cursor {
+                Some(cursor) => Bound::Excluded(cursor.clone().into_bytes()),
+                None => start,
+            };
+            // Ask for one extra pair to learn whether another page follows.
+            let fetch = limit.saturating_add(1);
+            let pairs = match client.scan_bytes((start, end), fetch).and_then(|pairs| {
+                pairs
+                    .into_iter()
+                    .map(|(key, value)| Ok((String::from_utf8(key)?, String::from_utf8(value)?)))
+                    .collect::<Result<Vec<_>, KvsError>>()
+            }) {
+                Ok(pairs) => pairs,
+                Err(e) => fail(e),
+            };
+            let shown = pairs.len().min(*limit);
+            for (key, value) in &pairs[..shown] {
+                println!("{}\t{}", key, value);
+            }
+            // A page the server cut short may be followed by more pairs too.
+            if pairs.len() > *limit || pairs.len() == MAX_SCAN_LIMIT {
+                if let Some((key, _)) = shown.checked_sub(1).map(|last| &pairs[last]) {
+                    eprintln!("Next cursor: {}", key);
+                }
+            }
+        }
     }
 }
 
-fn fail(reason: String) -> ! {
+fn fail(reason: KvsError) -> ! {
     eprintln!("{}", reason);
     exit(1);
 }
@@ -1,10 +1,19 @@
-use std::fs::read_dir;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::exit,
+    time::Duration,
+};
 
 use clap::Parser;
 use kvs::{
-    net::server::KvServer,
+    kvs::{dir_lock::DirLock, metadata::EngineMetadata},
+    net::{
+        protocol::DEFAULT_MAX_FRAME_SIZE,
+        server::{KvServer, Protocol},
+    },
     thread_pool::{SharedQueueThreadPool, ThreadPool},
-    KvStore, SledKvsEngine,
+    Durability, KvStore, KvStoreConfig, KvsEngine, LockFreeKvsEngine, SledKvsEngine,
 };
 
 #[derive(Parser)]
@@ -12,69 +21,117 @@ use kvs::{
 struct Cli {
     #[arg(long, value_name = "IP-PORT", default_value = "127.0.0.1:4000")]
     addr: Option<String>,
+    /// kvs, sled, or memory to keep everything in memory and leave the data
+    /// directory alone.
     #[arg(long, value_name = "ENGINE-NAME")]
     engine: Option<String>,
+    /// Directory holding the ENGINE file and the engine's data.
+    #[arg(long, value_name = "DIR", default_value = ".")]
+    data_dir: PathBuf,
+    /// When writes are synced to disk: always, <N>ms, on-close or never.
+    #[arg(long, value_name = "POLICY", default_value_t = Durability::default())]
+    sync: Durability,
+    /// How often expired keys are swept out, in milliseconds.
+    #[arg(long, value_name = "MS", default_value_t = 1000)]
+    sweep_interval: u64,
+    /// Wire protocol: kvs, or resp for Redis clients.
+    #[arg(long, value_name = "PROTOCOL", default_value_t = Protocol::default())]
+    protocol: Protocol,
+    /// Directory clients may have backups written to. Backups are refused
+    /// without one.
+    #[arg(long, value_name = "DIR")]
+    backup_dir: Option<PathBuf>,
+    /// Largest request or reply accepted, in bytes.
+    #[arg(long, value_name = "BYTES", default_value_t = DEFAULT_MAX_FRAME_SIZE)]
+    max_frame_size: u32,
 }
 
 fn main() {
     let cli = Cli::parse();
     eprintln!("version: {}", env!("CARGO_PKG_VERSION"));
     eprintln!("args: {:?}", std::env::args().collect::<Vec<String>>());
-    let mut engine = cli.engine;
-    let old_engine = auto_choose_engine();
-    if engine.is_some() && old_engine.is_some() && engine != old_engine {
-        eprintln!("Engine not match");
-        std::process::exit(1);
-    } else if engine.is_none() && old_engine.is_some() {
-        engine = old_engine;
+    let sweep_interval = Duration::from_millis(cli.sweep_interval);
+    if cli.engine.as_deref() == Some(LockFreeKvsEngine::NAME) {
+        run(
+            LockFreeKvsEngine::with_sweep_interval(sweep_interval).unwrap(),
+            &cli,
+        );
+        return;
     }
-    match engine.as_deref() {
-        Some("kvs") => {
-            let sever = KvServer::new(
-                KvStore::open("kvs".to_string()).unwrap(),
-                cli.addr.unwrap_or("127.0.0.1:4000".to_string()),
-                SharedQueueThreadPool::new(4).unwrap(),
-            );
-
-            sever.run().unwrap();
+    let (metadata, _lock) = match open_data_dir(&cli.data_dir, cli.engine.as_deref()) {
+        Ok(opened) => opened,
+        Err(reason) => {
+            eprintln!("{}", reason);
+            exit(1);
         }
-        Some("sled") => {
-            let sever = KvServer::new(
-                SledKvsEngine::new(sled::open("sled").unwrap()),
-                cli.addr.unwrap_or("127.0.0.1:4000".to_string()),
-                SharedQueueThreadPool::new(4).unwrap(),
-            );
+    };
+    let path = metadata.engine_dir(&cli.data_dir);
+    match metadata.engine.as_str() {
+        KvStore::NAME => run(
+            KvStore::open_with_config(
+                path,
+                KvStoreConfig {
+                    durability: cli.sync,
+                    sweep_interval,
+                    ..KvStoreConfig::default()
+                },
+            )
+            .unwrap(),
+            &cli,
+        ),
+        _ => run(
+            SledKvsEngine::with_sweep_interval(sled::open(path).unwrap(), cli.sync, sweep_interval)
+                .unwrap(),
+            &cli,
+        ),
+    };
+}
 
-            sever.run().unwrap();
-        }
-        Some(_) => {
-            eprintln!("Engine not supported");
-            std::process::exit(1);
-        }
+/// Lock the data directory and check it against `--engine`, setting it up
+/// on first start.
+fn open_data_dir(dir: &Path, engine: Option<&str>) -> Result<(EngineMetadata, DirLock), String> {
+    fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+    let lock = DirLock::acquire(dir).map_err(|e| e.to_string())?;
+    let metadata = match EngineMetadata::adopt(dir).map_err(|e| e.to_string())? {
+        Some(metadata) => match engine {
+            Some(engine) if engine != metadata.engine => Err(format!(
+                "Engine not match: {} holds {} data; convert it with `kvs-admin migrate`",
+                dir.display(),
+                metadata.engine
+            )),
+            _ => Ok(metadata),
+        },
         None => {
-            let sever = KvServer::new(
-                KvStore::open("kvs".to_string()).unwrap(),
-                cli.addr.unwrap_or("127.0.0.1:4000".to_string()),
-                SharedQueueThreadPool::new(4).unwrap(),
-            );
-
-            sever.run().unwrap();
+            let engine = engine.unwrap_or(KvStore::NAME);
+            if ![KvStore::NAME, SledKvsEngine::NAME].contains(&engine) {
+                return Err("Engine not supported".to_string());
+            }
+            let metadata = EngineMetadata::new(engine);
+            metadata.store(dir).map_err(|e| e.to_string())?;
+            Ok(metadata)
         }
-    };
+    }?;
+    Ok((metadata, lock))
 }
 
-fn auto_choose_engine() -> Option<String> {
-    for entry in read_dir(".").unwrap() {
-        let entry = entry.unwrap();
-        let path = entry.path();
-        if path.is_dir() {
-            let path = path.to_str().unwrap();
-            if path.ends_with("sled") {
-                return Some("sled".to_string());
-            } else if path.ends_with("kvs") {
-                return Some("kvs".to_string());
-            }
+fn run<E: KvsEngine>(engine: E, cli: &Cli) {
+    let addr = cli.addr.clone().unwrap_or("127.0.0.1:4000".to_string());
+    let mut sever = KvServer::new(engine, addr, SharedQueueThreadPool::new(4).unwrap()).unwrap();
+    sever.set_protocol(cli.protocol);
+    sever.set_max_frame_size(cli.max_frame_size);
+    if let Some(dir) = &cli.backup_dir {
+        if let Err(e) = fs::create_dir_all(dir) {
+            eprintln!("Cannot create {}: {}", dir.display(), e);
+            exit(1);
         }
+        sever.set_backup_dir(dir);
     }
-    None
+    let handle = sever.shutdown_handle().unwrap();
+    ctrlc::set_handler(move || {
+        eprintln!("Shutting down");
+        handle.shutdown();
+    })
+    .unwrap();
+    sever.run().unwrap();
+    eprintln!("Shut down cleanly");
 }
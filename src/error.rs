@@ -0,0 +1,229 @@
+use std::{error::Error, fmt, io, path::PathBuf, string::FromUtf8Error};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::kvs::bytes::Blob;
+
+/// Errors returned by engines, the network layer and thread pools.
+#[derive(Debug)]
+pub enum KvsError {
+    /// The key does not exist.
+    KeyNotFound,
+    /// An I/O error.
+    Io(io::Error),
+    /// A value could not be serialized or deserialized.
+    Serialization(serde_json::Error),
+    /// An error reported by sled.
+    Sled(sled::Error),
+    /// Stored bytes are not valid UTF-8.
+    Utf8(FromUtf8Error),
+    /// The peer sent something that does not follow the protocol.
+    Protocol(String),
+    /// On-disk data is damaged.
+    Corruption(String),
+    /// A thread pool could not be built.
+    ThreadPool(String),
+    /// A conditional write found a different value than it expected.
+    Conflict { current: Option<Vec<u8>> },
+    /// A transaction read a key that changed before it committed.
+    TransactionConflict { key: Vec<u8> },
+    /// A data directory is in use by another process, or another handle in
+    /// this one. `pid` is the holder's process ID, if it could be read.
+    Locked { path: PathBuf, pid: Option<u32> },
+}
+
+impl fmt::Display for KvsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KvsError::KeyNotFound => write!(f, "Key not found"),
+            KvsError::Io(e) => write!(f, "I/O error: {}", e),
+            KvsError::Serialization(e) => write!(f, "Serialization error: {}", e),
+            KvsError::Sled(e) => write!(f, "Sled error: {}", e),
+            KvsError::Utf8(e) => write!(f, "Invalid UTF-8: {}", e),
+            KvsError::Protocol(msg) => write!(f, "Protocol error: {}", msg),
+            KvsError::Corruption(msg) => write!(f, "Corrupt data: {}", msg),
+            KvsError::ThreadPool(msg) => write!(f, "Thread pool error: {}", msg),
+            KvsError::Conflict {
+                current: Some(value),
+            } => {
+                write!(
+                    f,
+                    "Conflict: current value is {:?}",
+                    String::from_utf8_lossy(value)
+                )
+            }
+            KvsError::Conflict { current: None } => write!(f, "Conflict: key does not exist"),
+            KvsError::TransactionConflict { key } => {
+                write!(
+                    f,
+                    "Transaction conflict: {:?} changed",
+                    String::from_utf8_lossy(key)
+                )
+            }
+            KvsError::Locked {
+                path,
+                pid: Some(pid),
+            } => write!(f, "{} is locked by process {}", path.display(), pid),
+            KvsError::Locked { path, pid: None } => {
+                write!(f, "{} is locked by another process", path.display())
+            }
+        }
+    }
+}
+
+impl Error for KvsError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            KvsError::Io(e) => Some(e),
+            KvsError::Serialization(e) => Some(e),
+            KvsError::Sled(e) => Some(e),
+            KvsError::Utf8(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for KvsError {
+    fn from(e: io::Error) -> Self {
+        KvsError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for KvsError {
+    fn from(e: serde_json::Error) -> Self {
+        KvsError::Serialization(e)
+    }
+}
+
+impl From<sled::Error> for KvsError {
+    fn from(e: sled::Error) -> Self {
+        KvsError::Sled(e)
+    }
+}
+
+impl From<FromUtf8Error> for KvsError {
+    fn from(e: FromUtf8Error) -> Self {
+        KvsError::Utf8(e)
+    }
+}
+
+/// How a `KvsError` travels over the wire.
+///
+/// Errors wrapping a foreign type keep their variant and message, so the
+/// client gets back the same `KvsError` variant the server produced.
+#[derive(Serialize, Deserialize)]
+enum WireError {
+    KeyNotFound,
+    Io(String),
+    Serialization(String),
+    SledCollectionNotFound(Vec<u8>),
+    SledUnsupported(String),
+    SledReportableBug(String),
+    SledIo(String),
+    SledCorruption,
+    Utf8(Vec<u8>),
+    Protocol(String),
+    Corruption(String),
+    ThreadPool(String),
+    Conflict(Option<Blob>),
+    TransactionConflict(Blob),
+    Locked(PathBuf, Option<u32>),
+}
+
+impl Serialize for KvsError {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let wire = match self {
+            KvsError::KeyNotFound => WireError::KeyNotFound,
+            KvsError::Io(e) => WireError::Io(e.to_string()),
+            KvsError::Serialization(e) => WireError::Serialization(e.to_string()),
+            KvsError::Sled(sled::Error::CollectionNotFound(name)) => {
+                WireError::SledCollectionNotFound(name.to_vec())
+            }
+            KvsError::Sled(sled::Error::Unsupported(msg)) => {
+                WireError::SledUnsupported(msg.clone())
+            }
+            KvsError::Sled(sled::Error::ReportableBug(msg)) => {
+                WireError::SledReportableBug(msg.clone())
+            }
+            KvsError::Sled(sled::Error::Io(e)) => WireError::SledIo(e.to_string()),
+            KvsError::Sled(_) => WireError::SledCorruption,
+            KvsError::Utf8(e) => WireError::Utf8(e.as_bytes().to_vec()),
+            KvsError::Protocol(msg) => WireError::Protocol(msg.clone()),
+            KvsError::Corruption(msg) => WireError::Corruption(msg.clone()),
+            KvsError::ThreadPool(msg) => WireError::ThreadPool(msg.clone()),
+            KvsError::Conflict { current } => WireError::Conflict(current.clone().map(Blob)),
+            KvsError::TransactionConflict { key } => {
+                WireError::TransactionConflict(Blob(key.clone()))
+            }
+            KvsError::Locked { path, pid } => WireError::Locked(path.clone(), *pid),
+        };
+        wire.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for KvsError {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        Ok(match WireError::deserialize(deserializer)? {
+            WireError::KeyNotFound => KvsError::KeyNotFound,
+            WireError::Io(msg) => KvsError::Io(io::Error::other(msg)),
+            WireError::Serialization(msg) => KvsError::Serialization(serde::de::Error::custom(msg)),
+            WireError::SledCollectionNotFound(name) => {
+                KvsError::Sled(sled::Error::CollectionNotFound(name.into()))
+            }
+            WireError::SledUnsupported(msg) => KvsError::Sled(sled::Error::Unsupported(msg)),
+            WireError::SledReportableBug(msg) => KvsError::Sled(sled::Error::ReportableBug(msg)),
+            WireError::SledIo(msg) => KvsError::Sled(sled::Error::Io(io::Error::other(msg))),
+            WireError::SledCorruption => {
+                KvsError::Sled(sled::Error::Corruption { at: None, bt: () })
+            }
+            WireError::Utf8(bytes) => match String::from_utf8(bytes) {
+                Err(e) => KvsError::Utf8(e),
+                Ok(_) => KvsError::Protocol("Valid UTF-8 reported as invalid".to_string()),
+            },
+            WireError::Protocol(msg) => KvsError::Protocol(msg),
+            WireError::Corruption(msg) => KvsError::Corruption(msg),
+            WireError::ThreadPool(msg) => KvsError::ThreadPool(msg),
+            WireError::Conflict(current) => KvsError::Conflict {
+                current: current.map(|Blob(value)| value),
+            },
+            WireError::TransactionConflict(Blob(key)) => KvsError::TransactionConflict { key },
+            WireError::Locked(path, pid) => KvsError::Locked { path, pid },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(e: KvsError) -> KvsError {
+        serde_json::from_str(&serde_json::to_string(&e).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_wire_round_trip_keeps_variant() {
+        assert!(matches!(
+            round_trip(KvsError::KeyNotFound),
+            KvsError::KeyNotFound
+        ));
+        assert!(matches!(
+            round_trip(KvsError::Io(io::Error::other("disk"))),
+            KvsError::Io(_)
+        ));
+        assert!(matches!(
+            round_trip(KvsError::Sled(sled::Error::Unsupported("x".to_string()))),
+            KvsError::Sled(sled::Error::Unsupported(msg)) if msg == "x"
+        ));
+        assert!(matches!(
+            round_trip(KvsError::Conflict {
+                current: Some(vec![0xff, b'v'])
+            }),
+            KvsError::Conflict { current: Some(v) } if v == [0xff, b'v']
+        ));
+        let utf8 = String::from_utf8(vec![0xff]).unwrap_err();
+        assert!(matches!(
+            round_trip(KvsError::Utf8(utf8)),
+            KvsError::Utf8(e) if e.as_bytes() == [0xff]
+        ));
+    }
+}
@@ -0,0 +1,61 @@
+use std::{
+    sync::Weak,
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use crossbeam::channel::{self, RecvTimeoutError, Sender};
+use log::error;
+
+use crate::Result;
+
+/// A thread started by `spawn_periodic`. Dropping it stops the thread and
+/// waits for the task it may be running, so the target is released by then.
+pub(crate) struct Periodic {
+    stop: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Drop for Periodic {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() {
+                error!("Background thread panicked");
+            }
+        }
+    }
+}
+
+/// Run `task` on `target` every `interval`, on a thread called `name`, until
+/// the returned handle or the target is dropped.
+pub(crate) fn spawn_periodic<T: Send + Sync + 'static>(
+    name: &str,
+    interval: Duration,
+    target: Weak<T>,
+    task: fn(&T) -> Result<()>,
+) -> Result<Periodic> {
+    let (stop, stopped) = channel::bounded::<()>(0);
+    let task_name = name.to_string();
+    let handle = thread::Builder::new()
+        .name(name.to_string())
+        .spawn(move || loop {
+            // Nothing is ever sent, so this returns early only once the
+            // handle is dropped.
+            if stopped.recv_timeout(interval) != Err(RecvTimeoutError::Timeout) {
+                break;
+            }
+            match target.upgrade() {
+                Some(target) => {
+                    if let Err(e) = task(&target) {
+                        error!("{} failed: {}", task_name, e);
+                    }
+                }
+                None => break,
+            }
+        })?;
+    Ok(Periodic {
+        stop: Some(stop),
+        handle: Some(handle),
+    })
+}
@@ -0,0 +1,448 @@
+//! Online backups of a `KvsEngine`.
+//!
+//! A backup is a single file of framed records, each with its own CRC32
+//! (see `record`): a header naming the format and the engine it was taken
+//! from, the key/value pairs in ascending key order with their expiry times,
+//! a chunk per record, and a trailer holding the number of pairs and a CRC32
+//! over every chunk. A backup that is cut short or corrupt is rejected as a
+//! whole.
+//!
+//! Backups are read from a snapshot, so they are consistent while the engine
+//! keeps taking writes, and can be restored into either engine. Keys that
+//! have expired by the time a backup is restored are left out.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, BufWriter, ErrorKind, Write},
+    ops::Bound,
+    path::{Component, Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::bytes::Blob;
+use super::expiry;
+use super::kvs_engine::KvsEngine;
+use super::record::{self, read_record, write_record};
+use super::snapshot::KvsSnapshot;
+use super::write_batch::WriteBatch;
+use crate::{KvsError, Result};
+
+/// Version of the backup format written by `backup`.
+const FORMAT: u32 = 2;
+
+/// The first format, whose chunks hold no expiry times. It can still be
+/// restored.
+const FORMAT_WITHOUT_EXPIRY: u32 = 1;
+
+/// Pairs per chunk record.
+const CHUNK_LEN: usize = 1024;
+
+#[derive(Serialize, Deserialize)]
+enum Entry {
+    Header {
+        format: u32,
+        engine: String,
+    },
+    /// A chunk of a `FORMAT_WITHOUT_EXPIRY` backup.
+    Chunk(Vec<(Blob, Blob)>),
+    /// A chunk of pairs, each with its expiry time in milliseconds since the
+    /// Unix epoch, if it has one.
+    ExpiringChunk(Vec<(Blob, Blob, Option<u64>)>),
+    Trailer {
+        pairs: u64,
+        checksum: u32,
+    },
+}
+
+/// What a verified backup holds.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BackupInfo {
+    /// Name of the engine the backup was taken from.
+    pub engine: String,
+    pub pairs: u64,
+}
+
+/// Write a backup of `engine` to `path`, which must not exist yet, and return
+/// the number of pairs written.
+///
+/// The backup is written next to `path` and linked into place once it is on
+/// disk, so `path` never holds a partial backup. Linking fails if `path` has
+/// been created in the meantime, where renaming would replace it.
+pub fn backup<E: KvsEngine>(engine: &E, path: &Path) -> Result<u64> {
+    if path.exists() {
+        return Err(already_exists(path).into());
+    }
+    let snapshot = engine.snapshot()?;
+    let partial = partial_path(path);
+    let file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&partial)?;
+    let written = write_backup(&snapshot, E::NAME, file).and_then(|pairs| {
+        publish(&partial, path)?;
+        Ok(pairs)
+    });
+    let _ = fs::remove_file(&partial);
+    written
+}
+
+/// Link the finished backup at `partial` to `path`, unless `path` exists.
+fn publish(partial: &Path, path: &Path) -> io::Result<()> {
+    fs::hard_link(partial, path).map_err(|e| match e.kind() {
+        ErrorKind::AlreadyExists => already_exists(path),
+        _ => e,
+    })
+}
+
+fn already_exists(path: &Path) -> io::Error {
+    io::Error::new(
+        ErrorKind::AlreadyExists,
+        format!("{} already exists", path.display()),
+    )
+}
+
+/// Write a backup of `engine` to `name`, as sent by a client, inside `dir`.
+///
+/// Fails if there is no `dir`, in which case backups are disabled, or if
+/// `name` would lead outside of it.
+pub fn backup_in<E: KvsEngine>(engine: &E, dir: Option<&Path>, name: &str) -> Result<u64> {
+    let dir = dir.ok_or_else(|| {
+        io::Error::new(
+            ErrorKind::PermissionDenied,
+            "Backups are disabled: the server has no backup directory",
+        )
+    })?;
+    backup(engine, &resolve(dir, Path::new(name))?)
+}
+
+/// Resolve `name` to a file in `dir`.
+///
+/// `name` must be a relative path without `..`, and its parent directory
+/// must still lie in `dir` once symbolic links are resolved.
+fn resolve(dir: &Path, name: &Path) -> Result<PathBuf> {
+    let outside = || {
+        io::Error::new(
+            ErrorKind::PermissionDenied,
+            format!("{} is outside the backup directory", name.display()),
+        )
+    };
+    let plain = name
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)));
+    let Some(file_name) = name.file_name().filter(|_| plain) else {
+        return Err(outside().into());
+    };
+    let dir = fs::canonicalize(dir)?;
+    let parent = fs::canonicalize(dir.join(name).parent().unwrap_or(&dir))?;
+    if !parent.starts_with(&dir) {
+        return Err(outside().into());
+    }
+    Ok(parent.join(file_name))
+}
+
+/// Check every checksum of the backup at `path` and describe it.
+pub fn verify(path: &Path) -> Result<BackupInfo> {
+    read_backup(path, |_| Ok(()))
+}
+
+/// Load the backup at `path` into `engine`, which must be empty, and return
+/// the number of pairs restored. Keys keep their expiry times.
+///
+/// The backup is verified before anything is written, so a corrupt backup
+/// leaves the engine empty.
+pub fn restore<E: KvsEngine>(path: &Path, engine: &E) -> Result<u64> {
+    if !engine.scan_bytes(.., 1)?.is_empty() {
+        return Err(io::Error::new(
+            ErrorKind::AlreadyExists,
+            "Cannot restore into a store that holds data",
+        )
+        .into());
+    }
+    verify(path)?;
+    let mut restored = 0;
+    read_backup(path, |chunk| {
+        let mut batch = WriteBatch::new();
+        for (Blob(key), Blob(value), expires_at) in chunk {
+            let kept;
+            (batch, kept) = expiry::restore_pair(engine, batch, key, value, expires_at)?;
+            if kept {
+                restored += 1;
+            }
+        }
+        engine.apply_batch(batch)
+    })?;
+    engine.flush()?;
+    Ok(restored)
+}
+
+fn partial_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".partial");
+    path.with_file_name(name)
+}
+
+fn write_backup<S: KvsSnapshot>(snapshot: &S, engine: &str, file: File) -> Result<u64> {
+    let mut writer = BufWriter::new(file);
+    let header = Entry::Header {
+        format: FORMAT,
+        engine: engine.to_string(),
+    };
+    write_record(&mut writer, &serde_json::to_vec(&header)?)?;
+
+    let mut checksum = crc32fast::Hasher::new();
+    let mut pairs = 0;
+    let mut start = Bound::Unbounded;
+    loop {
+        let chunk = snapshot.scan_with_expiry_bytes((start, Bound::Unbounded), CHUNK_LEN)?;
+        let Some((last, _, _)) = chunk.last() else {
+            break;
+        };
+        start = Bound::Excluded(last.clone());
+        pairs += chunk.len() as u64;
+        let chunk = chunk
+            .into_iter()
+            .map(|(key, value, expires_at)| (Blob(key), Blob(value), expires_at))
+            .collect();
+        let payload = serde_json::to_vec(&Entry::ExpiringChunk(chunk))?;
+        checksum.update(&payload);
+        write_record(&mut writer, &payload)?;
+    }
+
+    let trailer = Entry::Trailer {
+        pairs,
+        checksum: checksum.finalize(),
+    };
+    write_record(&mut writer, &serde_json::to_vec(&trailer)?)?;
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    Ok(pairs)
+}
+
+/// Read the backup at `path`, passing every chunk to `on_chunk` in order.
+fn read_backup(
+    path: &Path,
+    mut on_chunk: impl FnMut(Vec<(Blob, Blob, Option<u64>)>) -> Result<()>,
+) -> Result<BackupInfo> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut next = || -> Result<Option<(Entry, Vec<u8>)>> {
+        match read_record(&mut reader) {
+            Ok(Some(payload)) => Ok(Some((serde_json::from_slice(&payload)?, payload))),
+            Ok(None) => Ok(None),
+            Err(e) if record::is_corruption(&e) => Err(corrupt("record is torn or corrupt")),
+            Err(e) => Err(e.into()),
+        }
+    };
+
+    let (format, engine) = match next()? {
+        Some((Entry::Header { format, engine }, _))
+            if format == FORMAT || format == FORMAT_WITHOUT_EXPIRY =>
+        {
+            (format, engine)
+        }
+        Some((Entry::Header { format, .. }, _)) => {
+            return Err(corrupt(&format!("unsupported format {}", format)))
+        }
+        _ => return Err(corrupt("missing header")),
+    };
+    let mut checksum = crc32fast::Hasher::new();
+    let mut pairs = 0;
+    loop {
+        match next()? {
+            Some((Entry::Chunk(chunk), payload)) if format == FORMAT_WITHOUT_EXPIRY => {
+                checksum.update(&payload);
+                pairs += chunk.len() as u64;
+                on_chunk(
+                    chunk
+                        .into_iter()
+                        .map(|(key, value)| (key, value, None))
+                        .collect(),
+                )?;
+            }
+            Some((Entry::ExpiringChunk(chunk), payload)) if format == FORMAT => {
+                checksum.update(&payload);
+                pairs += chunk.len() as u64;
+                on_chunk(chunk)?;
+            }
+            Some((Entry::Chunk(_) | Entry::ExpiringChunk(_), _)) => {
+                return Err(corrupt("chunk does not match the format"))
+            }
+            Some((
+                Entry::Trailer {
+                    pairs: expected,
+                    checksum: crc,
+                },
+                _,
+            )) => {
+                if expected != pairs || crc != checksum.finalize() {
+                    return Err(corrupt("contents do not match the trailer"));
+                }
+                break;
+            }
+            Some((Entry::Header { .. }, _)) => return Err(corrupt("unexpected header")),
+            None => return Err(corrupt("missing trailer")),
+        }
+    }
+    if next()?.is_some() {
+        return Err(corrupt("data after the trailer"));
+    }
+    Ok(BackupInfo { engine, pairs })
+}
+
+fn corrupt(reason: &str) -> KvsError {
+    KvsError::Corruption(format!("Invalid backup: {}", reason))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{KvStore, SledKvsEngine};
+    use std::{thread, time::Duration};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_backup_restores_into_either_engine() {
+        let dir = TempDir::new().unwrap();
+        let store = KvStore::open(dir.path().join("kvs")).unwrap();
+        for i in 0..2500 {
+            store.set(format!("key{:04}", i), i.to_string()).unwrap();
+        }
+        let path = dir.path().join("backup");
+        assert_eq!(backup(&store, &path).unwrap(), 2500);
+        assert!(backup(&store, &path).is_err());
+        assert_eq!(
+            verify(&path).unwrap(),
+            BackupInfo {
+                engine: "kvs".to_string(),
+                pairs: 2500,
+            }
+        );
+
+        let sled = SledKvsEngine::new(sled::open(dir.path().join("sled")).unwrap());
+        assert_eq!(restore(&path, &sled).unwrap(), 2500);
+        assert_eq!(sled.keys().unwrap(), store.keys().unwrap());
+        assert_eq!(
+            sled.get("key1234".to_string()).unwrap(),
+            Some("1234".to_string())
+        );
+        assert!(restore(&path, &sled).is_err());
+    }
+
+    #[test]
+    fn test_backup_never_replaces_a_file() {
+        let dir = TempDir::new().unwrap();
+        let partial = dir.path().join("backup.partial");
+        let path = dir.path().join("backup");
+        fs::write(&partial, "new").unwrap();
+        fs::write(&path, "old").unwrap();
+        let e = publish(&partial, &path).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::AlreadyExists);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "old");
+
+        let store = KvStore::open(dir.path().join("kvs")).unwrap();
+        fs::remove_file(&partial).unwrap();
+        let path = dir.path().join("fresh");
+        backup(&store, &path).unwrap();
+        assert!(!partial_path(&path).exists());
+    }
+
+    #[test]
+    fn test_backup_keeps_expiry_times() {
+        let dir = TempDir::new().unwrap();
+        let store = KvStore::open(dir.path().join("kvs")).unwrap();
+        store.set("kept".to_string(), "1".to_string()).unwrap();
+        store
+            .set_with_ttl(
+                "later".to_string(),
+                "2".to_string(),
+                Duration::from_secs(60),
+            )
+            .unwrap();
+        store
+            .set_with_ttl(
+                "soon".to_string(),
+                "3".to_string(),
+                Duration::from_millis(50),
+            )
+            .unwrap();
+        let path = dir.path().join("backup");
+        assert_eq!(backup(&store, &path).unwrap(), 3);
+        thread::sleep(Duration::from_millis(100));
+
+        let target = KvStore::open(dir.path().join("target")).unwrap();
+        assert_eq!(restore(&path, &target).unwrap(), 2);
+        assert_eq!(target.keys().unwrap(), ["kept", "later"]);
+        assert_eq!(target.ttl("kept".to_string()).unwrap(), None);
+        let ttl = target.ttl("later".to_string()).unwrap().unwrap();
+        assert!(ttl > Duration::from_secs(50));
+    }
+
+    #[test]
+    fn test_backup_without_expiry_times_restores() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("backup");
+        let mut file = File::create(&path).unwrap();
+        let header = Entry::Header {
+            format: FORMAT_WITHOUT_EXPIRY,
+            engine: "kvs".to_string(),
+        };
+        write_record(&mut file, &serde_json::to_vec(&header).unwrap()).unwrap();
+        let chunk = Entry::Chunk(vec![(Blob(b"a".to_vec()), Blob(b"1".to_vec()))]);
+        let payload = serde_json::to_vec(&chunk).unwrap();
+        write_record(&mut file, &payload).unwrap();
+        let trailer = Entry::Trailer {
+            pairs: 1,
+            checksum: crc32fast::hash(&payload),
+        };
+        write_record(&mut file, &serde_json::to_vec(&trailer).unwrap()).unwrap();
+        drop(file);
+
+        let target = KvStore::open(dir.path().join("target")).unwrap();
+        assert_eq!(restore(&path, &target).unwrap(), 1);
+        assert_eq!(target.get("a".to_string()).unwrap(), Some("1".to_string()));
+    }
+
+    #[test]
+    fn test_backup_stays_in_its_directory() {
+        let dir = TempDir::new().unwrap();
+        let store = KvStore::open(dir.path().join("kvs")).unwrap();
+        store.set("a".to_string(), "1".to_string()).unwrap();
+        let backups = dir.path().join("backups");
+        fs::create_dir(&backups).unwrap();
+
+        assert_eq!(backup_in(&store, Some(&backups), "daily").unwrap(), 1);
+        assert!(backups.join("daily").exists());
+        let outside = dir.path().join("escaped").display().to_string();
+        for name in ["../escaped", &outside, "", "."] {
+            assert!(backup_in(&store, Some(&backups), name).is_err());
+        }
+        assert!(backup_in(&store, None, "disabled").is_err());
+        assert!(!dir.path().join("escaped").exists());
+    }
+
+    #[test]
+    fn test_damaged_backup_is_rejected() {
+        let dir = TempDir::new().unwrap();
+        let store = KvStore::open(dir.path().join("kvs")).unwrap();
+        store.set("a".to_string(), "1".to_string()).unwrap();
+        let path = dir.path().join("backup");
+        backup(&store, &path).unwrap();
+        let bytes = fs::read(&path).unwrap();
+
+        let truncated = dir.path().join("truncated");
+        fs::write(&truncated, &bytes[..bytes.len() - 1]).unwrap();
+        let mut flipped = bytes.clone();
+        flipped[bytes.len() / 2] ^= 0xff;
+        let corrupted = dir.path().join("corrupted");
+        fs::write(&corrupted, flipped).unwrap();
+
+        for path in [truncated, corrupted] {
+            let target = KvStore::open(dir.path().join("target")).unwrap();
+            assert!(matches!(
+                restore(&path, &target),
+                Err(KvsError::Corruption(_))
+            ));
+            assert!(target.keys().unwrap().is_empty());
+        }
+    }
+}
@@ -0,0 +1,174 @@
+//! Serialization of keys and values, which are arbitrary bytes.
+//!
+//! Bytes that are valid UTF-8 are written as a string, so text stays as
+//! compact and readable as it always was, and anything else as
+//! `{"base64": "..."}`. Arrays of numbers, which older versions wrote for
+//! binary data, are still accepted when reading, as are plain strings from
+//! back when keys and values had to be strings.
+//!
+//! Use `serialize` and `deserialize` with `#[serde(with = "...")]` on fields,
+//! and `Blob` where there is no field to put the attribute on.
+
+use std::{fmt, ops::Bound, str};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::{
+    de::{self, MapAccess, SeqAccess, Visitor},
+    ser::SerializeMap,
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+
+use crate::Result;
+
+/// The key of the one-entry map that holds binary data.
+const BASE64_KEY: &str = "base64";
+
+pub(crate) fn serialize<S: Serializer>(
+    bytes: &[u8],
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    match str::from_utf8(bytes) {
+        Ok(text) => serializer.serialize_str(text),
+        Err(_) => {
+            let mut map = serializer.serialize_map(Some(1))?;
+            map.serialize_entry(BASE64_KEY, &STANDARD.encode(bytes))?;
+            map.end()
+        }
+    }
+}
+
+pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Vec<u8>, D::Error> {
+    deserializer.deserialize_any(BytesVisitor)
+}
+
+struct BytesVisitor;
+
+impl<'de> Visitor<'de> for BytesVisitor {
+    type Value = Vec<u8>;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "a string, base64 data or an array of bytes")
+    }
+
+    fn visit_str<E: de::Error>(self, text: &str) -> std::result::Result<Vec<u8>, E> {
+        Ok(text.as_bytes().to_vec())
+    }
+
+    fn visit_string<E: de::Error>(self, text: String) -> std::result::Result<Vec<u8>, E> {
+        Ok(text.into_bytes())
+    }
+
+    fn visit_bytes<E: de::Error>(self, bytes: &[u8]) -> std::result::Result<Vec<u8>, E> {
+        Ok(bytes.to_vec())
+    }
+
+    fn visit_byte_buf<E: de::Error>(self, bytes: Vec<u8>) -> std::result::Result<Vec<u8>, E> {
+        Ok(bytes)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> std::result::Result<Vec<u8>, A::Error> {
+        let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(byte) = seq.next_element()? {
+            bytes.push(byte);
+        }
+        Ok(bytes)
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> std::result::Result<Vec<u8>, A::Error> {
+        let (key, encoded): (String, String) = map
+            .next_entry()?
+            .ok_or_else(|| de::Error::missing_field(BASE64_KEY))?;
+        if key != BASE64_KEY {
+            return Err(de::Error::unknown_field(&key, &[BASE64_KEY]));
+        }
+        if map.next_key::<de::IgnoredAny>()?.is_some() {
+            return Err(de::Error::custom("unexpected field after base64 data"));
+        }
+        STANDARD.decode(encoded).map_err(de::Error::custom)
+    }
+}
+
+/// Bytes that serialize like a field marked with this module. Borrowed bytes
+/// can be serialized too, as `Blob(&bytes)`.
+#[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct Blob<B = Vec<u8>>(pub(crate) B);
+
+impl<B: AsRef<[u8]>> Serialize for Blob<B> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serialize(self.0.as_ref(), serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Blob {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        deserialize(deserializer).map(Blob)
+    }
+}
+
+/// The range of keys that start with `prefix`.
+pub fn prefix_range(prefix: Vec<u8>) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+    let mut end = prefix.clone();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return (Bound::Included(prefix), Bound::Excluded(end));
+        }
+    }
+    (Bound::Included(prefix), Bound::Unbounded)
+}
+
+/// The bounds of a range of string keys, as bytes.
+pub(crate) fn range_to_bytes<R: std::ops::RangeBounds<String>>(
+    range: &R,
+) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+    (
+        range.start_bound().map(|key| key.as_bytes().to_vec()),
+        range.end_bound().map(|key| key.as_bytes().to_vec()),
+    )
+}
+
+/// A key/value pair as strings, failing if either is not valid UTF-8.
+pub(crate) fn pair_to_strings((key, value): (Vec<u8>, Vec<u8>)) -> Result<(String, String)> {
+    Ok((String::from_utf8(key)?, String::from_utf8(value)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_text_stays_a_string() {
+        let text = serde_json::to_string(&Blob(b"key".to_vec())).unwrap();
+        assert_eq!(text, "\"key\"");
+        let binary = serde_json::to_string(&Blob(vec![0xff, 0])).unwrap();
+        assert_eq!(binary, r#"{"base64":"/wA="}"#);
+        for json in [text, binary] {
+            let blob: Blob = serde_json::from_str(&json).unwrap();
+            assert_eq!(serde_json::to_string(&blob).unwrap(), json);
+        }
+    }
+
+    #[test]
+    fn test_number_arrays_still_load() {
+        let blob: Blob = serde_json::from_str("[255,0]").unwrap();
+        assert_eq!(blob, Blob(vec![0xff, 0]));
+        assert!(serde_json::from_str::<Blob>(r#"{"hex":"ff"}"#).is_err());
+    }
+
+    #[test]
+    fn test_prefix_range() {
+        assert_eq!(
+            prefix_range(vec![b'a', 0xff]),
+            (
+                Bound::Included(vec![b'a', 0xff]),
+                Bound::Excluded(vec![b'b'])
+            )
+        );
+        assert_eq!(
+            prefix_range(vec![0xff]),
+            (Bound::Included(vec![0xff]), Bound::Unbounded)
+        );
+    }
+}
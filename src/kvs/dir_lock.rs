@@ -0,0 +1,83 @@
+//! Advisory locks that keep a data directory to a single process.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::Path,
+    process,
+};
+
+use fs2::FileExt;
+use log::error;
+
+use crate::{KvsError, Result};
+
+/// Name of the lock file in a locked directory.
+pub const LOCK_FILE: &str = "LOCK";
+
+/// An exclusive lock on a directory, released when dropped.
+///
+/// The lock is taken on a `LOCK` file holding the process ID of its holder,
+/// so a second process, or a second handle in the same process, is turned
+/// away with `KvsError::Locked` instead of writing alongside the first.
+pub struct DirLock {
+    file: File,
+}
+
+impl DirLock {
+    /// Lock `dir`, which must exist, without waiting.
+    pub fn acquire(dir: &Path) -> Result<DirLock> {
+        let path = dir.join(LOCK_FILE);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+        if let Err(e) = file.try_lock_exclusive() {
+            if e.kind() != fs2::lock_contended_error().kind() {
+                return Err(e.into());
+            }
+            let mut holder = String::new();
+            file.read_to_string(&mut holder)?;
+            return Err(KvsError::Locked {
+                path: dir.to_path_buf(),
+                pid: holder.trim().parse().ok(),
+            });
+        }
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        writeln!(file, "{}", process::id())?;
+        file.sync_data()?;
+        Ok(DirLock { file })
+    }
+}
+
+impl Drop for DirLock {
+    fn drop(&mut self) {
+        if let Err(e) = self.file.set_len(0).and_then(|()| self.file.unlock()) {
+            error!("Failed to release directory lock: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_second_lock_names_holder() {
+        let dir = TempDir::new().unwrap();
+        let lock = DirLock::acquire(dir.path()).unwrap();
+        match DirLock::acquire(dir.path()) {
+            Err(KvsError::Locked { path, pid }) => {
+                assert_eq!(path, dir.path());
+                assert_eq!(pid, Some(process::id()));
+            }
+            _ => panic!("expected the directory to be locked"),
+        }
+        drop(lock);
+        DirLock::acquire(dir.path()).unwrap();
+    }
+}
@@ -0,0 +1,320 @@
+//! Engine-neutral dumps of a `KvsEngine`, for moving data between engines.
+//!
+//! A dump is written in one of two formats:
+//!
+//! * JSON Lines: one `{"key":...,"value":...}` object per line, easy to read
+//!   and to produce with other tools, with an `expires_at` field for keys
+//!   that expire. Keys and values that are not valid UTF-8 are written as
+//!   `{"base64":"..."}` objects holding their bytes in standard base64.
+//!   Arrays of bytes, which older versions wrote instead, can still be
+//!   imported.
+//! * Binary: the magic bytes `KVSDUMP2`, then every pair as a little-endian
+//!   `u32` key length, the key, a little-endian `u32` value length, the
+//!   value and a little-endian `u64` expiry time, 0 if there is none.
+//!   Dumps starting with `KVSDUMP1` have no expiry times, and can still be
+//!   imported.
+//!
+//! Expiry times are in milliseconds since the Unix epoch. Dumps are streamed
+//! from a snapshot in ascending key order, and `import` tells the formats
+//! apart by the magic bytes. Keys that have expired by the time a dump is
+//! imported are left out.
+
+use std::{
+    fmt,
+    io::{self, BufRead, ErrorKind, Read, Write},
+    ops::Bound,
+    str::FromStr,
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::bytes;
+use super::expiry;
+use super::kvs_engine::KvsEngine;
+use super::snapshot::KvsSnapshot;
+use super::write_batch::WriteBatch;
+use crate::{KvsError, Result};
+
+/// First bytes of a binary dump.
+const MAGIC: &[u8; 8] = b"KVSDUMP2";
+
+/// First bytes of a binary dump without expiry times.
+const MAGIC_WITHOUT_EXPIRY: &[u8; 8] = b"KVSDUMP1";
+
+/// Pairs read from the engine, or written to it, at a time.
+const CHUNK_LEN: usize = 1024;
+
+/// The format of a dump.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DumpFormat {
+    /// One JSON object per line.
+    #[default]
+    Json,
+    /// Length-prefixed keys and values.
+    Binary,
+}
+
+impl FromStr for DumpFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(DumpFormat::Json),
+            "binary" => Ok(DumpFormat::Binary),
+            _ => Err(format!(
+                "invalid dump format '{}': expected json or binary",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for DumpFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DumpFormat::Json => write!(f, "json"),
+            DumpFormat::Binary => write!(f, "binary"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Pair {
+    #[serde(with = "bytes")]
+    key: Vec<u8>,
+    #[serde(with = "bytes")]
+    value: Vec<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at: Option<u64>,
+}
+
+/// Write every pair of `engine` to `writer` and return how many there were.
+pub fn dump<E: KvsEngine, W: Write>(engine: &E, format: DumpFormat, mut writer: W) -> Result<u64> {
+    let snapshot = engine.snapshot()?;
+    if format == DumpFormat::Binary {
+        writer.write_all(MAGIC)?;
+    }
+    let mut pairs = 0;
+    let mut start = Bound::Unbounded;
+    loop {
+        let chunk = snapshot.scan_with_expiry_bytes((start, Bound::Unbounded), CHUNK_LEN)?;
+        let Some((last, _, _)) = chunk.last() else {
+            break;
+        };
+        start = Bound::Excluded(last.clone());
+        pairs += chunk.len() as u64;
+        for (key, value, expires_at) in chunk {
+            match format {
+                DumpFormat::Json => {
+                    let pair = Pair {
+                        key,
+                        value,
+                        expires_at,
+                    };
+                    serde_json::to_writer(&mut writer, &pair)?;
+                    writer.write_all(b"\n")?;
+                }
+                DumpFormat::Binary => {
+                    write_bytes(&mut writer, &key)?;
+                    write_bytes(&mut writer, &value)?;
+                    writer.write_all(&expires_at.unwrap_or(0).to_le_bytes())?;
+                }
+            }
+        }
+    }
+    writer.flush()?;
+    Ok(pairs)
+}
+
+/// Set every pair of the dump read from `reader` in `engine`, in either
+/// format, and return how many were set. Keys keep their expiry times.
+///
+/// Pairs are written in batches as they are read, so a malformed dump can
+/// leave the ones before the error behind.
+pub fn import<E: KvsEngine, R: BufRead>(engine: &E, mut reader: R) -> Result<u64> {
+    let magic = reader.fill_buf()?;
+    let binary = magic.starts_with(MAGIC);
+    let without_expiry = magic.starts_with(MAGIC_WITHOUT_EXPIRY);
+    let mut batch = WriteBatch::new();
+    let mut pairs = 0;
+    let mut add = |key: Vec<u8>, value: Vec<u8>, expires_at: Option<u64>| -> Result<()> {
+        let kept;
+        (batch, kept) =
+            expiry::restore_pair(engine, std::mem::take(&mut batch), key, value, expires_at)?;
+        if kept {
+            pairs += 1;
+        }
+        if batch.len() >= CHUNK_LEN {
+            engine.apply_batch(std::mem::take(&mut batch))?;
+        }
+        Ok(())
+    };
+    if binary || without_expiry {
+        reader.consume(MAGIC.len());
+        while let Some(key) = read_bytes(&mut reader)? {
+            let value = read_bytes(&mut reader)?.ok_or_else(|| corrupt("missing value"))?;
+            let expires_at = if binary {
+                let mut expires_at = [0; 8];
+                reader
+                    .read_exact(&mut expires_at)
+                    .map_err(|e| match e.kind() {
+                        ErrorKind::UnexpectedEof => corrupt("truncated expiry time"),
+                        _ => e.into(),
+                    })?;
+                Some(u64::from_le_bytes(expires_at)).filter(|&at| at != 0)
+            } else {
+                None
+            };
+            add(key, value, expires_at)?;
+        }
+    } else {
+        for (number, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let pair: Pair = serde_json::from_str(&line)
+                .map_err(|e| corrupt(&format!("line {}: {}", number + 1, e)))?;
+            add(pair.key, pair.value, pair.expires_at)?;
+        }
+    }
+    engine.apply_batch(batch)?;
+    engine.flush()?;
+    Ok(pairs)
+}
+
+fn write_bytes<W: Write>(writer: &mut W, bytes: &[u8]) -> Result<()> {
+    let len = u32::try_from(bytes.len())
+        .map_err(|_| io::Error::new(ErrorKind::InvalidInput, "key or value too large"))?;
+    writer.write_all(&len.to_le_bytes())?;
+    writer.write_all(bytes)?;
+    Ok(())
+}
+
+/// Read a length-prefixed key or value, or `None` at a clean end of the dump.
+fn read_bytes<R: Read>(reader: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut len = [0; 4];
+    let mut filled = 0;
+    while filled < len.len() {
+        match reader.read(&mut len[filled..]) {
+            Ok(0) if filled == 0 => return Ok(None),
+            Ok(0) => return Err(corrupt("truncated length")),
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    let len = u32::from_le_bytes(len) as u64;
+    let mut bytes = Vec::new();
+    reader.take(len).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 != len {
+        return Err(corrupt("truncated key or value"));
+    }
+    Ok(Some(bytes))
+}
+
+fn corrupt(reason: &str) -> KvsError {
+    KvsError::Corruption(format!("Invalid dump: {}", reason))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{KvStore, SledKvsEngine};
+    use std::{io::Cursor, thread, time::Duration};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_dump_round_trips_between_engines() {
+        let dir = TempDir::new().unwrap();
+        let store = KvStore::open(dir.path().join("kvs")).unwrap();
+        for i in 0..1500 {
+            store
+                .set(format!("key{}", i), format!("line\n{}", i))
+                .unwrap();
+        }
+        store.set("".to_string(), "\u{1F980}".to_string()).unwrap();
+        store.set_bytes(vec![0xff], vec![0, 0xfe]).unwrap();
+
+        for format in [DumpFormat::Json, DumpFormat::Binary] {
+            let mut bytes = Vec::new();
+            assert_eq!(dump(&store, format, &mut bytes).unwrap(), 1502);
+            let sled = SledKvsEngine::new(
+                sled::open(dir.path().join(format!("sled-{}", format))).unwrap(),
+            );
+            assert_eq!(import(&sled, Cursor::new(bytes)).unwrap(), 1502);
+            assert_eq!(
+                sled.scan_bytes(.., usize::MAX).unwrap(),
+                store.scan_bytes(.., usize::MAX).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn test_dump_keeps_expiry_times() {
+        let dir = TempDir::new().unwrap();
+        let store = KvStore::open(dir.path().join("kvs")).unwrap();
+        store.set("kept".to_string(), "1".to_string()).unwrap();
+        store
+            .set_with_ttl(
+                "later".to_string(),
+                "2".to_string(),
+                Duration::from_secs(60),
+            )
+            .unwrap();
+        store
+            .set_with_ttl(
+                "soon".to_string(),
+                "3".to_string(),
+                Duration::from_millis(50),
+            )
+            .unwrap();
+        let dumps = [DumpFormat::Json, DumpFormat::Binary].map(|format| {
+            let mut bytes = Vec::new();
+            assert_eq!(dump(&store, format, &mut bytes).unwrap(), 3);
+            bytes
+        });
+        thread::sleep(Duration::from_millis(100));
+
+        for (i, bytes) in dumps.into_iter().enumerate() {
+            let target = KvStore::open(dir.path().join(i.to_string())).unwrap();
+            assert_eq!(import(&target, Cursor::new(bytes)).unwrap(), 2);
+            assert_eq!(target.keys().unwrap(), ["kept", "later"]);
+            assert_eq!(target.ttl("kept".to_string()).unwrap(), None);
+            let ttl = target.ttl("later".to_string()).unwrap().unwrap();
+            assert!(ttl > Duration::from_secs(50));
+        }
+    }
+
+    #[test]
+    fn test_binary_dump_without_expiry_times_imports() {
+        let dir = TempDir::new().unwrap();
+        let store = KvStore::open(dir.path()).unwrap();
+        let mut binary = MAGIC_WITHOUT_EXPIRY.to_vec();
+        for bytes in [b"a", b"1"] {
+            binary.extend_from_slice(&1u32.to_le_bytes());
+            binary.extend_from_slice(bytes);
+        }
+        assert_eq!(import(&store, Cursor::new(binary)).unwrap(), 1);
+        assert_eq!(store.get("a".to_string()).unwrap(), Some("1".to_string()));
+    }
+
+    #[test]
+    fn test_malformed_dump_is_rejected() {
+        let dir = TempDir::new().unwrap();
+        let store = KvStore::open(dir.path()).unwrap();
+        let json = b"{\"key\":\"a\",\"value\":\"1\"}\n{\"key\":\"b\"}\n";
+        assert!(matches!(
+            import(&store, Cursor::new(&json[..])),
+            Err(KvsError::Corruption(_))
+        ));
+
+        let mut binary = MAGIC.to_vec();
+        binary.extend_from_slice(&5u32.to_le_bytes());
+        binary.extend_from_slice(b"ab");
+        assert!(matches!(
+            import(&store, Cursor::new(binary)),
+            Err(KvsError::Corruption(_))
+        ));
+    }
+}
@@ -0,0 +1,130 @@
+use std::{
+    fmt,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use crate::Result;
+
+/// When an engine forces its writes to stable storage.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Durability {
+    /// Sync before every write returns. Concurrent writers share one sync.
+    Always,
+    /// Sync in the background every N milliseconds.
+    EveryN(u64),
+    /// Sync only when the engine is closed.
+    OnClose,
+    /// Leave syncing to the operating system.
+    Never,
+}
+
+impl Default for Durability {
+    fn default() -> Self {
+        Durability::EveryN(1000)
+    }
+}
+
+impl fmt::Display for Durability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Durability::Always => write!(f, "always"),
+            Durability::EveryN(ms) => write!(f, "{}ms", ms),
+            Durability::OnClose => write!(f, "on-close"),
+            Durability::Never => write!(f, "never"),
+        }
+    }
+}
+
+/// Parses `always`, `on-close`, `never`, or an interval such as `100ms`.
+impl FromStr for Durability {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "always" => Ok(Durability::Always),
+            "on-close" => Ok(Durability::OnClose),
+            "never" => Ok(Durability::Never),
+            _ => s
+                .strip_suffix("ms")
+                .and_then(|ms| ms.parse().ok())
+                .filter(|ms| *ms > 0)
+                .map(Durability::EveryN)
+                .ok_or_else(|| format!("Invalid durability: {}", s)),
+        }
+    }
+}
+
+/// Lets concurrent writers share a single sync.
+///
+/// Every write takes a sequence number once it has reached the operating
+/// system. A writer that needs its write to be durable waits for the sync in
+/// progress; if that sync started after its write, nothing is left to do.
+#[derive(Default)]
+pub(crate) struct GroupCommit {
+    written: AtomicU64,
+    synced: Mutex<u64>,
+}
+
+impl GroupCommit {
+    /// Record a write, returning its sequence number.
+    pub fn record(&self) -> u64 {
+        self.written.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    /// Make sure the write numbered `seq` is durable, calling `sync` if no
+    /// other writer has already covered it.
+    pub fn sync(&self, seq: u64, sync: impl FnOnce() -> Result<()>) -> Result<()> {
+        let mut synced = self.synced.lock().expect("Poisoned lock");
+        if *synced >= seq {
+            return Ok(());
+        }
+        let target = self.written.load(Ordering::SeqCst);
+        sync()?;
+        *synced = target;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_durability() {
+        assert_eq!("always".parse(), Ok(Durability::Always));
+        assert_eq!("250ms".parse(), Ok(Durability::EveryN(250)));
+        assert_eq!("on-close".parse(), Ok(Durability::OnClose));
+        assert_eq!("never".parse(), Ok(Durability::Never));
+        assert!("0ms".parse::<Durability>().is_err());
+        assert!("sometimes".parse::<Durability>().is_err());
+        assert_eq!(
+            Durability::EveryN(250).to_string().parse(),
+            Ok(Durability::EveryN(250))
+        );
+    }
+
+    #[test]
+    fn test_group_commit_skips_covered_writes() {
+        let group = GroupCommit::default();
+        let first = group.record();
+        let second = group.record();
+        let mut syncs = 0;
+        group
+            .sync(first, || {
+                syncs += 1;
+                Ok(())
+            })
+            .unwrap();
+        group
+            .sync(second, || {
+                syncs += 1;
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(syncs, 1);
+    }
+}
@@ -0,0 +1,52 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::kvs_engine::KvsEngine;
+use super::write_batch::WriteBatch;
+use crate::Result;
+
+/// How often expired keys are swept out by default.
+pub(crate) const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Milliseconds since the Unix epoch. Expiry times are stored in this form
+/// so they survive restarts.
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_millis() as u64)
+}
+
+/// The expiry time of a key written now with the given time to live.
+pub(crate) fn expires_at(ttl: Duration) -> u64 {
+    now_millis().saturating_add(ttl.as_millis() as u64)
+}
+
+/// Whether a key with the given expiry time is gone at `now`.
+pub(crate) fn is_expired(expires_at: Option<u64>, now: u64) -> bool {
+    expires_at.is_some_and(|expires_at| expires_at <= now)
+}
+
+/// Time left until `expires_at`.
+pub(crate) fn remaining(expires_at: u64) -> Duration {
+    Duration::from_millis(expires_at.saturating_sub(now_millis()))
+}
+
+/// Add a pair read from a backup or a dump to `batch` or, if it expires,
+/// write it to `engine` with what is left of its time to live. Returns the
+/// batch, and whether the pair was kept: pairs that have expired since are
+/// dropped.
+pub(crate) fn restore_pair<E: KvsEngine>(
+    engine: &E,
+    batch: WriteBatch,
+    key: Vec<u8>,
+    value: Vec<u8>,
+    expires_at: Option<u64>,
+) -> Result<(WriteBatch, bool)> {
+    match expires_at {
+        None => Ok((batch.set(key, value), true)),
+        Some(expires_at) if expires_at > now_millis() => {
+            engine.set_with_ttl_bytes(key, value, remaining(expires_at))?;
+            Ok((batch, true))
+        }
+        Some(_) => Ok((batch, false)),
+    }
+}
@@ -0,0 +1,56 @@
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeSet},
+    hash::{Hash, Hasher},
+    sync::{Mutex, MutexGuard},
+};
+
+/// Number of locks keys are spread over.
+const KEY_LOCKS: usize = 64;
+
+/// Locks that writers take on the keys they touch, spread over a fixed
+/// number of mutexes so that writes to different keys rarely wait on each
+/// other.
+pub(crate) struct KeyLocks {
+    locks: Vec<Mutex<()>>,
+}
+
+impl Default for KeyLocks {
+    fn default() -> Self {
+        KeyLocks {
+            locks: (0..KEY_LOCKS).map(|_| Mutex::new(())).collect(),
+        }
+    }
+}
+
+impl KeyLocks {
+    /// Lock the keys that share a lock with `key`.
+    pub(crate) fn lock(&self, key: &[u8]) -> MutexGuard<'_, ()> {
+        lock(&self.locks[lock_index(key)])
+    }
+
+    /// Lock every key in `keys`. The locks are taken in order, so two callers
+    /// cannot deadlock.
+    pub(crate) fn lock_keys<'a>(
+        &self,
+        keys: impl IntoIterator<Item = &'a [u8]>,
+    ) -> Vec<MutexGuard<'_, ()>> {
+        let indices: BTreeSet<usize> = keys.into_iter().map(lock_index).collect();
+        indices.into_iter().map(|i| lock(&self.locks[i])).collect()
+    }
+
+    /// Lock every key, in the same order as `lock_keys`.
+    pub(crate) fn lock_all(&self) -> Vec<MutexGuard<'_, ()>> {
+        self.locks.iter().map(lock).collect()
+    }
+}
+
+fn lock(mutex: &Mutex<()>) -> MutexGuard<'_, ()> {
+    mutex.lock().expect("Poisoned lock")
+}
+
+/// Which of the `KEY_LOCKS` locks guards `key`.
+fn lock_index(key: &[u8]) -> usize {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    (hasher.finish() % KEY_LOCKS as u64) as usize
+}
@@ -1,132 +1,1919 @@
+use super::background::{spawn_periodic, Periodic};
+use super::bytes;
+use super::dir_lock::DirLock;
+use super::durability::{Durability, GroupCommit};
+use super::expiry::{self, now_millis};
 use super::kvs_engine::KvsEngine;
-use crate::Result;
-use log::trace;
+use super::record::{self, read_record, write_record};
+use super::snapshot::{ExpiringPair, KvsSnapshot};
+use super::write_batch::{BatchOp, WriteBatch};
+use crate::lock_free::hashmap::HashMap;
+use crate::{KvsError, Result};
+use crossbeam::channel::{self, RecvTimeoutError, Sender, TrySendError};
+use log::{debug, error, info, trace, warn};
+use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashMap,
-    fs::{File, OpenOptions},
-    io::{Read, Seek, SeekFrom, Write},
-    path::PathBuf,
-    sync::{Arc, Mutex},
+    cmp::Ordering,
+    collections::{BTreeMap, BTreeSet},
+    ffi::OsStr,
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    ops::{Bound, Deref, RangeBounds},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering as AtomicOrdering},
+        Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
 };
 
-/// The `KvStore` stores string key/value pairs.
+/// A command appended to the log.
+///
+/// Every command carries the version it was written at. Versions increase
+/// with every write to the store.
+#[derive(Serialize, Deserialize)]
+enum Command {
+    Set {
+        #[serde(with = "bytes")]
+        key: Vec<u8>,
+        #[serde(with = "bytes")]
+        value: Vec<u8>,
+        version: u64,
+        /// When the key expires, in milliseconds since the Unix epoch.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expires_at: Option<u64>,
+    },
+    Remove {
+        #[serde(with = "bytes")]
+        key: Vec<u8>,
+        version: u64,
+    },
+    Batch {
+        batch: WriteBatch,
+        version: u64,
+    },
+    /// Starts every compaction generation with the latest version at the
+    /// time, so the version counter survives compaction dropping the records
+    /// that reached it.
+    HighWater {
+        version: u64,
+    },
+}
+
+impl Command {
+    fn version(&self) -> u64 {
+        match self {
+            Command::Set { version, .. }
+            | Command::Remove { version, .. }
+            | Command::Batch { version, .. }
+            | Command::HighWater { version } => *version,
+        }
+    }
+
+    fn expires_at(&self) -> Option<u64> {
+        match self {
+            Command::Set { expires_at, .. } => *expires_at,
+            Command::Remove { .. } | Command::Batch { .. } | Command::HighWater { .. } => None,
+        }
+    }
+}
+
+/// Where the latest command of a key lives in the log.
+#[derive(Clone, Copy, PartialEq)]
+struct CommandPos {
+    gen: u64,
+    pos: u64,
+    len: u64,
+    /// The bytes this key accounts for in compaction statistics: `len`, or
+    /// its share of a batch record written together with other keys.
+    share: u64,
+    /// The version the key was written at.
+    version: u64,
+    /// When the key expires, in milliseconds since the Unix epoch.
+    expires_at: Option<u64>,
+}
+
+impl CommandPos {
+    fn is_expired(&self, now: u64) -> bool {
+        expiry::is_expired(self.expires_at, now)
+    }
+
+    /// Whether other keys were written in the same record.
+    fn is_shared(&self) -> bool {
+        self.share < self.len
+    }
+
+    /// Split a batch record into one position per write, sharing its length
+    /// between them.
+    fn split(self, count: usize) -> impl Iterator<Item = CommandPos> {
+        let count = count.max(1) as u64;
+        (0..count).map(move |i| CommandPos {
+            share: self.len / count + u64::from(i < self.len % count),
+            ..self
+        })
+    }
+}
+
+/// Entries replaced since the oldest live snapshot was taken, kept for as
+/// long as a snapshot can still see them.
+#[derive(Default)]
+struct History {
+    /// The version of the latest write applied to the index.
+    version: u64,
+    /// The version every live snapshot reads at, with how many read there.
+    snapshots: BTreeMap<u64, usize>,
+    /// The kept entries of each key, with the version of the write that
+    /// replaced them.
+    superseded: BTreeMap<Vec<u8>, Vec<(CommandPos, u64)>>,
+}
+
+impl History {
+    /// Whether a live snapshot reads at a version in `versions`.
+    fn is_seen<R: RangeBounds<u64>>(&self, versions: R) -> bool {
+        self.snapshots.range(versions).next().is_some()
+    }
+
+    /// Keep the entry of `key` at `pos`, replaced at version `until`, if a
+    /// snapshot can see it.
+    fn keep(&mut self, key: &[u8], pos: CommandPos, until: u64) {
+        if self.is_seen(pos.version..until) {
+            self.superseded
+                .entry(key.to_vec())
+                .or_default()
+                .push((pos, until));
+        }
+    }
+
+    /// The entry of `key` as of `version`, given its current one.
+    fn visible(
+        &self,
+        key: &[u8],
+        current: Option<&CommandPos>,
+        version: u64,
+    ) -> Option<CommandPos> {
+        match current {
+            Some(pos) if pos.version <= version => Some(*pos),
+            _ => self
+                .superseded
+                .get(key)?
+                .iter()
+                .find(|(pos, until)| pos.version <= version && version < *until)
+                .map(|(pos, _)| *pos),
+        }
+    }
+
+    /// Point a kept entry that compaction moved at its new position.
+    fn relocate(&mut self, key: &[u8], old: CommandPos, new: CommandPos) {
+        if let Some((pos, _)) = self
+            .superseded
+            .get_mut(key)
+            .and_then(|entries| entries.iter_mut().find(|(pos, _)| *pos == old))
+        {
+            *pos = new;
+        }
+    }
+
+    /// Register a snapshot reading at the latest version and return it.
+    fn acquire(&mut self) -> u64 {
+        *self.snapshots.entry(self.version).or_default() += 1;
+        self.version
+    }
+
+    /// Unregister a snapshot and forget the entries nothing can see anymore.
+    fn release(&mut self, version: u64) {
+        if let Some(count) = self.snapshots.get_mut(&version) {
+            *count -= 1;
+            if *count == 0 {
+                self.snapshots.remove(&version);
+            }
+        }
+        let snapshots = &self.snapshots;
+        self.superseded.retain(|_, entries| {
+            entries.retain(|(pos, until)| snapshots.range(pos.version..*until).next().is_some());
+            !entries.is_empty()
+        });
+    }
+}
+
+/// Tunables for a `KvStore`.
+#[derive(Clone, Copy, Debug)]
+pub struct KvStoreConfig {
+    /// Stale bytes that must pile up before a compaction is considered.
+    pub compaction_threshold: u64,
+    /// Minimum fraction of the log that must be stale for a compaction to run.
+    pub compaction_ratio: f64,
+    /// When writes are synced to disk.
+    pub durability: Durability,
+    /// How often expired keys are removed from the log.
+    pub sweep_interval: Duration,
+}
+
+impl Default for KvStoreConfig {
+    fn default() -> Self {
+        KvStoreConfig {
+            compaction_threshold: 1024 * 1024,
+            compaction_ratio: 0.5,
+            durability: Durability::default(),
+            sweep_interval: expiry::SWEEP_INTERVAL,
+        }
+    }
+}
+
+/// The `KvStore` stores key/value pairs.
+///
+/// Every `set` and `remove` is appended to a log file as a single command,
+/// and an in-memory index maps each key to the offset of its latest `set`.
+/// `open` rebuilds the index by replaying the log. `get` looks keys up in a
+/// lock-free copy of the index, so concurrent reads do not wait on each other
+/// or on writers.
+///
+/// Records are framed with their length and a CRC32, so a write torn by a
+/// crash only costs the records after it: `open` truncates the active
+/// generation at its last valid record. Only the active generation is ever
+/// appended to, so a damaged record in any other one fails `open` with
+/// `KvsError::Corruption` instead.
+///
+/// The log is split into generations (`<gen>.log`). Once enough stale
+/// records accumulate, a background thread copies the live entries into a
+/// fresh generation and deletes the old ones, while writers carry on in the
+/// generation after it. The copy is written under a temporary name and only
+/// renamed into place once it is on disk, so a crash mid-copy leaves nothing
+/// half-written behind.
+///
+/// Keys set with a time to live are hidden as soon as they expire. The same
+/// background thread removes them every `sweep_interval`, and compaction
+/// drops any it finds.
+///
+/// Snapshots read at the version of the latest write when they were taken.
+/// Writes carry on meanwhile: the entries they replace are kept in memory,
+/// and copied by compaction, until no snapshot can see them anymore.
+///
+/// The directory is locked while any handle to the store is open, so a
+/// second `open` of it, in this process or another, fails with
+/// `KvsError::Locked`.
+///
+/// Every write reaches the log file before it returns, so dropping a handle
+/// loses nothing. Dropping the last one also syncs the log to disk, unless
+/// the durability is `Durability::Never`.
 #[derive(Clone)]
 pub struct KvStore {
-    map: Arc<Mutex<HashMap<String, String>>>,
-    file: Arc<Mutex<File>>,
+    path: Arc<PathBuf>,
+    config: KvStoreConfig,
+    index: Arc<RwLock<Index>>,
+    /// The lock-free side of `index`, for `get`.
+    lookup: Arc<HashMap<Vec<u8>, CommandPos>>,
+    /// Lock after `index` when both are needed.
+    history: Arc<Mutex<History>>,
+    readers: Arc<RwLock<BTreeMap<u64, Arc<Generation>>>>,
+    writer: Arc<Mutex<LogWriter>>,
+    compaction: Arc<Mutex<()>>,
+    compactor: Option<Arc<Compactor>>,
+    /// Syncs the log in the background under `Durability::EveryN`.
+    flusher: Option<Arc<Periodic>>,
+    commit: Arc<GroupCommit>,
+    discarded: u64,
+    /// Declared last so the lock outlives everything else.
+    _lock: Arc<DirLock>,
+}
+
+/// The compaction thread, stopped and joined once the last handle to the
+/// store is dropped.
+struct Compactor {
+    sender: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for Compactor {
+    fn drop(&mut self) {
+        // Disconnecting the channel tells the thread to exit.
+        self.sender.take();
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("Compactor thread panicked");
+            }
+        }
+    }
+}
+
+/// The active generation and the bookkeeping that goes with it.
+struct LogWriter {
+    gen: u64,
+    writer: BufWriterWithPos<File>,
+    durability: Durability,
+    /// Size of every generation on disk.
+    sizes: BTreeMap<u64, u64>,
+    /// Bytes per generation that belong to overwritten or removed keys.
+    uncompacted: BTreeMap<u64, u64>,
+    /// The version of the latest write.
+    version: u64,
+}
+
+impl LogWriter {
+    /// Flush the active generation and sync it to disk.
+    fn sync(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        Ok(self.writer.get_ref().sync_data()?)
+    }
+
+    fn next_version(&mut self) -> u64 {
+        self.version += 1;
+        self.version
+    }
+
+    fn mark_stale(&mut self, pos: CommandPos) {
+        *self.uncompacted.entry(pos.gen).or_default() += pos.share;
+    }
+
+    fn needs_compaction(&self, config: &KvStoreConfig) -> bool {
+        let uncompacted: u64 = self.uncompacted.values().sum();
+        let total: u64 = self.sizes.values().sum();
+        uncompacted >= config.compaction_threshold
+            && uncompacted as f64 >= total as f64 * config.compaction_ratio
+    }
+}
+
+impl Drop for LogWriter {
+    fn drop(&mut self) {
+        if self.durability != Durability::Never {
+            if let Err(e) = self.sync() {
+                error!("Failed to sync log on close: {}", e);
+            }
+        }
+    }
+}
+
+/// Sync the active generation without holding the writer lock during the
+/// sync itself.
+fn sync_log(writer: &Mutex<LogWriter>) -> Result<()> {
+    let file = {
+        let mut writer = lock(writer);
+        writer.writer.flush()?;
+        writer.writer.get_ref().try_clone()?
+    };
+    Ok(file.sync_data()?)
 }
 
 impl KvStore {
-    /// Create a new `KvStore`
+    /// Open a KvStore in the current directory, panicking if it cannot be.
+    ///
+    /// Kept for callers of older releases. It is not `Default`, which should
+    /// not touch the file system.
+    #[deprecated(note = "use `KvStore::open`, which returns errors instead of panicking")]
+    #[allow(clippy::new_without_default)]
     pub fn new() -> KvStore {
-        KvStore {
-            map: Arc::new(Mutex::new(HashMap::new())),
-            file: Arc::new(Mutex::new(File::create("store").unwrap())),
-        }
+        Self::open(".").expect("Failed to open a KvStore in the current directory")
     }
 
     /// Open a KvStore at a given path.
     pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
+        Self::open_with_config(path, KvStoreConfig::default())
+    }
+
+    /// Open a KvStore at a given path with the given configuration.
+    pub fn open_with_config(path: impl Into<PathBuf>, config: KvStoreConfig) -> Result<KvStore> {
         let path = path.into();
-        if !path.exists() {
-            std::fs::create_dir_all(&path).map_err(|e| e.to_string())?;
-        }
-        let mut fs = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(path.join("store"))
-            .map_err(|e| e.to_string())?;
-        let mut buf = String::new();
-
-        Ok(KvStore {
-            map: match fs.read_to_string(&mut buf) {
-                Ok(_) => Arc::new(Mutex::new(
-                    serde_json::from_str(buf.as_str()).unwrap_or(HashMap::new()),
-                )),
-                Err(_) => Arc::new(Mutex::new(HashMap::new())),
-            },
-            file: Arc::new(Mutex::new(fs)),
-        })
+        fs::create_dir_all(&path)?;
+        let lock = DirLock::acquire(&path)?;
+
+        let mut index = Index::default();
+        let mut readers = BTreeMap::new();
+        let mut sizes = BTreeMap::new();
+        let mut uncompacted = BTreeMap::new();
+        let mut discarded = 0;
+        let mut version = 0;
+        remove_unfinished_compactions(&path)?;
+        migrate_legacy_log(&path)?;
+        let gens = sorted_gens(&path)?;
+        for &gen in &gens {
+            let mut reader = BufReader::new(File::open(log_path(&path, gen))?);
+            let (size, stale) = load(gen, &mut reader, &mut index, &mut version)?;
+            let file_len = reader.get_ref().metadata()?.len();
+            if size < file_len && Some(&gen) != gens.last() {
+                return Err(KvsError::Corruption(format!(
+                    "Unreadable record at offset {} of {}",
+                    size,
+                    log_path(&path, gen).display()
+                )));
+            }
+            if size < file_len {
+                warn!(
+                    "Discarding {} corrupt bytes at the end of {}",
+                    file_len - size,
+                    log_path(&path, gen).display()
+                );
+                OpenOptions::new()
+                    .write(true)
+                    .open(log_path(&path, gen))
+                    .and_then(|file| file.set_len(size))?;
+                discarded += file_len - size;
+            }
+            for (gen, len) in stale {
+                *uncompacted.entry(gen).or_default() += len;
+            }
+            sizes.insert(gen, size);
+            readers.insert(
+                gen,
+                Arc::new(Generation::new(log_path(&path, gen), reader.into_inner())),
+            );
+        }
+
+        let gen = gens.last().copied().unwrap_or(1);
+        let writer = new_log_file(&path, gen, &mut readers)?;
+        sizes.insert(gen, writer.pos);
+
+        let mut store = KvStore {
+            path: Arc::new(path),
+            config,
+            lookup: index.lookup.clone(),
+            index: Arc::new(RwLock::new(index)),
+            history: Arc::new(Mutex::new(History {
+                version,
+                ..History::default()
+            })),
+            readers: Arc::new(RwLock::new(readers)),
+            writer: Arc::new(Mutex::new(LogWriter {
+                gen,
+                writer,
+                durability: config.durability,
+                sizes,
+                uncompacted,
+                version,
+            })),
+            compaction: Arc::new(Mutex::new(())),
+            compactor: None,
+            flusher: None,
+            commit: Arc::new(GroupCommit::default()),
+            discarded,
+            _lock: Arc::new(lock),
+        };
+        store.compactor = Some(store.spawn_compactor()?);
+        if let Durability::EveryN(ms) = config.durability {
+            store.flusher = Some(Arc::new(spawn_periodic(
+                "kvs-flusher",
+                Duration::from_millis(ms),
+                Arc::downgrade(&store.writer),
+                sync_log,
+            )?));
+        }
+        Ok(store)
     }
 
-    /// Store the map to the file.
-    fn store(&self) -> Result<()> {
-        'outer: loop {
-            match self.file.try_lock() {
-                Ok(mut fs) => loop {
-                    match self.map.try_lock() {
-                        Ok(map) => {
-                            let buf = serde_json::to_string(&*map).map_err(|e| e.to_string())?;
-                            fs.set_len(0).map_err(|e| e.to_string())?;
-                            fs.seek(SeekFrom::Start(0)).map_err(|e| e.to_string())?;
-                            fs.write_all(buf.as_bytes()).map_err(|e| e.to_string())?;
-                            break 'outer;
+    /// Number of bytes of torn or corrupt records `open` dropped from the end
+    /// of the active generation.
+    pub fn discarded_bytes(&self) -> u64 {
+        self.discarded
+    }
+
+    /// Start the background thread that compacts the log when asked to and
+    /// sweeps out expired keys in between.
+    ///
+    /// The thread exits once every handle to the store has been dropped.
+    fn spawn_compactor(&self) -> Result<Arc<Compactor>> {
+        let (sender, receiver) = channel::bounded(1);
+        let store = KvStore {
+            compactor: None,
+            ..self.clone()
+        };
+        let thread = thread::Builder::new()
+            .name("kvs-compactor".to_string())
+            .spawn(move || loop {
+                match receiver.recv_timeout(store.config.sweep_interval) {
+                    Ok(()) => {
+                        if let Err(e) = store.compact() {
+                            error!("Compaction failed: {}", e);
                         }
-                        Err(std::sync::TryLockError::WouldBlock) => continue,
-                        Err(_) => panic!("Poisoned lock"),
                     }
-                },
-                Err(std::sync::TryLockError::WouldBlock) => continue,
-                Err(_) => panic!("Poisoned lock"),
+                    Err(RecvTimeoutError::Timeout) => {
+                        if let Err(e) = store.sweep() {
+                            error!("Sweeping expired keys failed: {}", e);
+                        }
+                    }
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+            })?;
+        Ok(Arc::new(Compactor {
+            sender: Some(sender),
+            thread: Some(thread),
+        }))
+    }
+
+    /// Rewrite the live entries into a new generation and drop the stale ones.
+    ///
+    /// Writers are only blocked while the new generations are created; the
+    /// copy itself runs concurrently with `set`, `get` and `remove`.
+    pub fn compact(&self) -> Result<()> {
+        let _compaction = lock(&self.compaction);
+
+        let (compaction_gen, high_water) = {
+            let mut writer = lock(&self.writer);
+            let mut readers = write_lock(&self.readers);
+            if writer.durability != Durability::Never {
+                writer.sync()?;
             }
+            let compaction_gen = writer.gen + 1;
+            writer.gen += 2;
+            writer.writer = new_log_file(&self.path, writer.gen, &mut readers)?;
+            let gen = writer.gen;
+            writer.sizes.insert(gen, 0);
+            (compaction_gen, writer.version)
+        };
+        let unfinished = compaction_path(&self.path, compaction_gen);
+        let mut compaction_writer = BufWriterWithPos::new(
+            OpenOptions::new()
+                .create(true)
+                .write(true)
+                .truncate(true)
+                .open(&unfinished)?,
+        )?;
+        let payload = serde_json::to_vec(&Command::HighWater {
+            version: high_water,
+        })?;
+        write_record(&mut compaction_writer, &payload)?;
+
+        let now = now_millis();
+        let mut expired = Vec::new();
+        let (entries, removed) = {
+            let index = read_lock(&self.index);
+            let history = lock(&self.history);
+            let live: Vec<(Vec<u8>, CommandPos)> = index
+                .iter()
+                .filter(|(_, pos)| pos.gen < compaction_gen)
+                .filter(|(key, pos)| {
+                    // Expired keys are dropped unless a snapshot can still
+                    // see them.
+                    let drop = pos.is_expired(now) && !history.is_seen(pos.version..);
+                    if drop {
+                        expired.push(((*key).clone(), **pos));
+                    }
+                    !drop
+                })
+                .map(|(key, pos)| (key.clone(), *pos))
+                .collect();
+            let kept: Vec<(Vec<u8>, CommandPos)> = history
+                .superseded
+                .iter()
+                .flat_map(|(key, entries)| entries.iter().map(move |(pos, _)| (key, pos)))
+                .filter(|(_, pos)| pos.gen < compaction_gen)
+                .map(|(key, pos)| (key.clone(), *pos))
+                .collect();
+            // Replay goes in file order, so entries kept for snapshots come
+            // first and the live ones override them. Keys with no live entry
+            // were removed since, and get a removal after their old entries.
+            let removed: BTreeSet<Vec<u8>> = kept
+                .iter()
+                .map(|(key, _)| key)
+                .filter(|key| live.binary_search_by(|(live, _)| live.cmp(key)).is_err())
+                .cloned()
+                .collect();
+            (kept.into_iter().chain(live).collect::<Vec<_>>(), removed)
+        };
+        let mut moved = Vec::with_capacity(entries.len());
+        for (key, pos) in entries {
+            let new_pos = self.copy_entry(&mut compaction_writer, compaction_gen, &key, pos)?;
+            moved.push((key, pos, new_pos));
+        }
+        let mut stale = 0;
+        for key in removed {
+            let start = compaction_writer.pos;
+            let payload = serde_json::to_vec(&Command::Remove {
+                key,
+                version: high_water,
+            })?;
+            write_record(&mut compaction_writer, &payload)?;
+            stale += compaction_writer.pos - start;
         }
+        // The old generations are deleted below, so the copy must be on disk
+        // whatever the durability setting.
+        compaction_writer.flush()?;
+        compaction_writer.get_ref().sync_data()?;
+        let finished = log_path(&self.path, compaction_gen);
+        fs::rename(&unfinished, &finished)?;
+        sync_dir(&self.path)?;
+        let file = File::open(&finished)?;
+        write_lock(&self.readers).insert(compaction_gen, Arc::new(Generation::new(finished, file)));
 
+        let stale_gens: Vec<u64> = {
+            let mut index = write_lock(&self.index);
+            let mut history = lock(&self.history);
+            for (key, old_pos, new_pos) in moved {
+                if index.get(&key) != Some(&old_pos) {
+                    // Replaced since, or copied for a snapshot.
+                    history.relocate(&key, old_pos, new_pos);
+                    stale += new_pos.share;
+                } else if old_pos.is_expired(now) {
+                    // Copied only for a snapshot that can still see it.
+                    index.remove(&key);
+                    history.keep(&key, new_pos, u64::MAX);
+                    stale += new_pos.share;
+                } else {
+                    index.insert(key, new_pos);
+                }
+            }
+            // Expired keys are not copied. Every record of theirs is in a
+            // generation deleted below, so they stay gone after a restart.
+            for (key, old_pos) in expired {
+                if index.get(&key) == Some(&old_pos) {
+                    index.remove(&key);
+                }
+            }
+            // The index already points at the new generation. Readers still
+            // in the middle of a stale one have pinned it, and the file is
+            // deleted once the last of them is done.
+            let mut readers = write_lock(&self.readers);
+            let stale_gens: Vec<u64> = readers
+                .range(..compaction_gen)
+                .map(|(gen, _)| *gen)
+                .collect();
+            for gen in &stale_gens {
+                if let Some(generation) = readers.remove(gen) {
+                    generation.retire();
+                }
+            }
+            stale_gens
+        };
+
+        let mut writer = lock(&self.writer);
+        for gen in &stale_gens {
+            writer.sizes.remove(gen);
+            writer.uncompacted.remove(gen);
+        }
+        writer.sizes.insert(compaction_gen, compaction_writer.pos);
+        if stale > 0 {
+            writer.uncompacted.insert(compaction_gen, stale);
+        }
+        debug!(
+            "compacted generations {:?} into {}",
+            stale_gens, compaction_gen
+        );
         Ok(())
     }
-}
 
-impl KvsEngine for KvStore {
-    /// Set the value of a string key to a string.
-    fn set(&self, key: String, value: String) -> Result<()> {
-        loop {
-            match self.map.try_lock() {
-                Ok(mut map) => {
-                    map.insert(key.clone(), value.clone());
-                    break;
+    /// Copy the entry of `key` at `pos` to the end of a compaction generation,
+    /// returning its new position.
+    fn copy_entry(
+        &self,
+        writer: &mut BufWriterWithPos<File>,
+        gen: u64,
+        key: &[u8],
+        pos: CommandPos,
+    ) -> Result<CommandPos> {
+        let new_pos = writer.pos;
+        if pos.is_shared() {
+            // Give the key a record of its own so the rest of the batch can
+            // be dropped.
+            let value = self.read_value(key, pos)?;
+            let payload = serde_json::to_vec(&Command::Set {
+                key: key.to_vec(),
+                value,
+                version: pos.version,
+                expires_at: pos.expires_at,
+            })?;
+            write_record(writer, &payload)?;
+        } else {
+            let generation = read_lock(&self.readers)
+                .get(&pos.gen)
+                .cloned()
+                .ok_or_else(missing_generation)?;
+            io::copy(
+                &mut ReadAt::new(&generation.file, pos.pos).take(pos.len),
+                writer,
+            )?;
+        }
+        let len = writer.pos - new_pos;
+        Ok(CommandPos {
+            gen,
+            pos: new_pos,
+            len,
+            share: len,
+            ..pos
+        })
+    }
+
+    /// Append a command to the active generation, returning its position and
+    /// its sequence number for `sync`.
+    fn append(&self, writer: &mut LogWriter, command: &Command) -> Result<(CommandPos, u64)> {
+        let pos = writer.writer.pos;
+        let payload = serde_json::to_vec(command)?;
+        write_record(&mut writer.writer, &payload)?;
+        writer.writer.flush()?;
+        let gen = writer.gen;
+        writer.sizes.insert(gen, writer.writer.pos);
+        let len = writer.writer.pos - pos;
+        Ok((
+            CommandPos {
+                gen,
+                pos,
+                len,
+                share: len,
+                version: command.version(),
+                expires_at: command.expires_at(),
+            },
+            self.commit.record(),
+        ))
+    }
+
+    /// Append a command and point the index at it, returning its sequence
+    /// number for `sync`.
+    fn write(&self, writer: &mut LogWriter, command: Command) -> Result<u64> {
+        let (pos, seq) = self.append(writer, &command)?;
+        let version = command.version();
+        let mut stale = Vec::new();
+        {
+            let mut index = write_lock(&self.index);
+            let mut history = lock(&self.history);
+            apply_command(&mut index, command, pos, &mut |key, old| {
+                // A removal makes its own record stale, but replaces nothing.
+                if old.version < version {
+                    history.keep(key, old, version);
                 }
-                Err(std::sync::TryLockError::WouldBlock) => continue,
-                Err(_) => panic!("Poisoned lock"),
+                stale.push(old);
+            });
+            // Expiry changes reuse an older version.
+            history.version = history.version.max(version);
+        }
+        for old in stale {
+            writer.mark_stale(old);
+        }
+        self.maybe_compact(writer);
+        Ok(seq)
+    }
+
+    /// The value of `key` and where it lives, unless the key is missing or
+    /// expired.
+    fn lookup(&self, key: &[u8]) -> Result<Option<(Vec<u8>, CommandPos)>> {
+        let index = read_lock(&self.index);
+        match index.get(key) {
+            Some(pos) if !pos.is_expired(now_millis()) => {
+                Ok(Some((self.read_value(key, *pos)?, *pos)))
             }
+            _ => Ok(None),
         }
-        self.store()?;
-        trace!("set:\t{}", key);
-        Ok(())
     }
 
-    /// Get the string value of a string key. If the key does not exist, return None.
-    fn get(&self, key: String) -> Result<Option<String>> {
+    /// Write a `set` command at the next version.
+    fn write_set(
+        &self,
+        writer: &mut LogWriter,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        expires_at: Option<u64>,
+    ) -> Result<u64> {
+        let version = writer.next_version();
+        self.write(
+            writer,
+            Command::Set {
+                key,
+                value,
+                version,
+                expires_at,
+            },
+        )
+    }
+
+    /// Rewrite a live key with a new expiry time, keeping its version.
+    /// Snapshots that can see the key see the new expiry time too.
+    fn set_expiry(&self, key: Vec<u8>, expires_at: Option<u64>) -> Result<()> {
+        let seq = {
+            let mut writer = lock(&self.writer);
+            let (value, pos) = self.lookup(&key)?.ok_or(KvsError::KeyNotFound)?;
+            if pos.expires_at == expires_at {
+                return Ok(());
+            }
+            let command = Command::Set {
+                key,
+                value,
+                version: pos.version,
+                expires_at,
+            };
+            self.write(&mut writer, command)?
+        };
+        self.sync(seq)
+    }
+
+    /// Remove every expired key with a single log record.
+    fn sweep(&self) -> Result<()> {
+        let seq = {
+            let mut writer = lock(&self.writer);
+            let now = now_millis();
+            let batch = read_lock(&self.index)
+                .iter()
+                .filter(|(_, pos)| pos.is_expired(now))
+                .fold(WriteBatch::new(), |batch, (key, _)| {
+                    batch.remove(key.clone())
+                });
+            if batch.is_empty() {
+                return Ok(());
+            }
+            debug!("sweeping {} expired keys", batch.len());
+            let version = writer.next_version();
+            self.write(&mut writer, Command::Batch { batch, version })?
+        };
+        self.sync(seq)
+    }
+
+    /// Wait until the write numbered `seq` is durable, if the configuration
+    /// asks for it.
+    fn sync(&self, seq: u64) -> Result<()> {
+        if self.config.durability != Durability::Always {
+            return Ok(());
+        }
+        self.commit.sync(seq, || sync_log(&self.writer))
+    }
+
+    /// Read the value `key` was set to by the record at `pos`. The caller
+    /// holds the index lock, so compaction cannot retire the generation
+    /// meanwhile.
+    fn read_value(&self, key: &[u8], pos: CommandPos) -> Result<Vec<u8>> {
+        self.try_read_value(key, pos)?
+            .ok_or_else(missing_generation)
+    }
+
+    /// Like `read_value`, but `None` if compaction has retired the
+    /// generation, for callers that do not hold the index lock.
+    fn try_read_value(&self, key: &[u8], pos: CommandPos) -> Result<Option<Vec<u8>>> {
+        let Some(generation) = read_lock(&self.readers).get(&pos.gen).cloned() else {
+            return Ok(None);
+        };
+        generation.read_value(key, pos).map(Some)
+    }
+
+    /// Keep the generations in `gens` from being deleted until the result is
+    /// dropped, so their records can be read without the index lock. The
+    /// caller holds the index lock.
+    fn pin(&self, gens: impl IntoIterator<Item = u64>) -> Result<Pinned> {
+        let readers = read_lock(&self.readers);
+        gens.into_iter()
+            .map(|gen| {
+                let generation = readers.get(&gen).ok_or_else(missing_generation)?;
+                Ok((gen, generation.clone()))
+            })
+            .collect()
+    }
+
+    /// Wake up the compactor if enough of the log is stale.
+    fn maybe_compact(&self, writer: &LogWriter) {
+        if !writer.needs_compaction(&self.config) {
+            return;
+        }
+        if let Some(sender) = self.compactor.as_ref().and_then(|c| c.sender.as_ref()) {
+            match sender.try_send(()) {
+                // A full channel means a compaction is already pending.
+                Ok(()) | Err(TrySendError::Full(())) => {}
+                Err(TrySendError::Disconnected(())) => error!("Compactor thread is gone"),
+            }
+        }
+    }
+}
+
+impl KvsEngine for KvStore {
+    const NAME: &'static str = "kvs";
+
+    type Snapshot = KvStoreSnapshot;
+
+    /// Set the value of a key.
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        trace!("set:\t{}", String::from_utf8_lossy(&key));
+        let seq = self.write_set(&mut lock(&self.writer), key, value, None)?;
+        self.sync(seq)
+    }
+
+    /// Get the value of a key. If the key does not exist, return None.
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
         let value = loop {
-            match self.map.try_lock() {
-                Ok(map) => break map.get_key_value(key.as_str()).map(|(_, v)| v.to_string()),
-                Err(std::sync::TryLockError::WouldBlock) => continue,
-                Err(_) => panic!("Poisoned lock"),
+            let pos = match self.lookup.get(&key) {
+                Some((_, pos)) if !pos.is_expired(now_millis()) => pos,
+                _ => break None,
+            };
+            // Without the index lock, compaction can move the key and delete
+            // its generation under us. The new position is in `lookup` by
+            // then.
+            if let Some(value) = self.try_read_value(&key, pos)? {
+                break Some(value);
             }
         };
-        trace!("get:\t{}", key);
+        trace!("get:\t{}", String::from_utf8_lossy(&key));
         Ok(value)
     }
 
     /// Remove a key.
-    fn remove(&self, key: String) -> Result<()> {
-        let result = loop {
-            match self.map.try_lock() {
-                Ok(mut map) => break map.remove(key.as_str()),
-                Err(std::sync::TryLockError::WouldBlock) => continue,
-                Err(_) => panic!("Poisoned lock"),
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        trace!("remove:\t{}", String::from_utf8_lossy(&key));
+        let seq = {
+            let mut writer = lock(&self.writer);
+            match read_lock(&self.index).get(&key) {
+                Some(pos) if !pos.is_expired(now_millis()) => {}
+                _ => return Err(KvsError::KeyNotFound),
+            }
+            let version = writer.next_version();
+            self.write(&mut writer, Command::Remove { key, version })?
+        };
+        self.sync(seq)
+    }
+
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<()> {
+        let seq = {
+            // Every write takes the writer lock, so the value cannot change
+            // between the check and the write.
+            let mut writer = lock(&self.writer);
+            let current = self.lookup(&key)?.map(|(value, _)| value);
+            if current != expected {
+                return Err(KvsError::Conflict { current });
+            }
+            match new {
+                Some(value) => self.write_set(&mut writer, key, value, None)?,
+                None if current.is_some() => {
+                    let version = writer.next_version();
+                    self.write(&mut writer, Command::Remove { key, version })?
+                }
+                None => return Ok(()),
+            }
+        };
+        self.sync(seq)
+    }
+
+    fn get_with_version_bytes(&self, key: Vec<u8>) -> Result<Option<(Vec<u8>, u64)>> {
+        Ok(self.lookup(&key)?.map(|(value, pos)| (value, pos.version)))
+    }
+
+    fn set_if_version_bytes(&self, key: Vec<u8>, value: Vec<u8>, version: u64) -> Result<()> {
+        let seq = {
+            let mut writer = lock(&self.writer);
+            match self.lookup(&key)? {
+                Some((_, pos)) if pos.version == version => {}
+                current => {
+                    return Err(KvsError::Conflict {
+                        current: current.map(|(value, _)| value),
+                    })
+                }
+            }
+            self.write_set(&mut writer, key, value, None)?
+        };
+        self.sync(seq)
+    }
+
+    fn set_with_ttl_bytes(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let expires_at = expiry::expires_at(ttl);
+        let seq = self.write_set(&mut lock(&self.writer), key, value, Some(expires_at))?;
+        self.sync(seq)
+    }
+
+    fn expire_bytes(&self, key: Vec<u8>, ttl: Duration) -> Result<()> {
+        self.set_expiry(key, Some(expiry::expires_at(ttl)))
+    }
+
+    fn ttl_bytes(&self, key: Vec<u8>) -> Result<Option<Duration>> {
+        match read_lock(&self.index).get(&key) {
+            Some(pos) if !pos.is_expired(now_millis()) => Ok(pos.expires_at.map(expiry::remaining)),
+            _ => Err(KvsError::KeyNotFound),
+        }
+    }
+
+    fn persist_bytes(&self, key: Vec<u8>) -> Result<()> {
+        self.set_expiry(key, None)
+    }
+
+    /// List every key, in ascending order.
+    fn keys_bytes(&self) -> Result<Vec<Vec<u8>>> {
+        let now = now_millis();
+        Ok(read_lock(&self.index)
+            .iter()
+            .filter(|(_, pos)| !pos.is_expired(now))
+            .map(|(key, _)| key.clone())
+            .collect())
+    }
+
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(
+        &self,
+        range: R,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        if is_empty_range(&range) {
+            return Ok(Vec::new());
+        }
+        let now = now_millis();
+        let (positions, pinned) = {
+            let index = read_lock(&self.index);
+            let positions: Vec<(Vec<u8>, CommandPos)> = index
+                .range(range)
+                .filter(|(_, pos)| !pos.is_expired(now))
+                .take(limit)
+                .map(|(key, pos)| (key.clone(), *pos))
+                .collect();
+            let pinned = self.pin(positions.iter().map(|(_, pos)| pos.gen))?;
+            (positions, pinned)
+        };
+        read_pinned(&pinned, positions)
+    }
+
+    /// Apply every write in `batch` as one log record.
+    fn apply_batch(&self, batch: WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let seq = {
+            let mut writer = lock(&self.writer);
+            let version = writer.next_version();
+            self.write(&mut writer, Command::Batch { batch, version })?
+        };
+        self.sync(seq)
+    }
+
+    fn snapshot(&self) -> Result<KvStoreSnapshot> {
+        let version = lock(&self.history).acquire();
+        Ok(KvStoreSnapshot {
+            store: self.clone(),
+            version,
+            now: now_millis(),
+        })
+    }
+
+    fn commit_transaction(
+        &self,
+        reads: &BTreeMap<Vec<u8>, Option<u64>>,
+        batch: WriteBatch,
+    ) -> Result<()> {
+        let seq = {
+            let mut writer = lock(&self.writer);
+            {
+                let now = now_millis();
+                let index = read_lock(&self.index);
+                for (key, version) in reads {
+                    let current = index
+                        .get(key)
+                        .filter(|pos| !pos.is_expired(now))
+                        .map(|pos| pos.version);
+                    if current != *version {
+                        return Err(KvsError::TransactionConflict { key: key.clone() });
+                    }
+                }
             }
+            if batch.is_empty() {
+                return Ok(());
+            }
+            let version = writer.next_version();
+            self.write(&mut writer, Command::Batch { batch, version })?
+        };
+        self.sync(seq)
+    }
+
+    /// Sync the active generation to disk.
+    fn flush(&self) -> Result<()> {
+        sync_log(&self.writer)
+    }
+}
+
+/// A frozen view of a `KvStore`, from `KvsEngine::snapshot`.
+///
+/// Reads see the store as of the latest write when it was taken, and expiry
+/// as of that moment. Dropping it lets compaction reclaim what only it could
+/// see.
+pub struct KvStoreSnapshot {
+    store: KvStore,
+    version: u64,
+    now: u64,
+}
+
+impl KvStoreSnapshot {
+    fn visible(&self, index: &Index, history: &History, key: &[u8]) -> Option<CommandPos> {
+        history
+            .visible(key, index.get(key), self.version)
+            .filter(|pos| !pos.is_expired(self.now))
+    }
+}
+
+impl KvsSnapshot for KvStoreSnapshot {
+    fn get_with_version_bytes(&self, key: Vec<u8>) -> Result<Option<(Vec<u8>, u64)>> {
+        let (pos, pinned) = {
+            let index = read_lock(&self.store.index);
+            let Some(pos) = self.visible(&index, &lock(&self.store.history), &key) else {
+                return Ok(None);
+            };
+            (pos, self.store.pin([pos.gen])?)
+        };
+        let mut pairs = read_pinned(&pinned, vec![(key, pos)])?;
+        Ok(pairs.pop().map(|(_, value)| (value, pos.version)))
+    }
+
+    fn scan_with_expiry_bytes<R: RangeBounds<Vec<u8>>>(
+        &self,
+        range: R,
+        limit: usize,
+    ) -> Result<Vec<ExpiringPair>> {
+        if is_empty_range(&range) {
+            return Ok(Vec::new());
+        }
+        let range = (range.start_bound().cloned(), range.end_bound().cloned());
+        let (positions, pinned) = {
+            let index = read_lock(&self.store.index);
+            let history = lock(&self.store.history);
+            let positions: Vec<(Vec<u8>, CommandPos)> = merge_keys(
+                index.range(range.clone()).map(|(key, _)| key),
+                history.superseded.range(range).map(|(key, _)| key),
+            )
+            .filter_map(|key| Some((key.clone(), self.visible(&index, &history, key)?)))
+            .take(limit)
+            .collect();
+            let pinned = self.store.pin(positions.iter().map(|(_, pos)| pos.gen))?;
+            (positions, pinned)
+        };
+        let expiry: Vec<Option<u64>> = positions.iter().map(|(_, pos)| pos.expires_at).collect();
+        Ok(read_pinned(&pinned, positions)?
+            .into_iter()
+            .zip(expiry)
+            .map(|((key, value), expires_at)| (key, value, expires_at))
+            .collect())
+    }
+}
+
+impl Drop for KvStoreSnapshot {
+    fn drop(&mut self) {
+        lock(&self.store.history).release(self.version);
+    }
+}
+
+/// The index of a `KvStore`: every live key and where its value is.
+///
+/// The ordered map serves scans, compaction and writers, under the index
+/// lock. The hash map mirrors it for `get`, which reads it without taking
+/// any lock. Both are only changed through `insert` and `remove`.
+#[derive(Default)]
+struct Index {
+    ordered: BTreeMap<Vec<u8>, CommandPos>,
+    lookup: Arc<HashMap<Vec<u8>, CommandPos>>,
+}
+
+impl Index {
+    fn insert(&mut self, key: Vec<u8>, pos: CommandPos) -> Option<CommandPos> {
+        self.lookup.add(&key, &pos);
+        self.ordered.insert(key, pos)
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Option<CommandPos> {
+        let old = self.ordered.remove(key)?;
+        self.lookup.remove(&key.to_vec());
+        Some(old)
+    }
+}
+
+impl Deref for Index {
+    type Target = BTreeMap<Vec<u8>, CommandPos>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.ordered
+    }
+}
+
+/// Whether `range` cannot hold any key. `BTreeMap::range` panics on these.
+pub(super) fn is_empty_range<T: Ord, R: RangeBounds<T>>(range: &R) -> bool {
+    match (range.start_bound(), range.end_bound()) {
+        (Bound::Included(start), Bound::Included(end)) => start > end,
+        (Bound::Included(start) | Bound::Excluded(start), Bound::Excluded(end))
+        | (Bound::Excluded(start), Bound::Included(end)) => start >= end,
+        _ => false,
+    }
+}
+
+fn log_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.log", gen))
+}
+
+/// Where compaction writes a generation until it is complete. `sorted_gens`
+/// does not see these files.
+fn compaction_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.log.compacting", gen))
+}
+
+/// Delete what a compaction interrupted by a crash left behind. The
+/// generations it was copying are all still there.
+fn remove_unfinished_compactions(path: &Path) -> Result<()> {
+    for entry in fs::read_dir(path)? {
+        let path = entry?.path();
+        if path.extension() == Some(OsStr::new("compacting")) {
+            warn!("Removing unfinished compaction {}", path.display());
+            fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Name of the file older releases kept the whole store in, as one JSON map.
+const LEGACY_LOG: &str = "store";
+
+/// Move the pairs of a store written by an older release into generation 1.
+///
+/// The generation is written under a temporary name and renamed into place
+/// before the old file is deleted, so a crash at any point is recovered by
+/// the next `open`. Contents that cannot be read fail with
+/// `KvsError::Corruption` rather than being dropped.
+fn migrate_legacy_log(path: &Path) -> Result<()> {
+    let legacy = path.join(LEGACY_LOG);
+    if !legacy.is_file() {
+        return Ok(());
+    }
+    if !sorted_gens(path)?.is_empty() {
+        // Migrated already, and stopped before the old file was deleted.
+        fs::remove_file(&legacy)?;
+        return Ok(());
+    }
+    let contents = fs::read(&legacy)?;
+    // Older releases left the file empty until the first write.
+    let pairs: BTreeMap<String, String> = if contents.is_empty() {
+        BTreeMap::new()
+    } else {
+        serde_json::from_slice(&contents)
+            .map_err(|e| KvsError::Corruption(format!("Unreadable {}: {}", legacy.display(), e)))?
+    };
+
+    let unfinished = compaction_path(path, 1);
+    let mut writer = BufWriter::new(File::create(&unfinished)?);
+    for (version, (key, value)) in (1..).zip(pairs) {
+        let command = Command::Set {
+            key: key.into_bytes(),
+            value: value.into_bytes(),
+            version,
+            expires_at: None,
         };
-        self.store()?;
-        trace!("remove:\t{}", key);
-        match result {
-            Some(_) => Ok(()),
-            None => Err("Key not found".to_string()),
+        write_record(&mut writer, &serde_json::to_vec(&command)?)?;
+    }
+    writer.flush()?;
+    writer.get_ref().sync_data()?;
+    fs::rename(&unfinished, log_path(path, 1))?;
+    sync_dir(path)?;
+    fs::remove_file(&legacy)?;
+    info!(
+        "Migrated {} to {}",
+        legacy.display(),
+        log_path(path, 1).display()
+    );
+    Ok(())
+}
+
+/// Make renames in `dir` durable.
+fn sync_dir(dir: &Path) -> Result<()> {
+    // Directories cannot be opened as files on Windows, so this is left to
+    // the file system there.
+    #[cfg(unix)]
+    File::open(dir)?.sync_all()?;
+    #[cfg(not(unix))]
+    let _ = dir;
+    Ok(())
+}
+
+/// List the generations found in a directory, oldest first.
+fn sorted_gens(path: &Path) -> Result<Vec<u64>> {
+    let mut gens: Vec<u64> = fs::read_dir(path)?
+        .flat_map(|entry| entry.map(|entry| entry.path()))
+        .filter(|path| path.is_file() && path.extension() == Some(OsStr::new("log")))
+        .flat_map(|path| {
+            path.file_stem()
+                .and_then(OsStr::to_str)
+                .and_then(|stem| stem.parse::<u64>().ok())
+        })
+        .collect();
+    gens.sort_unstable();
+    Ok(gens)
+}
+
+/// Create the file for a new generation and register a reader for it.
+fn new_log_file(
+    path: &Path,
+    gen: u64,
+    readers: &mut BTreeMap<u64, Arc<Generation>>,
+) -> Result<BufWriterWithPos<File>> {
+    let path = log_path(path, gen);
+    let writer = BufWriterWithPos::new(OpenOptions::new().create(true).append(true).open(&path)?)?;
+    let file = File::open(&path)?;
+    readers.insert(gen, Arc::new(Generation::new(path, file)));
+    Ok(writer)
+}
+
+/// A log generation open for reading.
+///
+/// Compaction retires the generations it has copied, and the file is deleted
+/// once the last reader holding it lets go.
+struct Generation {
+    file: File,
+    /// Declared after `file`, so the file is closed before it is deleted.
+    cleanup: Cleanup,
+}
+
+struct Cleanup {
+    path: PathBuf,
+    retired: AtomicBool,
+}
+
+/// Generations kept open by a reader, by number.
+type Pinned = BTreeMap<u64, Arc<Generation>>;
+
+impl Generation {
+    fn new(path: PathBuf, file: File) -> Generation {
+        Generation {
+            file,
+            cleanup: Cleanup {
+                path,
+                retired: AtomicBool::new(false),
+            },
+        }
+    }
+
+    fn retire(&self) {
+        self.cleanup.retired.store(true, AtomicOrdering::SeqCst);
+    }
+
+    /// Read the value `key` was set to by the record at `pos`.
+    fn read_value(&self, key: &[u8], pos: CommandPos) -> Result<Vec<u8>> {
+        let payload = read_record(&mut ReadAt::new(&self.file, pos.pos))?
+            .ok_or_else(|| KvsError::Corruption("Unexpected end of log".to_string()))?;
+        match serde_json::from_slice(&payload)? {
+            Command::Set { value, .. } => Ok(value),
+            Command::Batch { batch, .. } => batch
+                .into_iter()
+                .filter_map(|op| match op {
+                    BatchOp::Set { key, value } => Some((key, value)),
+                    BatchOp::Remove { .. } => None,
+                })
+                .rfind(|(batch_key, _)| batch_key == key)
+                .map(|(_, value)| value)
+                .ok_or_else(|| KvsError::Corruption("Key missing from batch".to_string())),
+            Command::Remove { .. } | Command::HighWater { .. } => {
+                Err(KvsError::Corruption("Unexpected command".to_string()))
+            }
         }
     }
 }
 
-impl Drop for KvStore {
+impl Drop for Cleanup {
     fn drop(&mut self) {
-        self.store().unwrap();
+        if self.retired.load(AtomicOrdering::SeqCst) {
+            if let Err(e) = fs::remove_file(&self.path) {
+                error!("Failed to remove {}: {}", self.path.display(), e);
+            }
+        }
+    }
+}
+
+/// Read the values at `positions` from generations pinned by `KvStore::pin`.
+fn read_pinned(
+    pinned: &Pinned,
+    positions: Vec<(Vec<u8>, CommandPos)>,
+) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    positions
+        .into_iter()
+        .map(|(key, pos)| {
+            let generation = pinned.get(&pos.gen).ok_or_else(missing_generation)?;
+            let value = generation.read_value(&key, pos)?;
+            Ok((key, value))
+        })
+        .collect()
+}
+
+fn missing_generation() -> KvsError {
+    KvsError::Corruption("Missing log generation".to_string())
+}
+
+/// Replay one generation, filling the index.
+///
+/// Returns the size of the valid prefix of the generation and the stale bytes
+/// it made, keyed by the generation they live in. Replay stops at the first
+/// torn or corrupt record.
+fn load(
+    gen: u64,
+    reader: &mut BufReader<File>,
+    index: &mut Index,
+    version: &mut u64,
+) -> Result<(u64, Vec<(u64, u64)>)> {
+    reader.seek(SeekFrom::Start(0))?;
+    let mut stale = Vec::new();
+    let mut pos = 0;
+    loop {
+        let command = match read_record(reader) {
+            Ok(Some(payload)) => match serde_json::from_slice::<Command>(&payload) {
+                Ok(command) => command,
+                Err(_) => break,
+            },
+            Ok(None) => break,
+            Err(e) if record::is_corruption(&e) => break,
+            Err(e) => return Err(e.into()),
+        };
+        let new_pos = reader.stream_position()?;
+        let len = new_pos - pos;
+        let cmd_pos = CommandPos {
+            gen,
+            pos,
+            len,
+            share: len,
+            version: command.version(),
+            expires_at: command.expires_at(),
+        };
+        *version = (*version).max(command.version());
+        apply_command(index, command, cmd_pos, &mut |_, old| {
+            stale.push((old.gen, old.share))
+        });
+        pos = new_pos;
+    }
+    Ok((pos, stale))
+}
+
+/// Point the index at a command written at `pos`, passing every position it
+/// makes stale to `stale` along with its key.
+fn apply_command(
+    index: &mut Index,
+    command: Command,
+    pos: CommandPos,
+    stale: &mut impl FnMut(&[u8], CommandPos),
+) {
+    match command {
+        Command::Set { key, .. } => {
+            if let Some(old) = index.insert(key.clone(), pos) {
+                stale(&key, old);
+            }
+        }
+        Command::Remove { key, .. } => {
+            if let Some(old) = index.remove(&key) {
+                stale(&key, old);
+            }
+            stale(&key, pos);
+        }
+        Command::Batch { batch, .. } => {
+            let positions = pos.split(batch.len());
+            for (op, pos) in batch.into_iter().zip(positions) {
+                match op {
+                    BatchOp::Set { key, .. } => {
+                        if let Some(old) = index.insert(key.clone(), pos) {
+                            stale(&key, old);
+                        }
+                    }
+                    BatchOp::Remove { key } => {
+                        if let Some(old) = index.remove(&key) {
+                            stale(&key, old);
+                        }
+                        stale(&key, pos);
+                    }
+                }
+            }
+        }
+        Command::HighWater { .. } => {}
+    }
+}
+
+/// Merge two ascending sequences of keys, dropping duplicates.
+fn merge_keys<'a, K: Ord + 'a>(
+    a: impl Iterator<Item = &'a K>,
+    b: impl Iterator<Item = &'a K>,
+) -> impl Iterator<Item = &'a K> {
+    let mut a = a.peekable();
+    let mut b = b.peekable();
+    std::iter::from_fn(move || match (a.peek(), b.peek()) {
+        (Some(x), Some(y)) => match x.cmp(y) {
+            Ordering::Less => a.next(),
+            Ordering::Greater => b.next(),
+            Ordering::Equal => {
+                b.next();
+                a.next()
+            }
+        },
+        (Some(_), None) => a.next(),
+        (None, _) => b.next(),
+    })
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().expect("Poisoned lock")
+}
+
+fn read_lock<T>(lock: &RwLock<T>) -> RwLockReadGuard<'_, T> {
+    lock.read().expect("Poisoned lock")
+}
+
+fn write_lock<T>(lock: &RwLock<T>) -> RwLockWriteGuard<'_, T> {
+    lock.write().expect("Poisoned lock")
+}
+
+/// Reads a file from an offset on without moving its cursor, so that
+/// threads can read one handle at once.
+struct ReadAt<'a> {
+    file: &'a File,
+    pos: u64,
+}
+
+impl<'a> ReadAt<'a> {
+    fn new(file: &'a File, pos: u64) -> Self {
+        ReadAt { file, pos }
+    }
+}
+
+impl Read for ReadAt<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        #[cfg(unix)]
+        let len = std::os::unix::fs::FileExt::read_at(self.file, buf, self.pos)?;
+        #[cfg(windows)]
+        let len = std::os::windows::fs::FileExt::seek_read(self.file, buf, self.pos)?;
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+/// A `BufWriter` that keeps track of the current end of the file.
+struct BufWriterWithPos<W: Write + Seek> {
+    writer: BufWriter<W>,
+    pos: u64,
+}
+
+impl<W: Write + Seek> BufWriterWithPos<W> {
+    fn get_ref(&self) -> &W {
+        self.writer.get_ref()
+    }
+
+    fn new(mut inner: W) -> Result<Self> {
+        let pos = inner.seek(SeekFrom::End(0))?;
+        Ok(BufWriterWithPos {
+            writer: BufWriter::new(inner),
+            pos,
+        })
+    }
+}
+
+impl<W: Write + Seek> Write for BufWriterWithPos<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let len = self.writer.write(buf)?;
+        self.pos += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_reopen_replays_log() {
+        let dir = TempDir::new().unwrap();
+        let store = KvStore::open(dir.path()).unwrap();
+        store.set("a".to_string(), "1".to_string()).unwrap();
+        store.set("b".to_string(), "2".to_string()).unwrap();
+        store.set("a".to_string(), "3".to_string()).unwrap();
+        store.remove("b".to_string()).unwrap();
+        drop(store);
+
+        let store = KvStore::open(dir.path()).unwrap();
+        assert_eq!(store.get("a".to_string()).unwrap(), Some("3".to_string()));
+        assert_eq!(store.get("b".to_string()).unwrap(), None);
+    }
+
+    #[test]
+    fn test_remove_missing_key() {
+        let dir = TempDir::new().unwrap();
+        let store = KvStore::open(dir.path()).unwrap();
+        assert!(matches!(
+            store.remove("a".to_string()),
+            Err(KvsError::KeyNotFound)
+        ));
+    }
+
+    #[test]
+    fn test_second_open_is_locked_out() {
+        let dir = TempDir::new().unwrap();
+        let store = KvStore::open(dir.path()).unwrap();
+        assert!(matches!(
+            KvStore::open(dir.path()),
+            Err(KvsError::Locked { pid: Some(_), .. })
+        ));
+        let snapshot = store.snapshot().unwrap();
+        drop(store);
+        assert!(KvStore::open(dir.path()).is_err());
+        drop(snapshot);
+        KvStore::open(dir.path()).unwrap();
+    }
+
+    #[test]
+    fn test_open_truncates_torn_write() {
+        let dir = TempDir::new().unwrap();
+        let store = KvStore::open(dir.path()).unwrap();
+        store.set("a".to_string(), "1".to_string()).unwrap();
+        store.set("b".to_string(), "2".to_string()).unwrap();
+        drop(store);
+
+        let log = log_path(dir.path(), 1);
+        let len = fs::metadata(&log).unwrap().len();
+        let file = OpenOptions::new().write(true).open(&log).unwrap();
+        file.set_len(len - 3).unwrap();
+
+        let store = KvStore::open(dir.path()).unwrap();
+        assert!(store.discarded_bytes() > 0);
+        assert_eq!(store.get("a".to_string()).unwrap(), Some("1".to_string()));
+        assert_eq!(store.get("b".to_string()).unwrap(), None);
+        store.set("c".to_string(), "3".to_string()).unwrap();
+        drop(store);
+
+        let store = KvStore::open(dir.path()).unwrap();
+        assert_eq!(store.discarded_bytes(), 0);
+        assert_eq!(store.get("a".to_string()).unwrap(), Some("1".to_string()));
+        assert_eq!(store.get("c".to_string()).unwrap(), Some("3".to_string()));
+    }
+
+    #[test]
+    fn test_open_rejects_corruption_before_the_active_generation() {
+        let dir = TempDir::new().unwrap();
+        let store = KvStore::open(dir.path()).unwrap();
+        store.set("a".to_string(), "1".to_string()).unwrap();
+        store.compact().unwrap();
+        store.set("b".to_string(), "2".to_string()).unwrap();
+        drop(store);
+
+        let sealed = *sorted_gens(dir.path()).unwrap().first().unwrap();
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(log_path(dir.path(), sealed))
+            .unwrap();
+        file.write_all(b"garbage").unwrap();
+        drop(file);
+
+        assert!(matches!(
+            KvStore::open(dir.path()),
+            Err(KvsError::Corruption(_))
+        ));
+    }
+
+    #[test]
+    fn test_open_migrates_legacy_log() {
+        let dir = TempDir::new().unwrap();
+        fs::write(dir.path().join(LEGACY_LOG), r#"{"a":"1","b":"2"}"#).unwrap();
+
+        let store = KvStore::open(dir.path()).unwrap();
+        assert_eq!(store.get("a".to_string()).unwrap(), Some("1".to_string()));
+        assert_eq!(store.get("b".to_string()).unwrap(), Some("2".to_string()));
+        assert!(!dir.path().join(LEGACY_LOG).exists());
+        store.set("c".to_string(), "3".to_string()).unwrap();
+        drop(store);
+
+        let store = KvStore::open(dir.path()).unwrap();
+        assert_eq!(store.keys().unwrap(), ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn test_open_rejects_unreadable_legacy_log() {
+        let dir = TempDir::new().unwrap();
+        fs::write(dir.path().join(LEGACY_LOG), b"{\"a\":").unwrap();
+
+        assert!(matches!(
+            KvStore::open(dir.path()),
+            Err(KvsError::Corruption(_))
+        ));
+        assert!(dir.path().join(LEGACY_LOG).exists());
+    }
+
+    #[test]
+    fn test_open_removes_unfinished_compaction() {
+        let dir = TempDir::new().unwrap();
+        let store = KvStore::open(dir.path()).unwrap();
+        store.set("a".to_string(), "1".to_string()).unwrap();
+        drop(store);
+        fs::write(compaction_path(dir.path(), 2), b"half a record").unwrap();
+
+        let store = KvStore::open(dir.path()).unwrap();
+        assert_eq!(store.get("a".to_string()).unwrap(), Some("1".to_string()));
+        assert!(!compaction_path(dir.path(), 2).exists());
+    }
+
+    #[test]
+    fn test_compaction_shrinks_log() {
+        let dir = TempDir::new().unwrap();
+        let store = KvStore::open_with_config(
+            dir.path(),
+            KvStoreConfig {
+                compaction_threshold: u64::MAX,
+                ..KvStoreConfig::default()
+            },
+        )
+        .unwrap();
+        for i in 0..1000 {
+            store.set("key".to_string(), i.to_string()).unwrap();
+        }
+        store.set("other".to_string(), "value".to_string()).unwrap();
+        let dir_size = || -> u64 {
+            fs::read_dir(dir.path())
+                .unwrap()
+                .map(|entry| entry.unwrap().metadata().unwrap().len())
+                .sum()
+        };
+        let before = dir_size();
+        store.compact().unwrap();
+        assert!(dir_size() < before);
+        assert_eq!(
+            store.get("key".to_string()).unwrap(),
+            Some("999".to_string())
+        );
+        drop(store);
+
+        let store = KvStore::open(dir.path()).unwrap();
+        assert_eq!(
+            store.get("key".to_string()).unwrap(),
+            Some("999".to_string())
+        );
+        assert_eq!(
+            store.get("other".to_string()).unwrap(),
+            Some("value".to_string())
+        );
+    }
+
+    #[test]
+    fn test_gets_survive_compaction() {
+        let dir = TempDir::new().unwrap();
+        let store = KvStore::open_with_config(
+            dir.path(),
+            KvStoreConfig {
+                compaction_threshold: u64::MAX,
+                ..KvStoreConfig::default()
+            },
+        )
+        .unwrap();
+        for i in 0..100 {
+            store.set(i.to_string(), i.to_string()).unwrap();
+        }
+        thread::scope(|scope| {
+            for _ in 0..4 {
+                let store = store.clone();
+                scope.spawn(move || {
+                    for round in 0..2000 {
+                        let key = (round % 100).to_string();
+                        assert_eq!(store.get(key.clone()).unwrap(), Some(key));
+                    }
+                });
+            }
+            for _ in 0..20 {
+                store.set("other".to_string(), "value".to_string()).unwrap();
+                store.compact().unwrap();
+            }
+        });
+    }
+
+    #[test]
+    fn test_batch_survives_reopen_and_compaction() {
+        let dir = TempDir::new().unwrap();
+        let config = KvStoreConfig {
+            compaction_threshold: u64::MAX,
+            ..KvStoreConfig::default()
+        };
+        let store = KvStore::open_with_config(dir.path(), config).unwrap();
+        store.set("gone".to_string(), "0".to_string()).unwrap();
+        store
+            .apply_batch(
+                WriteBatch::new()
+                    .set("a".to_string(), "1".to_string())
+                    .set("b".to_string(), "2".to_string())
+                    .set("a".to_string(), "3".to_string())
+                    .remove("gone".to_string()),
+            )
+            .unwrap();
+        store.set("b".to_string(), "4".to_string()).unwrap();
+        drop(store);
+
+        let store = KvStore::open_with_config(dir.path(), config).unwrap();
+        assert_eq!(store.get("a".to_string()).unwrap(), Some("3".to_string()));
+        assert_eq!(store.get("b".to_string()).unwrap(), Some("4".to_string()));
+        assert_eq!(store.get("gone".to_string()).unwrap(), None);
+        store.compact().unwrap();
+        drop(store);
+
+        let store = KvStore::open_with_config(dir.path(), config).unwrap();
+        assert_eq!(store.keys().unwrap(), ["a", "b"]);
+        assert_eq!(store.get("a".to_string()).unwrap(), Some("3".to_string()));
+    }
+
+    #[test]
+    fn test_versions_survive_reopen() {
+        let dir = TempDir::new().unwrap();
+        let store = KvStore::open(dir.path()).unwrap();
+        store.set("a".to_string(), "1".to_string()).unwrap();
+        store.set("b".to_string(), "2".to_string()).unwrap();
+        let (_, version) = store.get_with_version("a".to_string()).unwrap().unwrap();
+        drop(store);
+
+        let store = KvStore::open(dir.path()).unwrap();
+        assert_eq!(
+            store.get_with_version("a".to_string()).unwrap(),
+            Some(("1".to_string(), version))
+        );
+        store.set("c".to_string(), "3".to_string()).unwrap();
+        let (_, newer) = store.get_with_version("c".to_string()).unwrap().unwrap();
+        assert!(newer > version + 1);
+
+        // Compaction drops the removal that reached the latest version.
+        store.remove("c".to_string()).unwrap();
+        store.compact().unwrap();
+        drop(store);
+        let store = KvStore::open(dir.path()).unwrap();
+        store.set("d".to_string(), "4".to_string()).unwrap();
+        let (_, newest) = store.get_with_version("d".to_string()).unwrap().unwrap();
+        assert!(newest > newer + 1);
+    }
+
+    #[test]
+    fn test_expired_keys_are_reclaimed() {
+        let dir = TempDir::new().unwrap();
+        let config = KvStoreConfig {
+            compaction_threshold: u64::MAX,
+            sweep_interval: Duration::from_secs(3600),
+            ..KvStoreConfig::default()
+        };
+        let store = KvStore::open_with_config(dir.path(), config).unwrap();
+        let ttl = Duration::from_millis(20);
+        store
+            .set_with_ttl("a".to_string(), "1".to_string(), ttl)
+            .unwrap();
+        store
+            .set_with_ttl("b".to_string(), "2".to_string(), ttl)
+            .unwrap();
+        store.set("c".to_string(), "3".to_string()).unwrap();
+        thread::sleep(ttl * 2);
+
+        store.compact().unwrap();
+        assert!(!read_lock(&store.index).contains_key(&b"a"[..]));
+        store
+            .set_with_ttl("d".to_string(), "4".to_string(), ttl)
+            .unwrap();
+        thread::sleep(ttl * 2);
+        store.sweep().unwrap();
+        assert_eq!(read_lock(&store.index).keys().collect::<Vec<_>>(), [b"c"]);
+        drop(store);
+
+        let store = KvStore::open_with_config(dir.path(), config).unwrap();
+        assert_eq!(read_lock(&store.index).keys().collect::<Vec<_>>(), [b"c"]);
+    }
+
+    #[test]
+    fn test_snapshot_survives_compaction() {
+        let dir = TempDir::new().unwrap();
+        let store = KvStore::open_with_config(
+            dir.path(),
+            KvStoreConfig {
+                compaction_threshold: u64::MAX,
+                sweep_interval: Duration::from_secs(3600),
+                ..KvStoreConfig::default()
+            },
+        )
+        .unwrap();
+        store.set("a".to_string(), "old".to_string()).unwrap();
+        store.set("b".to_string(), "old".to_string()).unwrap();
+        store
+            .set_with_ttl(
+                "c".to_string(),
+                "old".to_string(),
+                Duration::from_millis(20),
+            )
+            .unwrap();
+        let snapshot = store.snapshot().unwrap();
+        for i in 0..100 {
+            store.set("a".to_string(), i.to_string()).unwrap();
+        }
+        store.remove("b".to_string()).unwrap();
+        thread::sleep(Duration::from_millis(40));
+        store.compact().unwrap();
+        store.compact().unwrap();
+
+        assert_eq!(store.keys().unwrap(), ["a"]);
+        assert_eq!(
+            snapshot.scan(.., usize::MAX).unwrap(),
+            [
+                ("a".to_string(), "old".to_string()),
+                ("b".to_string(), "old".to_string()),
+                ("c".to_string(), "old".to_string()),
+            ]
+        );
+        assert!(!lock(&store.history).superseded.is_empty());
+        drop(snapshot);
+        assert!(lock(&store.history).superseded.is_empty());
+
+        drop(store);
+        let store = KvStore::open(dir.path()).unwrap();
+        assert_eq!(store.get("a".to_string()).unwrap(), Some("99".to_string()));
+        assert_eq!(store.get("b".to_string()).unwrap(), None);
+        assert_eq!(store.keys().unwrap(), ["a"]);
+    }
+
+    #[test]
+    fn test_pinned_generation_outlives_compaction() {
+        let dir = TempDir::new().unwrap();
+        let store = KvStore::open(dir.path()).unwrap();
+        store.set("a".to_string(), "1".to_string()).unwrap();
+        let gen = *read_lock(&store.readers).keys().next().unwrap();
+        let pinned = store.pin([gen]).unwrap();
+        store.compact().unwrap();
+
+        assert!(!read_lock(&store.readers).contains_key(&gen));
+        assert!(log_path(dir.path(), gen).exists());
+        drop(pinned);
+        assert!(!log_path(dir.path(), gen).exists());
     }
 }
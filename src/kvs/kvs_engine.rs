@@ -1,92 +1,1222 @@
-use std::sync::{Arc, Mutex};
+use std::{
+    collections::BTreeMap,
+    ops::{Bound, RangeBounds},
+    sync::Arc,
+    time::Duration,
+};
 
-use crate::Result;
-use sled::Db;
+use super::background::{spawn_periodic, Periodic};
+use super::bytes::{pair_to_strings, prefix_range, range_to_bytes};
+use super::durability::{Durability, GroupCommit};
+use super::expiry::{self, now_millis};
+use super::key_locks::KeyLocks;
+use super::kv_store::is_empty_range;
+use super::preserved::{KeyRange, Preserved};
+use super::snapshot::{ExpiringPair, KvsSnapshot};
+use super::transaction::Transaction;
+use super::write_batch::{BatchOp, WriteBatch};
+use crate::{KvsError, Result};
+use log::error;
+use sled::{
+    transaction::{
+        ConflictableTransactionError, ConflictableTransactionResult, TransactionError,
+        TransactionalTree,
+    },
+    Db, IVec, Transactional, Tree,
+};
+
+/// A key/value store.
+///
+/// Keys and values are arbitrary bytes. Every `_bytes` method has a
+/// counterpart without the suffix that wraps it for string data, and fails
+/// with `KvsError::Utf8` if it comes across anything else.
 pub trait KvsEngine: Clone + Send + 'static {
-    fn set(&self, key: String, value: String) -> Result<()>;
-    fn get(&self, key: String) -> Result<Option<String>>;
-    fn remove(&self, key: String) -> Result<()>;
+    /// The name `kvs-server --engine` knows the engine by.
+    const NAME: &'static str;
+
+    type Snapshot: KvsSnapshot;
+
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()>;
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>>;
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()>;
+    /// Up to `limit` key/value pairs whose keys fall in `range`, in ascending
+    /// byte order.
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(
+        &self,
+        range: R,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>>;
+    /// Set `key` to `new`, or remove it if `new` is `None`, but only if its
+    /// value is `expected`, where `None` means the key does not exist.
+    /// Otherwise fail with `KvsError::Conflict` holding the current value.
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<()>;
+    /// Set `key` only if it does not exist yet.
+    fn set_if_absent_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.compare_and_swap_bytes(key, None, Some(value))
+    }
+    /// Get the value of `key` along with its version, for `set_if_version`.
+    fn get_with_version_bytes(&self, key: Vec<u8>) -> Result<Option<(Vec<u8>, u64)>>;
+    /// Set `key` only if it exists and is still at `version`. Otherwise fail
+    /// with `KvsError::Conflict` holding the current value.
+    fn set_if_version_bytes(&self, key: Vec<u8>, value: Vec<u8>, version: u64) -> Result<()>;
+    /// Set `key` so that it disappears once `ttl` has passed. Other writes
+    /// to a key clear its expiry.
+    fn set_with_ttl_bytes(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()>;
+    /// Make an existing key disappear once `ttl` has passed.
+    fn expire_bytes(&self, key: Vec<u8>, ttl: Duration) -> Result<()>;
+    /// Time left before `key` expires, or `None` if it never does.
+    fn ttl_bytes(&self, key: Vec<u8>) -> Result<Option<Duration>>;
+    /// Make an existing key never expire.
+    fn persist_bytes(&self, key: Vec<u8>) -> Result<()>;
+    /// List every key, in ascending byte order.
+    fn keys_bytes(&self) -> Result<Vec<Vec<u8>>>;
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+    fn get(&self, key: String) -> Result<Option<String>> {
+        Ok(self
+            .get_bytes(key.into_bytes())?
+            .map(String::from_utf8)
+            .transpose()?)
+    }
+    fn remove(&self, key: String) -> Result<()> {
+        self.remove_bytes(key.into_bytes())
+    }
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<()> {
+        self.compare_and_swap_bytes(
+            key.into_bytes(),
+            expected.map(String::into_bytes),
+            new.map(String::into_bytes),
+        )
+    }
+    fn set_if_absent(&self, key: String, value: String) -> Result<()> {
+        self.set_if_absent_bytes(key.into_bytes(), value.into_bytes())
+    }
+    fn get_with_version(&self, key: String) -> Result<Option<(String, u64)>> {
+        self.get_with_version_bytes(key.into_bytes())?
+            .map(|(value, version)| Ok((String::from_utf8(value)?, version)))
+            .transpose()
+    }
+    fn set_if_version(&self, key: String, value: String, version: u64) -> Result<()> {
+        self.set_if_version_bytes(key.into_bytes(), value.into_bytes(), version)
+    }
+    fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.set_with_ttl_bytes(key.into_bytes(), value.into_bytes(), ttl)
+    }
+    fn expire(&self, key: String, ttl: Duration) -> Result<()> {
+        self.expire_bytes(key.into_bytes(), ttl)
+    }
+    fn ttl(&self, key: String) -> Result<Option<Duration>> {
+        self.ttl_bytes(key.into_bytes())
+    }
+    fn persist(&self, key: String) -> Result<()> {
+        self.persist_bytes(key.into_bytes())
+    }
+    /// List every key, in ascending order.
+    fn keys(&self) -> Result<Vec<String>> {
+        self.keys_bytes()?
+            .into_iter()
+            .map(|key| Ok(String::from_utf8(key)?))
+            .collect()
+    }
+    /// Up to `limit` key/value pairs whose keys fall in `range`, in ascending
+    /// key order.
+    fn scan<R: RangeBounds<String>>(
+        &self,
+        range: R,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>> {
+        self.scan_bytes(range_to_bytes(&range), limit)?
+            .into_iter()
+            .map(pair_to_strings)
+            .collect()
+    }
+    /// Up to `limit` key/value pairs whose keys start with `prefix`, in
+    /// ascending key order.
+    fn scan_prefix(&self, prefix: String, limit: usize) -> Result<Vec<(String, String)>> {
+        self.scan_bytes(prefix_range(prefix.into_bytes()), limit)?
+            .into_iter()
+            .map(pair_to_strings)
+            .collect()
+    }
+    /// Apply every write in `batch`, or none of them.
+    fn apply_batch(&self, batch: WriteBatch) -> Result<()>;
+    /// Take a read-only view of the store as it is now, which later writes
+    /// do not change.
+    fn snapshot(&self) -> Result<Self::Snapshot>;
+    /// Start an optimistic transaction.
+    fn begin(&self) -> Transaction<Self> {
+        Transaction::new(self.clone())
+    }
+    /// Apply `batch` only if every key in `reads` is still at the version
+    /// given, where `None` means the key does not exist. Otherwise fail with
+    /// `KvsError::TransactionConflict`. Used by `Transaction::commit`.
+    fn commit_transaction(
+        &self,
+        reads: &BTreeMap<Vec<u8>, Option<u64>>,
+        batch: WriteBatch,
+    ) -> Result<()>;
+    /// Make every write so far durable, whatever the durability policy.
+    fn flush(&self) -> Result<()>;
+}
+
+/// Name of the sled tree holding expiry times, keyed like the data.
+const EXPIRY_TREE: &str = "kvs-expiry";
+
+/// Name of the sled tree holding the version of every key, keyed like the
+/// data. Keys written before versions were kept have none, and count as
+/// version 0.
+const VERSION_TREE: &str = "kvs-versions";
+
+struct Inner {
+    map: Db,
+    /// Expiry time of every key that has one, in milliseconds since the
+    /// Unix epoch.
+    expiry: Tree,
+    /// Version of every key, as a big-endian `u64`.
+    versions: Tree,
+    /// Held by writes to the keys that hash to them, so that a write and the
+    /// expiry check it depends on are atomic. Reads take none.
+    locks: KeyLocks,
+    /// What writes replaced while snapshots were open.
+    preserved: Preserved<Stored>,
+    durability: Durability,
+}
+
+/// The value of a key with its version and expiry time.
+type Stored = (IVec, u64, Option<u64>);
+
+impl Inner {
+    /// The version of `key`, which is assumed to exist.
+    fn version(&self, key: &[u8]) -> Result<u64> {
+        self.versions
+            .get(key)?
+            .map_or(Ok(0), |version| decode_version(&version))
+    }
+
+    /// The value of `key` with its version and expiry time, expired or not.
+    fn stored(&self, key: &[u8]) -> Result<Option<Stored>> {
+        match self.map.get(key)? {
+            Some(value) => Ok(Some(self.stored_with(key, value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// `value` with the version and expiry time of `key`.
+    fn stored_with(&self, key: &[u8], value: IVec) -> Result<Stored> {
+        let expires_at = match self.expiry.get(key)? {
+            Some(expires_at) => Some(decode_expiry(&expires_at)?),
+            None => None,
+        };
+        Ok((value, self.version(key)?, expires_at))
+    }
+
+    /// Save the entries of `keys` that open snapshots may need before they
+    /// are written. The caller holds the locks of `keys`.
+    fn preserve<'a>(&self, keys: impl IntoIterator<Item = &'a [u8]>) -> Result<()> {
+        for key in keys {
+            if let Some(epoch) = self.preserved.needs(key) {
+                self.preserved.save(key, epoch, self.stored(key)?);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The trees of a `SledKvsEngine`, inside a transaction.
+struct Trees<'a> {
+    data: &'a TransactionalTree,
+    expiry: &'a TransactionalTree,
+    versions: &'a TransactionalTree,
+}
+
+impl Trees<'_> {
+    /// Set `key` at a new version and clear its expiry.
+    fn set(&self, key: &[u8], value: &[u8]) -> ConflictableTransactionResult<(), KvsError> {
+        // IDs grow across restarts, and never reach 0.
+        let version = self.data.generate_id()? + 1;
+        self.data.insert(key, value)?;
+        self.versions.insert(key, &version.to_be_bytes())?;
+        self.expiry.remove(key)?;
+        Ok(())
+    }
+
+    /// Remove `key` along with its version and expiry, returning its value.
+    fn remove(&self, key: &[u8]) -> ConflictableTransactionResult<Option<IVec>, KvsError> {
+        self.versions.remove(key)?;
+        self.expiry.remove(key)?;
+        Ok(self.data.remove(key)?)
+    }
+
+    /// Fail with `KvsError::KeyNotFound` unless `key` exists.
+    fn require(&self, key: &[u8]) -> ConflictableTransactionResult<(), KvsError> {
+        match self.data.get(key)? {
+            Some(_) => Ok(()),
+            None => Err(ConflictableTransactionError::Abort(KvsError::KeyNotFound)),
+        }
+    }
+
+    fn version(&self, key: &[u8]) -> ConflictableTransactionResult<u64, KvsError> {
+        match self.versions.get(key)? {
+            Some(version) => decode_version(&version).map_err(ConflictableTransactionError::Abort),
+            None => Ok(0),
+        }
+    }
+
+    fn apply(&self, batch: &WriteBatch) -> ConflictableTransactionResult<(), KvsError> {
+        for op in batch.ops() {
+            match op {
+                BatchOp::Set { key, value } => self.set(key, value)?,
+                BatchOp::Remove { key } => {
+                    self.remove(key)?;
+                }
+            }
+        }
+        Ok(())
+    }
 }
 
 #[derive(Clone)]
 pub struct SledKvsEngine {
-    map: Arc<Mutex<Db>>,
+    inner: Arc<Inner>,
+    commit: Arc<GroupCommit>,
+    /// The flusher and sweeper threads, stopped once the last handle is
+    /// dropped.
+    _background: Arc<Vec<Periodic>>,
 }
 
 impl SledKvsEngine {
     pub fn new(map: Db) -> SledKvsEngine {
-        SledKvsEngine {
-            map: Arc::new(Mutex::new(map)),
+        Self::with_durability(map, Durability::default()).unwrap()
+    }
+
+    pub fn with_durability(map: Db, durability: Durability) -> Result<SledKvsEngine> {
+        Self::with_sweep_interval(map, durability, expiry::SWEEP_INTERVAL)
+    }
+
+    /// Like `with_durability`, also choosing how often expired keys are
+    /// swept out.
+    pub fn with_sweep_interval(
+        map: Db,
+        durability: Durability,
+        sweep_interval: Duration,
+    ) -> Result<SledKvsEngine> {
+        let inner = Arc::new(Inner {
+            expiry: map.open_tree(EXPIRY_TREE)?,
+            versions: map.open_tree(VERSION_TREE)?,
+            map,
+            locks: KeyLocks::default(),
+            preserved: Preserved::default(),
+            durability,
+        });
+        let mut background = vec![spawn_periodic(
+            "kvs-sweeper",
+            sweep_interval,
+            Arc::downgrade(&inner),
+            sweep,
+        )?];
+        if let Durability::EveryN(ms) = durability {
+            background.push(spawn_periodic(
+                "kvs-flusher",
+                Duration::from_millis(ms),
+                Arc::downgrade(&inner),
+                flush,
+            )?);
         }
+        Ok(SledKvsEngine {
+            inner,
+            commit: Arc::new(GroupCommit::default()),
+            _background: Arc::new(background),
+        })
     }
 
     pub fn store(&self) -> Result<()> {
-        loop {
-            match self.map.try_lock() {
-                Ok(map) => {
-                    map.flush().map_err(|e| e.to_string())?;
-                    break;
-                }
-                Err(std::sync::TryLockError::WouldBlock) => continue,
-                Err(_) => panic!("Poisoned lock"),
-            }
+        flush(&self.inner)
+    }
+
+    /// The value of `key`, unless it is missing or expired.
+    fn live(&self, key: &[u8]) -> Result<Option<IVec>> {
+        if is_expired(&self.inner.expiry, key, now_millis())? {
+            return Ok(None);
+        }
+        Ok(self.inner.map.get(key)?)
+    }
+
+    /// Remove `key` if it has expired. The caller holds the lock of `key`.
+    fn purge(&self, key: &[u8]) -> Result<()> {
+        if is_expired(&self.inner.expiry, key, now_millis())? {
+            transaction(&self.inner, [key], |trees| trees.remove(key))?;
         }
         Ok(())
     }
+
+    /// Drop out of `iter` the pairs whose keys have expired.
+    fn unexpired(&self, iter: sled::Iter) -> impl Iterator<Item = Result<(IVec, IVec)>> + '_ {
+        let now = now_millis();
+        let expiry = &self.inner.expiry;
+        let check = !expiry.is_empty();
+        iter.filter_map(move |pair| match pair {
+            Ok((key, value)) if check => match is_expired(expiry, &key, now) {
+                Ok(true) => None,
+                Ok(false) => Some(Ok((key, value))),
+                Err(e) => Some(Err(e)),
+            },
+            Ok(pair) => Some(Ok(pair)),
+            Err(e) => Some(Err(e.into())),
+        })
+    }
+
+    /// Wait until the write numbered `seq` is durable, if the configuration
+    /// asks for it.
+    fn sync(&self, seq: u64) -> Result<()> {
+        if self.inner.durability != Durability::Always {
+            return Ok(());
+        }
+        self.commit.sync(seq, || self.store())
+    }
+}
+
+fn flush(inner: &Inner) -> Result<()> {
+    inner.map.flush()?;
+    Ok(())
+}
+
+/// Remove every expired key.
+fn sweep(inner: &Inner) -> Result<()> {
+    let now = now_millis();
+    for pair in inner.expiry.iter() {
+        let (key, expires_at) = pair?;
+        if decode_expiry(&expires_at)? > now {
+            continue;
+        }
+        let _lock = inner.locks.lock(&key);
+        // Check again under the lock in case the key was written since.
+        if is_expired(&inner.expiry, &key, now)? {
+            transaction(inner, [&*key], |trees| trees.remove(&key))?;
+        }
+    }
+    Ok(())
+}
+
+/// Run `f` atomically over the data, expiry and version trees, which write
+/// at most `keys`. The caller holds their locks.
+fn transaction<'a, T>(
+    inner: &Inner,
+    keys: impl IntoIterator<Item = &'a [u8]>,
+    f: impl Fn(&Trees) -> ConflictableTransactionResult<T, KvsError>,
+) -> Result<T> {
+    inner.preserve(keys)?;
+    let data: &Tree = &inner.map;
+    (data, &inner.expiry, &inner.versions)
+        .transaction(|(data, expiry, versions)| {
+            f(&Trees {
+                data,
+                expiry,
+                versions,
+            })
+        })
+        .map_err(|e| match e {
+            TransactionError::Abort(e) => e,
+            TransactionError::Storage(e) => e.into(),
+        })
+}
+
+fn is_expired(expiry: &Tree, key: &[u8], now: u64) -> Result<bool> {
+    match expiry.get(key)? {
+        Some(expires_at) => Ok(decode_expiry(&expires_at)? <= now),
+        None => Ok(false),
+    }
+}
+
+fn decode_expiry(bytes: &[u8]) -> Result<u64> {
+    bytes
+        .try_into()
+        .map(u64::from_be_bytes)
+        .map_err(|_| KvsError::Corruption("Malformed expiry time".to_string()))
+}
+
+fn decode_version(bytes: &[u8]) -> Result<u64> {
+    bytes
+        .try_into()
+        .map(u64::from_be_bytes)
+        .map_err(|_| KvsError::Corruption("Malformed version".to_string()))
 }
 
 impl KvsEngine for SledKvsEngine {
-    fn set(&self, key: String, value: String) -> Result<()> {
-        loop {
-            match self.map.try_lock() {
-                Ok(map) => {
-                    map.insert(key.as_bytes(), value.as_bytes())
-                        .map_err(|e| e.to_string())?;
-                    break;
-                }
-                Err(std::sync::TryLockError::WouldBlock) => continue,
-                Err(_) => panic!("Poisoned lock"),
+    const NAME: &'static str = "sled";
+
+    type Snapshot = SledKvsSnapshot;
+
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        let seq = {
+            let _lock = self.inner.locks.lock(&key);
+            transaction(&self.inner, [&*key], |trees| trees.set(&key, &value))?;
+            self.commit.record()
+        };
+        self.sync(seq)
+    }
+
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        Ok(self.live(&key)?.map(|value| value.to_vec()))
+    }
+
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        let seq = {
+            let _lock = self.inner.locks.lock(&key);
+            self.purge(&key)?;
+            if transaction(&self.inner, [&*key], |trees| trees.remove(&key))?.is_none() {
+                return Err(KvsError::KeyNotFound);
             }
-        }
-        self.store()?;
-        Ok(())
+            self.commit.record()
+        };
+        self.sync(seq)
     }
 
-    fn get(&self, key: String) -> Result<Option<String>> {
-        loop {
-            match self.map.try_lock() {
-                Ok(map) => {
-                    break match map.get(key.as_bytes()).map_err(|e| e.to_string())? {
-                        Some(value) => Ok(Some(
-                            String::from_utf8(value.to_vec()).map_err(|e| e.to_string())?,
-                        )),
-                        None => Ok(None),
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<()> {
+        let swapped = {
+            let _lock = self.inner.locks.lock(&key);
+            self.purge(&key)?;
+            transaction(&self.inner, [&*key], |trees| {
+                let current = trees.data.get(&*key)?;
+                if current.as_deref() != expected.as_deref() {
+                    return Ok(Err(current));
+                }
+                match &new {
+                    Some(new) => trees.set(&key, new)?,
+                    None => {
+                        trees.remove(&key)?;
                     }
                 }
-                Err(std::sync::TryLockError::WouldBlock) => continue,
-                Err(_) => panic!("Poisoned lock"),
-            }
+                Ok(Ok(()))
+            })?
+        };
+        match swapped {
+            Ok(()) => self.sync(self.commit.record()),
+            Err(current) => Err(KvsError::Conflict {
+                current: current.map(|value| value.to_vec()),
+            }),
         }
     }
 
-    fn remove(&self, key: String) -> Result<()> {
-        let result = loop {
-            match self.map.try_lock() {
-                Ok(map) => break map.remove(key.as_bytes()).map_err(|e| e.to_string())?,
-                Err(std::sync::TryLockError::WouldBlock) => continue,
-                Err(_) => panic!("Poisoned lock"),
+    /// Read under the lock of `key`, so the value and version match.
+    fn get_with_version_bytes(&self, key: Vec<u8>) -> Result<Option<(Vec<u8>, u64)>> {
+        let _lock = self.inner.locks.lock(&key);
+        match self.live(&key)? {
+            Some(value) => Ok(Some((value.to_vec(), self.inner.version(&key)?))),
+            None => Ok(None),
+        }
+    }
+
+    fn set_if_version_bytes(&self, key: Vec<u8>, value: Vec<u8>, version: u64) -> Result<()> {
+        let seq = {
+            let _lock = self.inner.locks.lock(&key);
+            self.purge(&key)?;
+            transaction(&self.inner, [&*key], |trees| {
+                match trees.data.get(&*key)? {
+                    Some(_) if trees.version(&key)? == version => trees.set(&key, &value),
+                    current => Err(ConflictableTransactionError::Abort(KvsError::Conflict {
+                        current: current.map(|value| value.to_vec()),
+                    })),
+                }
+            })?;
+            self.commit.record()
+        };
+        self.sync(seq)
+    }
+
+    fn set_with_ttl_bytes(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let expires_at = expiry::expires_at(ttl).to_be_bytes();
+        let seq = {
+            let _lock = self.inner.locks.lock(&key);
+            transaction(&self.inner, [&*key], |trees| {
+                trees.set(&key, &value)?;
+                trees.expiry.insert(&*key, &expires_at)?;
+                Ok(())
+            })?;
+            self.commit.record()
+        };
+        self.sync(seq)
+    }
+
+    /// Changing the expiry time of a key keeps its version.
+    fn expire_bytes(&self, key: Vec<u8>, ttl: Duration) -> Result<()> {
+        let expires_at = expiry::expires_at(ttl).to_be_bytes();
+        let seq = {
+            let _lock = self.inner.locks.lock(&key);
+            self.purge(&key)?;
+            transaction(&self.inner, [&*key], |trees| {
+                trees.require(&key)?;
+                trees.expiry.insert(&*key, &expires_at)?;
+                Ok(())
+            })?;
+            self.commit.record()
+        };
+        self.sync(seq)
+    }
+
+    fn ttl_bytes(&self, key: Vec<u8>) -> Result<Option<Duration>> {
+        if self.live(&key)?.is_none() {
+            return Err(KvsError::KeyNotFound);
+        }
+        match self.inner.expiry.get(&*key)? {
+            Some(expires_at) => Ok(Some(expiry::remaining(decode_expiry(&expires_at)?))),
+            None => Ok(None),
+        }
+    }
+
+    fn persist_bytes(&self, key: Vec<u8>) -> Result<()> {
+        let seq = {
+            let _lock = self.inner.locks.lock(&key);
+            self.purge(&key)?;
+            let persisted = transaction(&self.inner, [&*key], |trees| {
+                trees.require(&key)?;
+                Ok(trees.expiry.remove(&*key)?.is_some())
+            })?;
+            if !persisted {
+                return Ok(());
             }
+            self.commit.record()
         };
-        self.store()?;
-        match result {
-            Some(_) => Ok(()),
-            None => Err("Key not found".to_string()),
+        self.sync(seq)
+    }
+
+    fn keys_bytes(&self) -> Result<Vec<Vec<u8>>> {
+        self.unexpired(self.inner.map.iter())
+            .map(|pair| Ok(pair?.0.to_vec()))
+            .collect()
+    }
+
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(
+        &self,
+        range: R,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let range: (Bound<&[u8]>, Bound<&[u8]>) = (
+            range.start_bound().map(|key| &key[..]),
+            range.end_bound().map(|key| &key[..]),
+        );
+        collect_pairs(
+            self.unexpired(self.inner.map.range::<&[u8], _>(range)),
+            limit,
+        )
+    }
+
+    fn apply_batch(&self, batch: WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
         }
+        let seq = {
+            let _locks = self
+                .inner
+                .locks
+                .lock_keys(batch.ops().iter().map(BatchOp::key));
+            transaction(&self.inner, batch.ops().iter().map(BatchOp::key), |trees| {
+                trees.apply(&batch)
+            })?;
+            self.commit.record()
+        };
+        self.sync(seq)
     }
+
+    /// sled has no point-in-time reads, so while the snapshot is open every
+    /// write first saves the entry it replaces. Taking one only waits for
+    /// the writes in flight.
+    fn snapshot(&self) -> Result<SledKvsSnapshot> {
+        let epoch = {
+            let _locks = self.inner.locks.lock_all();
+            self.inner.preserved.acquire()
+        };
+        Ok(SledKvsSnapshot {
+            inner: self.inner.clone(),
+            epoch,
+            now: now_millis(),
+        })
+    }
+
+    fn commit_transaction(
+        &self,
+        reads: &BTreeMap<Vec<u8>, Option<u64>>,
+        batch: WriteBatch,
+    ) -> Result<()> {
+        let seq = {
+            let _locks = self.inner.locks.lock_keys(
+                reads
+                    .keys()
+                    .map(Vec::as_slice)
+                    .chain(batch.ops().iter().map(BatchOp::key)),
+            );
+            let now = now_millis();
+            let keys = batch.ops().iter().map(BatchOp::key);
+            transaction(&self.inner, keys, |trees| {
+                for (key, version) in reads {
+                    let expired = match trees.expiry.get(&**key)? {
+                        Some(expires_at) => match decode_expiry(&expires_at) {
+                            Ok(expires_at) => expires_at <= now,
+                            Err(e) => return Err(ConflictableTransactionError::Abort(e)),
+                        },
+                        None => false,
+                    };
+                    let current = match trees.data.get(&**key)? {
+                        Some(_) if !expired => Some(trees.version(key)?),
+                        _ => None,
+                    };
+                    if current != *version {
+                        return Err(ConflictableTransactionError::Abort(
+                            KvsError::TransactionConflict { key: key.clone() },
+                        ));
+                    }
+                }
+                trees.apply(&batch)
+            })?;
+            self.commit.record()
+        };
+        self.sync(seq)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.store()
+    }
+}
+
+/// A view of a `SledKvsEngine`, from `KvsEngine::snapshot`.
+///
+/// Reads go to the live trees, and to the entries writes saved for the keys
+/// they replaced since.
+pub struct SledKvsSnapshot {
+    inner: Arc<Inner>,
+    epoch: u64,
+    /// When the snapshot was taken, which decides what has expired.
+    now: u64,
 }
 
-impl Drop for SledKvsEngine {
+impl SledKvsSnapshot {
+    fn is_live(&self, (_, _, expires_at): &Stored) -> bool {
+        !expiry::is_expired(*expires_at, self.now)
+    }
+}
+
+impl KvsSnapshot for SledKvsSnapshot {
+    /// The live entry is read first, so a write that replaces it in the
+    /// meantime has already saved it.
+    fn get_with_version_bytes(&self, key: Vec<u8>) -> Result<Option<(Vec<u8>, u64)>> {
+        let live = self.inner.stored(&key)?;
+        let stored = self.inner.preserved.get(&key, self.epoch).unwrap_or(live);
+        Ok(stored
+            .filter(|stored| self.is_live(stored))
+            .map(|(value, version, _)| (value.to_vec(), version)))
+    }
+
+    fn scan_with_expiry_bytes<R: RangeBounds<Vec<u8>>>(
+        &self,
+        range: R,
+        limit: usize,
+    ) -> Result<Vec<ExpiringPair>> {
+        if is_empty_range(&range) {
+            return Ok(Vec::new());
+        }
+        let read_live = |range: KeyRange, limit: usize| {
+            self.inner
+                .map
+                .range(range)
+                .take(limit)
+                .map(|pair| {
+                    // A key written after it was listed has its entry saved
+                    // by then, and that one is used instead.
+                    let (key, value) = pair?;
+                    Ok((key.to_vec(), Some(self.inner.stored_with(&key, value)?)))
+                })
+                .collect()
+        };
+        let range = (range.start_bound().cloned(), range.end_bound().cloned());
+        let pairs = self
+            .inner
+            .preserved
+            .scan(self.epoch, range, limit, read_live, |stored| {
+                self.is_live(stored)
+            })?;
+        Ok(pairs
+            .into_iter()
+            .map(|(key, (value, _, expires_at))| (key, value.to_vec(), expires_at))
+            .collect())
+    }
+}
+
+impl Drop for SledKvsSnapshot {
     fn drop(&mut self) {
-        self.store().unwrap();
+        self.inner.preserved.release(self.epoch);
+    }
+}
+
+fn collect_pairs(
+    iter: impl Iterator<Item = Result<(IVec, IVec)>>,
+    limit: usize,
+) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    iter.take(limit)
+        .map(|pair| {
+            let (key, value) = pair?;
+            Ok((key.to_vec(), value.to_vec()))
+        })
+        .collect()
+}
+
+/// Runs once the last handle is gone, even if a background thread briefly
+/// held the final reference.
+impl Drop for Inner {
+    fn drop(&mut self) {
+        if self.durability != Durability::Never {
+            if let Err(e) = flush(self) {
+                error!("Failed to flush sled on close: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{KvStore, LockFreeKvsEngine};
+    use std::{path::Path, thread};
+    use tempfile::TempDir;
+
+    fn check_scans<E: KvsEngine>(engine: E) {
+        for key in ["a", "b", "user:1", "user:2", "user:3", "v"] {
+            engine.set(key.to_string(), key.to_uppercase()).unwrap();
+        }
+        let keys = |pairs: Vec<(String, String)>| -> Vec<String> {
+            pairs.into_iter().map(|(key, _)| key).collect()
+        };
+        assert_eq!(
+            engine
+                .scan("b".to_string()..="user:2".to_string(), 10)
+                .unwrap(),
+            vec![
+                ("b".to_string(), "B".to_string()),
+                ("user:1".to_string(), "USER:1".to_string()),
+                ("user:2".to_string(), "USER:2".to_string()),
+            ]
+        );
+        assert_eq!(keys(engine.scan(.., 2).unwrap()), ["a", "b"]);
+        assert_eq!(
+            keys(engine.scan("v".to_string().."a".to_string(), 10).unwrap()),
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            keys(engine.scan_prefix("user:".to_string(), 10).unwrap()),
+            ["user:1", "user:2", "user:3"]
+        );
+        assert_eq!(
+            keys(engine.scan_prefix("user:".to_string(), 1).unwrap()),
+            ["user:1"]
+        );
+    }
+
+    fn check_batch<E: KvsEngine>(engine: E) {
+        engine.set("old".to_string(), "0".to_string()).unwrap();
+        engine
+            .apply_batch(
+                WriteBatch::new()
+                    .set("a".to_string(), "1".to_string())
+                    .set("b".to_string(), "2".to_string())
+                    .remove("old".to_string())
+                    .remove("missing".to_string())
+                    .remove("b".to_string()),
+            )
+            .unwrap();
+        assert_eq!(engine.keys().unwrap(), ["a"]);
+        assert_eq!(engine.get("a".to_string()).unwrap(), Some("1".to_string()));
+    }
+
+    fn check_conditional_writes<E: KvsEngine>(engine: E) {
+        let conflict = |result: Result<()>| match result {
+            Err(KvsError::Conflict { current }) => current,
+            other => panic!("expected a conflict, got {:?}", other),
+        };
+        engine
+            .set_if_absent("a".to_string(), "1".to_string())
+            .unwrap();
+        assert_eq!(
+            conflict(engine.set_if_absent("a".to_string(), "2".to_string())),
+            Some(b"1".to_vec())
+        );
+        assert_eq!(
+            conflict(engine.compare_and_swap(
+                "a".to_string(),
+                Some("0".to_string()),
+                Some("2".to_string())
+            )),
+            Some(b"1".to_vec())
+        );
+        engine
+            .compare_and_swap(
+                "a".to_string(),
+                Some("1".to_string()),
+                Some("2".to_string()),
+            )
+            .unwrap();
+
+        let (value, version) = engine.get_with_version("a".to_string()).unwrap().unwrap();
+        assert_eq!(value, "2");
+        engine
+            .set_if_version("a".to_string(), "3".to_string(), version)
+            .unwrap();
+        assert_eq!(
+            conflict(engine.set_if_version("a".to_string(), "4".to_string(), version)),
+            Some(b"3".to_vec())
+        );
+        assert_eq!(
+            conflict(engine.set_if_version("b".to_string(), "1".to_string(), version)),
+            None
+        );
+
+        // Writing back an earlier value does not bring back its version.
+        let (_, version) = engine.get_with_version("a".to_string()).unwrap().unwrap();
+        engine.set("a".to_string(), "x".to_string()).unwrap();
+        engine.set("a".to_string(), "3".to_string()).unwrap();
+        assert!(matches!(
+            engine.set_if_version("a".to_string(), "5".to_string(), version),
+            Err(KvsError::Conflict { .. })
+        ));
+
+        engine
+            .compare_and_swap("a".to_string(), Some("3".to_string()), None)
+            .unwrap();
+        assert_eq!(engine.get("a".to_string()).unwrap(), None);
+        engine
+            .compare_and_swap("a".to_string(), None, None)
+            .unwrap();
+    }
+
+    fn check_expiry<E: KvsEngine>(engine: E) {
+        let short = Duration::from_millis(50);
+        engine
+            .set_with_ttl("a".to_string(), "1".to_string(), short)
+            .unwrap();
+        engine
+            .set_with_ttl("b".to_string(), "2".to_string(), short)
+            .unwrap();
+        engine
+            .set_with_ttl("c".to_string(), "3".to_string(), short)
+            .unwrap();
+        engine.set("b".to_string(), "2".to_string()).unwrap();
+        engine.persist("c".to_string()).unwrap();
+        engine.set("d".to_string(), "4".to_string()).unwrap();
+        let (_, version) = engine.get_with_version("d".to_string()).unwrap().unwrap();
+        engine
+            .expire("d".to_string(), Duration::from_secs(60))
+            .unwrap();
+        engine.persist("d".to_string()).unwrap();
+        engine.expire("d".to_string(), short).unwrap();
+        assert_eq!(
+            engine.get_with_version("d".to_string()).unwrap(),
+            Some(("4".to_string(), version))
+        );
+        assert_eq!(engine.get("a".to_string()).unwrap(), Some("1".to_string()));
+        assert!(engine.ttl("a".to_string()).unwrap().unwrap() <= short);
+        assert_eq!(engine.ttl("b".to_string()).unwrap(), None);
+
+        thread::sleep(short * 2);
+        assert_eq!(engine.get("a".to_string()).unwrap(), None);
+        assert!(matches!(
+            engine.ttl("a".to_string()),
+            Err(KvsError::KeyNotFound)
+        ));
+        assert!(matches!(
+            engine.remove("d".to_string()),
+            Err(KvsError::KeyNotFound)
+        ));
+        assert_eq!(engine.keys().unwrap(), ["b", "c"]);
+        engine
+            .set_if_absent("a".to_string(), "5".to_string())
+            .unwrap();
+        assert_eq!(engine.get("a".to_string()).unwrap(), Some("5".to_string()));
+    }
+
+    fn check_transactions<E: KvsEngine>(engine: E) {
+        engine.set("a".to_string(), "1".to_string()).unwrap();
+        let mut transaction = engine.begin();
+        assert_eq!(
+            transaction.get("a".to_string()).unwrap(),
+            Some("1".to_string())
+        );
+        transaction.set("b".to_string(), "2".to_string());
+        transaction.remove("a".to_string());
+        assert_eq!(transaction.get("a".to_string()).unwrap(), None);
+        assert_eq!(engine.get("b".to_string()).unwrap(), None);
+        transaction.commit().unwrap();
+        assert_eq!(engine.keys().unwrap(), ["b"]);
+
+        let mut stale = engine.begin();
+        assert_eq!(stale.get("a".to_string()).unwrap(), None);
+        assert_eq!(stale.get("b".to_string()).unwrap(), Some("2".to_string()));
+        stale.set("c".to_string(), "3".to_string());
+        engine.set("a".to_string(), "4".to_string()).unwrap();
+        assert!(matches!(
+            stale.commit(),
+            Err(KvsError::TransactionConflict { key }) if key == b"a"
+        ));
+        assert_eq!(engine.keys().unwrap(), ["a", "b"]);
+
+        let mut consistent = engine.begin();
+        assert_eq!(
+            consistent.get("a".to_string()).unwrap(),
+            Some("4".to_string())
+        );
+        engine.set("b".to_string(), "5".to_string()).unwrap();
+        assert_eq!(
+            consistent.get("b".to_string()).unwrap(),
+            Some("2".to_string())
+        );
+        assert!(matches!(
+            consistent.commit(),
+            Err(KvsError::TransactionConflict { key }) if key == b"b"
+        ));
+    }
+
+    fn check_snapshots<E: KvsEngine>(engine: E) {
+        engine.set("a".to_string(), "1".to_string()).unwrap();
+        engine.set("b".to_string(), "2".to_string()).unwrap();
+        engine
+            .set_with_ttl("c".to_string(), "3".to_string(), Duration::from_millis(50))
+            .unwrap();
+        let snapshot = engine.snapshot().unwrap();
+        engine.set("a".to_string(), "4".to_string()).unwrap();
+        engine.remove("b".to_string()).unwrap();
+        engine.set("d".to_string(), "5".to_string()).unwrap();
+        thread::sleep(Duration::from_millis(100));
+
+        assert_eq!(
+            snapshot.get("a".to_string()).unwrap(),
+            Some("1".to_string())
+        );
+        assert_eq!(
+            snapshot.get("c".to_string()).unwrap(),
+            Some("3".to_string())
+        );
+        assert_eq!(snapshot.get("d".to_string()).unwrap(), None);
+        assert_eq!(
+            snapshot.scan("b".to_string().., 10).unwrap(),
+            [
+                ("b".to_string(), "2".to_string()),
+                ("c".to_string(), "3".to_string()),
+            ]
+        );
+        assert_eq!(engine.keys().unwrap(), ["a", "d"]);
+    }
+
+    fn check_snapshots_under_writes<E: KvsEngine>(engine: E) {
+        let key = |i: usize| format!("k{:04}", i);
+        for i in 0..3000 {
+            engine.set(key(i), i.to_string()).unwrap();
+        }
+        let before = engine.snapshot().unwrap();
+        engine.set("a".to_string(), "new".to_string()).unwrap();
+        for i in (0..3000).step_by(2) {
+            engine.remove(key(i)).unwrap();
+        }
+        let between = engine.snapshot().unwrap();
+        engine.set(key(1), "changed".to_string()).unwrap();
+
+        let scanned = before.scan(.., usize::MAX).unwrap();
+        assert_eq!(scanned.len(), 3000);
+        assert!(scanned
+            .iter()
+            .enumerate()
+            .all(|(i, (k, v))| *k == key(i) && *v == i.to_string()));
+        let scanned = between.scan(.., 3).unwrap();
+        assert_eq!(
+            scanned,
+            [
+                ("a".to_string(), "new".to_string()),
+                (key(1), "1".to_string()),
+                (key(3), "3".to_string()),
+            ]
+        );
+        drop(before);
+        assert_eq!(between.get(key(0)).unwrap(), None);
+        assert_eq!(between.get(key(1)).unwrap(), Some("1".to_string()));
+        drop(between);
+
+        // Writes keep "x" and "y" equal, and every snapshot must agree.
+        let writer = {
+            let engine = engine.clone();
+            thread::spawn(move || {
+                for i in 0..200 {
+                    let batch = WriteBatch::new()
+                        .set("x".to_string(), i.to_string())
+                        .set("y".to_string(), i.to_string());
+                    engine.apply_batch(batch).unwrap();
+                }
+            })
+        };
+        while !writer.is_finished() {
+            let snapshot = engine.snapshot().unwrap();
+            let x = snapshot.get("x".to_string()).unwrap();
+            thread::yield_now();
+            let pairs = snapshot.scan("x".to_string()..="y".to_string(), 2).unwrap();
+            assert_eq!(snapshot.get("y".to_string()).unwrap(), x);
+            if let Some(x) = x {
+                assert_eq!(pairs, [("x".to_string(), x.clone()), ("y".to_string(), x)]);
+            }
+        }
+        writer.join().unwrap();
+    }
+
+    fn check_binary_data<E: KvsEngine>(open: impl Fn() -> E) {
+        let engine = open();
+        let key = vec![0xff, 0, b'k'];
+        engine.set_bytes(key.clone(), vec![0x80, 0]).unwrap();
+        engine.set_bytes(b"bin".to_vec(), vec![0xff]).unwrap();
+        engine.set("text".to_string(), "v".to_string()).unwrap();
+        engine
+            .apply_batch(WriteBatch::new().set(vec![0xfe], vec![0xc0]))
+            .unwrap();
+        assert!(matches!(
+            engine.get("bin".to_string()),
+            Err(KvsError::Utf8(_))
+        ));
+        drop(engine);
+
+        let engine = open();
+        assert_eq!(engine.get_bytes(key.clone()).unwrap(), Some(vec![0x80, 0]));
+        let pairs = vec![
+            (b"bin".to_vec(), vec![0xff]),
+            (b"text".to_vec(), b"v".to_vec()),
+            (vec![0xfe], vec![0xc0]),
+            (key.clone(), vec![0x80, 0]),
+        ];
+        assert_eq!(engine.scan_bytes(.., usize::MAX).unwrap(), pairs);
+        assert_eq!(
+            engine
+                .snapshot()
+                .unwrap()
+                .scan_bytes(.., usize::MAX)
+                .unwrap(),
+            pairs
+        );
+        assert_eq!(
+            engine.scan_bytes(vec![0xfe].., usize::MAX).unwrap(),
+            &pairs[2..]
+        );
+        assert_eq!(
+            engine.scan_prefix("te".to_string(), 10).unwrap(),
+            [("text".to_string(), "v".to_string())]
+        );
+        assert!(matches!(
+            engine.scan(.., usize::MAX),
+            Err(KvsError::Utf8(_))
+        ));
+        engine.remove_bytes(key.clone()).unwrap();
+        assert_eq!(engine.get_bytes(key.clone()).unwrap(), None);
+
+        assert!(matches!(
+            engine.compare_and_swap_bytes(b"bin".to_vec(), Some(vec![0xfe]), None),
+            Err(KvsError::Conflict { current: Some(v) }) if v == [0xff]
+        ));
+        let (value, version) = engine
+            .get_with_version_bytes(b"bin".to_vec())
+            .unwrap()
+            .unwrap();
+        assert_eq!(value, [0xff]);
+        engine
+            .set_if_version_bytes(b"bin".to_vec(), vec![0xfd], version)
+            .unwrap();
+        engine
+            .set_with_ttl_bytes(key.clone(), vec![0xfc], Duration::from_secs(60))
+            .unwrap();
+        assert!(engine.ttl_bytes(key.clone()).unwrap().is_some());
+        engine.persist_bytes(key.clone()).unwrap();
+        assert_eq!(engine.ttl_bytes(key.clone()).unwrap(), None);
+
+        let mut transaction = engine.begin();
+        assert_eq!(
+            transaction.get_bytes(key.clone()).unwrap(),
+            Some(vec![0xfc])
+        );
+        transaction.set_bytes(key.clone(), vec![0xfb]);
+        transaction.commit().unwrap();
+        assert_eq!(engine.get_bytes(key.clone()).unwrap(), Some(vec![0xfb]));
+        assert!(engine.keys_bytes().unwrap().contains(&key));
+    }
+
+    #[test]
+    fn test_binary_data_agrees_across_engines() {
+        let dir = TempDir::new().unwrap();
+        check_binary_data(|| KvStore::open(dir.path().join("kvs")).unwrap());
+        check_binary_data(|| SledKvsEngine::new(reopen_sled(&dir.path().join("sled"))));
+        let memory = LockFreeKvsEngine::new().unwrap();
+        check_binary_data(|| memory.clone());
+    }
+
+    /// Open the sled database at `path`, which was just closed. sled's own
+    /// flusher thread can hold its lock for a moment after the last handle
+    /// is dropped.
+    fn reopen_sled(path: &Path) -> Db {
+        for _ in 0..100 {
+            match sled::open(path) {
+                Err(sled::Error::Io(e)) if e.to_string().contains("could not acquire lock") => {
+                    thread::sleep(Duration::from_millis(10))
+                }
+                opened => return opened.unwrap(),
+            }
+        }
+        panic!("{} is still locked", path.display());
+    }
+
+    #[test]
+    fn test_sled_flushes_when_the_last_handle_drops() {
+        let dir = TempDir::new().unwrap();
+        let engine = SledKvsEngine::with_sweep_interval(
+            sled::open(dir.path()).unwrap(),
+            Durability::OnClose,
+            Duration::from_millis(1),
+        )
+        .unwrap();
+        let inner = Arc::downgrade(&engine.inner);
+        let clone = engine.clone();
+        engine.set("a".to_string(), "1".to_string()).unwrap();
+        drop(engine);
+        assert!(inner.strong_count() > 0);
+        drop(clone);
+        assert_eq!(inner.strong_count(), 0);
+        let engine = SledKvsEngine::new(reopen_sled(dir.path()));
+        assert_eq!(engine.get("a".to_string()).unwrap(), Some("1".to_string()));
+    }
+
+    /// Define a test running a check against every engine.
+    macro_rules! agree_across_engines {
+        ($($test:ident: $check:ident,)*) => {$(
+            #[test]
+            fn $test() {
+                let dir = TempDir::new().unwrap();
+                $check(KvStore::open(dir.path().join("kvs")).unwrap());
+                $check(SledKvsEngine::new(sled::open(dir.path().join("sled")).unwrap()));
+                $check(LockFreeKvsEngine::new().unwrap());
+            }
+        )*};
+    }
+
+    agree_across_engines! {
+        test_conditional_writes_agree_across_engines: check_conditional_writes,
+        test_expiry_agrees_across_engines: check_expiry,
+        test_batch_agrees_across_engines: check_batch,
+        test_transactions_agree_across_engines: check_transactions,
+        test_snapshots_agree_across_engines: check_snapshots,
+        test_snapshots_under_writes_agree_across_engines: check_snapshots_under_writes,
+        test_scans_agree_across_engines: check_scans,
     }
 }
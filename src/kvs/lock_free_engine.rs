@@ -0,0 +1,558 @@
+use std::{
+    collections::BTreeMap,
+    ops::{Bound, RangeBounds},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, OnceLock,
+    },
+    time::Duration,
+};
+
+use super::background::{spawn_periodic, Periodic};
+use super::expiry::{self, now_millis};
+use super::key_locks::KeyLocks;
+use super::kv_store::is_empty_range;
+use super::kvs_engine::KvsEngine;
+use super::preserved::{KeyRange, Preserved};
+use super::snapshot::{ExpiringPair, KvsSnapshot};
+use super::write_batch::{BatchOp, WriteBatch};
+use crate::lock_free::hashmap::HashMap;
+use crate::{KvsError, Result};
+
+/// A live key's value.
+struct Entry {
+    value: Vec<u8>,
+    version: u64,
+    /// When the key expires, in milliseconds since the Unix epoch.
+    expires_at: Option<u64>,
+}
+
+impl Entry {
+    fn is_expired(&self, now: u64) -> bool {
+        expiry::is_expired(self.expires_at, now)
+    }
+}
+
+/// What the map holds for a key. Cloning one is cheap.
+#[derive(Clone)]
+struct Slot {
+    /// `None` for a key that a batch being applied removes.
+    entry: Option<Arc<Entry>>,
+    /// Set while the batch that wrote `entry` is being applied.
+    pending: Option<Pending>,
+}
+
+#[derive(Clone)]
+struct Pending {
+    /// Set once every write of the batch is in the map, which publishes
+    /// them all at once.
+    committed: Arc<AtomicBool>,
+    /// What readers see until then.
+    previous: Option<Arc<Entry>>,
+}
+
+impl Slot {
+    fn new(entry: Entry) -> Slot {
+        Slot {
+            entry: Some(Arc::new(entry)),
+            pending: None,
+        }
+    }
+
+    /// The entry readers see, expired or not.
+    fn visible(self) -> Option<Arc<Entry>> {
+        match self.pending {
+            Some(pending) if !pending.committed.load(Ordering::Acquire) => pending.previous,
+            _ => self.entry,
+        }
+    }
+}
+
+struct Inner {
+    map: HashMap<Vec<u8>, Slot>,
+    /// Taken by every write on the keys it touches, and never by a read.
+    locks: KeyLocks,
+    /// The version of the latest write.
+    version: AtomicU64,
+    /// What writes replaced while snapshots were open.
+    preserved: Preserved<Arc<Entry>>,
+}
+
+impl Inner {
+    /// The entry of `key`, expired or not.
+    fn visible(&self, key: &[u8]) -> Option<Arc<Entry>> {
+        let (_, slot) = self.map.get(&key.to_vec())?;
+        slot.visible()
+    }
+
+    /// The entry of `key`, unless it is missing or expired.
+    fn live(&self, key: &[u8], now: u64) -> Option<Arc<Entry>> {
+        self.visible(key).filter(|entry| !entry.is_expired(now))
+    }
+
+    /// Save the entry of `key` before it is written, if an open snapshot
+    /// may need it. The caller holds the lock of `key`.
+    fn preserve(&self, key: &[u8]) {
+        if let Some(epoch) = self.preserved.needs(key) {
+            self.preserved.save(key, epoch, self.visible(key));
+        }
+    }
+
+    /// Remove `key`. The caller holds its lock.
+    fn remove(&self, key: &[u8]) {
+        self.preserve(key);
+        self.map.remove(&key.to_vec());
+    }
+}
+
+/// An engine that keeps every pair in memory, in a `lock_free::HashMap`.
+///
+/// Nothing is written to disk, so the data is gone once the last handle is
+/// dropped. Reads never wait. Writes lock only the keys they touch, so
+/// conditional writes, batches and transactions are atomic with respect to
+/// each other, and a batch becomes visible to reads all at once. Scans read
+/// every key and sort the ones in range.
+#[derive(Clone)]
+pub struct LockFreeKvsEngine {
+    inner: Arc<Inner>,
+    /// The sweeper thread, stopped once the last handle is dropped.
+    _sweeper: Arc<Periodic>,
+}
+
+impl LockFreeKvsEngine {
+    pub fn new() -> Result<LockFreeKvsEngine> {
+        Self::with_sweep_interval(expiry::SWEEP_INTERVAL)
+    }
+
+    /// An empty engine that sweeps out expired keys every `sweep_interval`.
+    pub fn with_sweep_interval(sweep_interval: Duration) -> Result<LockFreeKvsEngine> {
+        let inner = Arc::new(Inner {
+            map: HashMap::new(),
+            locks: KeyLocks::default(),
+            version: AtomicU64::new(0),
+            preserved: Preserved::default(),
+        });
+        let sweeper = spawn_periodic("kvs-sweeper", sweep_interval, Arc::downgrade(&inner), sweep)?;
+        Ok(LockFreeKvsEngine {
+            inner,
+            _sweeper: Arc::new(sweeper),
+        })
+    }
+
+    fn live(&self, key: &[u8]) -> Option<Arc<Entry>> {
+        self.inner.live(key, now_millis())
+    }
+
+    /// The version for a new write. The caller holds the locks of the keys
+    /// it writes, so versions of a key only grow.
+    fn next_version(&self) -> u64 {
+        self.inner.version.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    /// Every key that may be live for which `keep` holds, in ascending order.
+    /// Values are not copied.
+    fn keys_where(&self, mut keep: impl FnMut(&Vec<u8>) -> bool) -> Vec<Vec<u8>> {
+        let now = now_millis();
+        let mut keys = Vec::new();
+        self.inner.map.for_each(|key, slot| {
+            let live = slot
+                .clone()
+                .visible()
+                .is_some_and(|entry| !entry.is_expired(now));
+            if live && keep(key) {
+                keys.push(key.clone());
+            }
+        });
+        keys.sort_unstable();
+        keys
+    }
+
+    /// Write `value` at a new version. The caller holds the lock of `key`.
+    fn put(&self, key: Vec<u8>, value: Vec<u8>, expires_at: Option<u64>) {
+        let version = self.next_version();
+        self.replace(key, value, version, expires_at);
+    }
+
+    fn replace(&self, key: Vec<u8>, value: Vec<u8>, version: u64, expires_at: Option<u64>) {
+        let entry = Entry {
+            value,
+            version,
+            expires_at,
+        };
+        self.inner.preserve(&key);
+        self.inner.map.add(&key, &Slot::new(entry));
+    }
+
+    /// Apply the writes of `batch` at one version. The caller holds the locks
+    /// of its keys.
+    ///
+    /// Each write goes in marked pending, so readers keep seeing the entry it
+    /// replaces, and then one flag publishes them all. The marks are cleared
+    /// afterwards. Only the last write to each key is applied, so clearing
+    /// the marks never shows a value the batch goes on to replace.
+    fn apply(&self, batch: WriteBatch) {
+        let version = self.next_version();
+        let committed = Arc::new(AtomicBool::new(false));
+        let mut written = BTreeMap::new();
+        for op in batch {
+            let (key, entry) = match op {
+                BatchOp::Set { key, value } => {
+                    let entry = Entry {
+                        value,
+                        version,
+                        expires_at: None,
+                    };
+                    (key, Some(Arc::new(entry)))
+                }
+                BatchOp::Remove { key } => (key, None),
+            };
+            written.insert(key, entry);
+        }
+        for (key, entry) in &written {
+            self.inner.preserve(key);
+            let previous = self.inner.visible(key);
+            let slot = Slot {
+                entry: entry.clone(),
+                pending: Some(Pending {
+                    committed: committed.clone(),
+                    previous,
+                }),
+            };
+            self.inner.map.add(key, &slot);
+        }
+        committed.store(true, Ordering::Release);
+        for (key, entry) in written {
+            match entry {
+                Some(entry) => {
+                    let slot = Slot {
+                        entry: Some(entry),
+                        pending: None,
+                    };
+                    self.inner.map.add(&key, &slot);
+                }
+                None => {
+                    self.inner.map.remove(&key);
+                }
+            }
+        }
+    }
+}
+
+/// Remove every expired key.
+fn sweep(inner: &Inner) -> Result<()> {
+    let now = now_millis();
+    let mut expired = Vec::new();
+    inner.map.for_each(|key, slot| {
+        if slot
+            .entry
+            .as_ref()
+            .is_some_and(|entry| entry.is_expired(now))
+        {
+            expired.push(key.clone());
+        }
+    });
+    for key in expired {
+        let _lock = inner.locks.lock(&key);
+        // Check again under the lock in case the key was written since.
+        if inner.live(&key, now).is_none() {
+            inner.remove(&key);
+        }
+    }
+    Ok(())
+}
+
+impl KvsEngine for LockFreeKvsEngine {
+    const NAME: &'static str = "memory";
+
+    type Snapshot = LockFreeKvsSnapshot;
+
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        let _lock = self.inner.locks.lock(&key);
+        self.put(key, value, None);
+        Ok(())
+    }
+
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        Ok(self.live(&key).map(|entry| entry.value.clone()))
+    }
+
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        let _lock = self.inner.locks.lock(&key);
+        if self.live(&key).is_none() {
+            return Err(KvsError::KeyNotFound);
+        }
+        self.inner.remove(&key);
+        Ok(())
+    }
+
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(
+        &self,
+        range: R,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        if is_empty_range(&range) {
+            return Ok(Vec::new());
+        }
+        // A key may be removed between listing it and reading it, so read
+        // until there are enough.
+        Ok(self
+            .keys_where(|key| range.contains(key))
+            .into_iter()
+            .filter_map(|key| {
+                let entry = self.live(&key)?;
+                Some((key, entry.value.clone()))
+            })
+            .take(limit)
+            .collect())
+    }
+
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<()> {
+        let _lock = self.inner.locks.lock(&key);
+        let current = self.live(&key).map(|entry| entry.value.clone());
+        if current != expected {
+            return Err(KvsError::Conflict { current });
+        }
+        match new {
+            Some(value) => self.put(key, value, None),
+            None => self.inner.remove(&key),
+        }
+        Ok(())
+    }
+
+    fn get_with_version_bytes(&self, key: Vec<u8>) -> Result<Option<(Vec<u8>, u64)>> {
+        Ok(self
+            .live(&key)
+            .map(|entry| (entry.value.clone(), entry.version)))
+    }
+
+    fn set_if_version_bytes(&self, key: Vec<u8>, value: Vec<u8>, version: u64) -> Result<()> {
+        let _lock = self.inner.locks.lock(&key);
+        match self.live(&key) {
+            Some(entry) if entry.version == version => {}
+            current => {
+                return Err(KvsError::Conflict {
+                    current: current.map(|entry| entry.value.clone()),
+                })
+            }
+        }
+        self.put(key, value, None);
+        Ok(())
+    }
+
+    fn set_with_ttl_bytes(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let _lock = self.inner.locks.lock(&key);
+        self.put(key, value, Some(expiry::expires_at(ttl)));
+        Ok(())
+    }
+
+    /// Changing the expiry time of a key keeps its version.
+    fn expire_bytes(&self, key: Vec<u8>, ttl: Duration) -> Result<()> {
+        let _lock = self.inner.locks.lock(&key);
+        let entry = self.live(&key).ok_or(KvsError::KeyNotFound)?;
+        let expires_at = Some(expiry::expires_at(ttl));
+        self.replace(key, entry.value.clone(), entry.version, expires_at);
+        Ok(())
+    }
+
+    fn ttl_bytes(&self, key: Vec<u8>) -> Result<Option<Duration>> {
+        let entry = self.live(&key).ok_or(KvsError::KeyNotFound)?;
+        Ok(entry.expires_at.map(expiry::remaining))
+    }
+
+    fn persist_bytes(&self, key: Vec<u8>) -> Result<()> {
+        let _lock = self.inner.locks.lock(&key);
+        let entry = self.live(&key).ok_or(KvsError::KeyNotFound)?;
+        if entry.expires_at.is_some() {
+            self.replace(key, entry.value.clone(), entry.version, None);
+        }
+        Ok(())
+    }
+
+    fn keys_bytes(&self) -> Result<Vec<Vec<u8>>> {
+        Ok(self.keys_where(|_| true))
+    }
+
+    fn apply_batch(&self, batch: WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let _locks = self
+            .inner
+            .locks
+            .lock_keys(batch.ops().iter().map(BatchOp::key));
+        self.apply(batch);
+        Ok(())
+    }
+
+    /// While the snapshot is open every write first saves the entry it
+    /// replaces. Taking one only waits for the writes in flight.
+    fn snapshot(&self) -> Result<LockFreeKvsSnapshot> {
+        let epoch = {
+            let _locks = self.inner.locks.lock_all();
+            self.inner.preserved.acquire()
+        };
+        Ok(LockFreeKvsSnapshot {
+            inner: self.inner.clone(),
+            epoch,
+            now: now_millis(),
+            keys: OnceLock::new(),
+        })
+    }
+
+    fn commit_transaction(
+        &self,
+        reads: &BTreeMap<Vec<u8>, Option<u64>>,
+        batch: WriteBatch,
+    ) -> Result<()> {
+        let _locks = self.inner.locks.lock_keys(
+            reads
+                .keys()
+                .map(Vec::as_slice)
+                .chain(batch.ops().iter().map(BatchOp::key)),
+        );
+        for (key, expected) in reads {
+            let current = self.live(key).map(|entry| entry.version);
+            if current != *expected {
+                return Err(KvsError::TransactionConflict { key: key.clone() });
+            }
+        }
+        if !batch.is_empty() {
+            self.apply(batch);
+        }
+        Ok(())
+    }
+
+    /// There is nothing to make durable.
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// A view of a `LockFreeKvsEngine`, from `KvsEngine::snapshot`.
+///
+/// Reads go to the map, and to the entries writes saved for the keys they
+/// replaced since.
+pub struct LockFreeKvsSnapshot {
+    inner: Arc<Inner>,
+    epoch: u64,
+    /// When the snapshot was taken, which decides what has expired.
+    now: u64,
+    /// The keys in the map, sorted, listed by the first scan. Keys removed
+    /// before that have their entries saved.
+    keys: OnceLock<Vec<Vec<u8>>>,
+}
+
+impl LockFreeKvsSnapshot {
+    /// The entry `key` had, unless it was missing or expired. The live entry
+    /// is read first, so a write that replaces it in the meantime has already
+    /// saved it.
+    fn entry(&self, key: &[u8]) -> Option<Arc<Entry>> {
+        let live = self.inner.visible(key);
+        self.inner
+            .preserved
+            .get(key, self.epoch)
+            .unwrap_or(live)
+            .filter(|entry| !entry.is_expired(self.now))
+    }
+
+    fn keys(&self) -> &[Vec<u8>] {
+        self.keys.get_or_init(|| {
+            let mut keys = Vec::new();
+            self.inner.map.for_each(|key, _| keys.push(key.clone()));
+            keys.sort_unstable();
+            keys
+        })
+    }
+}
+
+impl KvsSnapshot for LockFreeKvsSnapshot {
+    fn get_with_version_bytes(&self, key: Vec<u8>) -> Result<Option<(Vec<u8>, u64)>> {
+        Ok(self
+            .entry(&key)
+            .map(|entry| (entry.value.clone(), entry.version)))
+    }
+
+    fn scan_with_expiry_bytes<R: RangeBounds<Vec<u8>>>(
+        &self,
+        range: R,
+        limit: usize,
+    ) -> Result<Vec<ExpiringPair>> {
+        if is_empty_range(&range) {
+            return Ok(Vec::new());
+        }
+        let keys = self.keys();
+        let read_live = |range: KeyRange, limit: usize| {
+            let start = keys.partition_point(|key| match &range.0 {
+                Bound::Included(start) => key < start,
+                Bound::Excluded(start) => key <= start,
+                Bound::Unbounded => false,
+            });
+            Ok(keys[start..]
+                .iter()
+                .take_while(|key| range.contains(key))
+                .take(limit)
+                .map(|key| (key.clone(), self.inner.visible(key)))
+                .collect())
+        };
+        let range = (range.start_bound().cloned(), range.end_bound().cloned());
+        let entries = self
+            .inner
+            .preserved
+            .scan(self.epoch, range, limit, read_live, |entry| {
+                !entry.is_expired(self.now)
+            })?;
+        Ok(entries
+            .into_iter()
+            .map(|(key, entry)| (key, entry.value.clone(), entry.expires_at))
+            .collect())
+    }
+}
+
+impl Drop for LockFreeKvsSnapshot {
+    fn drop(&mut self) {
+        self.inner.preserved.release(self.epoch);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_readers_never_see_part_of_a_batch() {
+        let engine = LockFreeKvsEngine::new().unwrap();
+        let count = |key: &str| -> u32 {
+            engine
+                .get(key.to_string())
+                .unwrap()
+                .map_or(0, |value| value.parse().unwrap())
+        };
+        thread::scope(|scope| {
+            let writer = engine.clone();
+            scope.spawn(move || {
+                for i in 1..=2000u32 {
+                    let batch = WriteBatch::new()
+                        .set("a", i.to_string())
+                        .set("c", i.to_string())
+                        .remove("c")
+                        .set("b", i.to_string());
+                    writer.apply_batch(batch).unwrap();
+                }
+            });
+            while count("b") < 2000 {
+                // The batch writes `a` first, so reading `a` before `b`
+                // would catch a half-applied one.
+                let a = count("a");
+                assert!(count("b") >= a);
+                // No batch leaves `c` behind.
+                assert_eq!(count("c"), 0);
+            }
+        });
+    }
+}
@@ -0,0 +1,210 @@
+//! The metadata file `kvs-server` keeps at the top of its data directory.
+//!
+//! `ENGINE` names the engine whose data lives in the subdirectory of the same
+//! name, along with the version of this file's format and when the data
+//! directory was created. It is written on first start, checked on every start
+//! after that, and switched over by `kvs-admin migrate`.
+
+use std::{
+    fs::{self, File},
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use super::kv_store::KvStore;
+use super::kvs_engine::{KvsEngine, SledKvsEngine};
+use crate::{KvsError, Result};
+
+/// Name of the metadata file in a data directory.
+pub const METADATA_FILE: &str = "ENGINE";
+
+/// Version of the metadata format written by this build.
+const FORMAT: u32 = 1;
+
+/// What the `ENGINE` file of a data directory says.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EngineMetadata {
+    pub format: u32,
+    /// The name of the engine, as given to `kvs-server --engine`.
+    pub engine: String,
+    /// When the data directory was created, in seconds since the Unix epoch.
+    pub created_at: u64,
+}
+
+impl EngineMetadata {
+    /// Metadata for a new data directory holding `engine`.
+    pub fn new(engine: &str) -> EngineMetadata {
+        EngineMetadata {
+            format: FORMAT,
+            engine: engine.to_string(),
+            created_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |now| now.as_secs()),
+        }
+    }
+
+    /// Read the metadata of the data directory `dir`, or `None` if it holds
+    /// no data yet.
+    ///
+    /// For a directory from before metadata files existed, with a `kvs` or
+    /// `sled` subdirectory and no `ENGINE` file, the metadata is worked out
+    /// from the subdirectory but not written.
+    pub fn load(dir: &Path) -> Result<Option<EngineMetadata>> {
+        match Self::read(dir)? {
+            Some(metadata) => Ok(Some(metadata)),
+            None => Self::infer(dir),
+        }
+    }
+
+    /// Like `load`, but also writes the `ENGINE` file of a directory from
+    /// before metadata files existed. Only `kvs-server` does this, when it
+    /// starts.
+    pub fn adopt(dir: &Path) -> Result<Option<EngineMetadata>> {
+        if let Some(metadata) = Self::read(dir)? {
+            return Ok(Some(metadata));
+        }
+        let Some(metadata) = Self::infer(dir)? else {
+            return Ok(None);
+        };
+        info!(
+            "Writing {} for the existing {} data in {}",
+            METADATA_FILE,
+            metadata.engine,
+            dir.display()
+        );
+        metadata.store(dir)?;
+        Ok(Some(metadata))
+    }
+
+    /// The same metadata, for the data directory once it holds `engine`.
+    pub fn with_engine(&self, engine: &str) -> EngineMetadata {
+        EngineMetadata {
+            engine: engine.to_string(),
+            ..self.clone()
+        }
+    }
+
+    /// Read and check the metadata file of `dir`, if there is one.
+    fn read(dir: &Path) -> Result<Option<EngineMetadata>> {
+        let metadata: EngineMetadata = match fs::read(dir.join(METADATA_FILE)) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .map_err(|e| invalid(dir, &format!("malformed: {}", e)))?,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        if metadata.format > FORMAT {
+            return Err(invalid(
+                dir,
+                &format!("format {} is newer than this build", metadata.format),
+            ));
+        }
+        if ![KvStore::NAME, SledKvsEngine::NAME].contains(&metadata.engine.as_str()) {
+            return Err(invalid(
+                dir,
+                &format!("unknown engine '{}'", metadata.engine),
+            ));
+        }
+        Ok(Some(metadata))
+    }
+
+    /// Write the metadata file of `dir`, replacing any other atomically.
+    pub fn store(&self, dir: &Path) -> Result<()> {
+        let partial = dir.join(format!("{}.partial", METADATA_FILE));
+        let mut file = File::create(&partial)?;
+        serde_json::to_writer(&mut file, self)?;
+        file.write_all(b"\n")?;
+        file.sync_all()?;
+        fs::rename(partial, dir.join(METADATA_FILE))?;
+        Ok(())
+    }
+
+    /// Where the engine keeps its data inside `dir`.
+    pub fn engine_dir(&self, dir: &Path) -> PathBuf {
+        dir.join(&self.engine)
+    }
+
+    /// Work out the metadata of a directory without a metadata file from the
+    /// engine subdirectory it holds.
+    fn infer(dir: &Path) -> Result<Option<EngineMetadata>> {
+        let engines: Vec<&str> = [KvStore::NAME, SledKvsEngine::NAME]
+            .into_iter()
+            .filter(|name| dir.join(name).is_dir())
+            .collect();
+        match engines[..] {
+            [] => Ok(None),
+            [engine] => Ok(Some(EngineMetadata::new(engine))),
+            _ => Err(invalid(
+                dir,
+                "missing, and both kvs and sled data are present",
+            )),
+        }
+    }
+}
+
+fn invalid(dir: &Path, reason: &str) -> KvsError {
+    KvsError::Corruption(format!(
+        "Invalid {}: {}",
+        dir.join(METADATA_FILE).display(),
+        reason
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_load_validates_metadata() {
+        let dir = TempDir::new().unwrap();
+        assert_eq!(EngineMetadata::load(dir.path()).unwrap(), None);
+
+        let metadata = EngineMetadata::new("sled");
+        metadata.store(dir.path()).unwrap();
+        assert_eq!(EngineMetadata::load(dir.path()).unwrap(), Some(metadata));
+
+        for bad in [
+            r#"{"format":2,"engine":"kvs","created_at":0}"#,
+            r#"{"format":1,"engine":"mysled","created_at":0}"#,
+            "kvs",
+        ] {
+            fs::write(dir.path().join(METADATA_FILE), bad).unwrap();
+            assert!(matches!(
+                EngineMetadata::load(dir.path()),
+                Err(KvsError::Corruption(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_load_adopts_existing_data() {
+        let dir = TempDir::new().unwrap();
+        fs::create_dir(dir.path().join("mysled")).unwrap();
+        assert_eq!(EngineMetadata::load(dir.path()).unwrap(), None);
+
+        fs::create_dir(dir.path().join("kvs")).unwrap();
+        let metadata = EngineMetadata::load(dir.path()).unwrap().unwrap();
+        assert_eq!(metadata.engine, "kvs");
+        assert!(!dir.path().join(METADATA_FILE).exists());
+
+        let metadata = EngineMetadata::adopt(dir.path()).unwrap().unwrap();
+        assert_eq!(metadata.engine, "kvs");
+        assert_eq!(EngineMetadata::load(dir.path()).unwrap(), Some(metadata));
+    }
+
+    #[test]
+    fn test_adopt_refuses_ambiguous_data() {
+        let dir = TempDir::new().unwrap();
+        fs::create_dir(dir.path().join("kvs")).unwrap();
+        fs::create_dir(dir.path().join("sled")).unwrap();
+        assert!(matches!(
+            EngineMetadata::adopt(dir.path()),
+            Err(KvsError::Corruption(_))
+        ));
+        assert!(!dir.path().join(METADATA_FILE).exists());
+    }
+}
@@ -1,2 +1,17 @@
+mod background;
+pub mod backup;
+pub mod bytes;
+pub mod dir_lock;
+pub mod dump;
+pub mod durability;
+mod expiry;
+mod key_locks;
 pub mod kv_store;
 pub mod kvs_engine;
+pub mod lock_free_engine;
+pub mod metadata;
+mod preserved;
+mod record;
+pub mod snapshot;
+pub mod transaction;
+pub mod write_batch;
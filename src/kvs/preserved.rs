@@ -0,0 +1,255 @@
+use std::{
+    collections::BTreeMap,
+    ops::Bound,
+    sync::{Mutex, MutexGuard},
+};
+
+use super::kv_store::is_empty_range;
+use crate::Result;
+
+/// A range of keys, owned.
+pub(crate) type KeyRange = (Bound<Vec<u8>>, Bound<Vec<u8>>);
+
+/// Entries read from the live store per step of a snapshot scan.
+const SCAN_CHUNK: usize = 1024;
+
+/// The entries that writes replaced while snapshots were open, for engines
+/// that only store the latest entry of each key.
+///
+/// Registering a snapshot starts a new epoch. The first write to a key in an
+/// epoch saves the entry it replaces, so a snapshot sees the first entry of a
+/// key saved in its epoch or later, or the live one if there is none.
+pub(crate) struct Preserved<E> {
+    state: Mutex<State<E>>,
+}
+
+struct State<E> {
+    /// The epoch of the latest snapshot.
+    epoch: u64,
+    /// The epoch of every open snapshot, with how many there are.
+    snapshots: BTreeMap<u64, usize>,
+    /// The saved entries of each key, oldest first, with the epoch they were
+    /// saved in. `None` for a key that did not exist.
+    saved: BTreeMap<Vec<u8>, Vec<(u64, Option<E>)>>,
+}
+
+impl<E> Default for Preserved<E> {
+    fn default() -> Self {
+        Preserved {
+            state: Mutex::new(State {
+                epoch: 0,
+                snapshots: BTreeMap::new(),
+                saved: BTreeMap::new(),
+            }),
+        }
+    }
+}
+
+impl<E: Clone> Preserved<E> {
+    fn lock(&self) -> MutexGuard<'_, State<E>> {
+        self.state.lock().expect("Poisoned lock")
+    }
+
+    /// Register a snapshot and return its epoch. The caller holds every key
+    /// lock, so no write is half done.
+    pub(crate) fn acquire(&self) -> u64 {
+        let mut state = self.lock();
+        state.epoch += 1;
+        let epoch = state.epoch;
+        *state.snapshots.entry(epoch).or_default() += 1;
+        epoch
+    }
+
+    /// Unregister a snapshot, dropping the entries no open snapshot sees.
+    pub(crate) fn release(&self, epoch: u64) {
+        let mut state = self.lock();
+        if let Some(count) = state.snapshots.get_mut(&epoch) {
+            *count -= 1;
+            if *count == 0 {
+                state.snapshots.remove(&epoch);
+            }
+        }
+        let Some(&oldest) = state.snapshots.keys().next() else {
+            state.saved.clear();
+            return;
+        };
+        state.saved.retain(|_, entries| {
+            entries.retain(|(saved_in, _)| *saved_in >= oldest);
+            !entries.is_empty()
+        });
+    }
+
+    /// The epoch to save the entry of `key` in before it is written, if an
+    /// open snapshot may need it. The caller holds the lock of `key` until it
+    /// has saved the entry, so that neither changes in between.
+    pub(crate) fn needs(&self, key: &[u8]) -> Option<u64> {
+        let state = self.lock();
+        if state.snapshots.is_empty() {
+            return None;
+        }
+        let saved_in = state.saved.get(key).and_then(|entries| entries.last());
+        match saved_in {
+            Some((saved_in, _)) if *saved_in == state.epoch => None,
+            _ => Some(state.epoch),
+        }
+    }
+
+    /// Save `entry` as the entry of `key`, in the epoch `needs` returned.
+    pub(crate) fn save(&self, key: &[u8], epoch: u64, entry: Option<E>) {
+        self.lock()
+            .saved
+            .entry(key.to_vec())
+            .or_default()
+            .push((epoch, entry));
+    }
+
+    /// The entry of `key` as of `epoch`, if it has been written since.
+    pub(crate) fn get(&self, key: &[u8], epoch: u64) -> Option<Option<E>> {
+        seen_at(self.lock().saved.get(key)?, epoch)
+    }
+
+    /// The entries as of `epoch` of the keys in `range` written since.
+    fn range(&self, range: KeyRange, epoch: u64) -> BTreeMap<Vec<u8>, Option<E>> {
+        self.lock()
+            .saved
+            .range(range)
+            .filter_map(|(key, entries)| Some((key.clone(), seen_at(entries, epoch)?)))
+            .collect()
+    }
+
+    /// Up to `limit` entries as of `epoch` whose keys fall in `range` and
+    /// pass `keep`, in ascending key order.
+    ///
+    /// `read_live` reads up to the given number of keys in a range from the
+    /// live store, with `None` for a key it listed but found gone. Saved
+    /// entries are read after the live ones they may
+    /// replace, so a write that lands in between is always caught.
+    pub(crate) fn scan(
+        &self,
+        epoch: u64,
+        range: KeyRange,
+        limit: usize,
+        mut read_live: impl FnMut(KeyRange, usize) -> Result<Vec<(Vec<u8>, Option<E>)>>,
+        keep: impl Fn(&E) -> bool,
+    ) -> Result<Vec<(Vec<u8>, E)>> {
+        let (mut start, end) = range;
+        let mut found = Vec::new();
+        while found.len() < limit && !is_empty_range(&(start.clone(), end.clone())) {
+            let live = read_live((start.clone(), end.clone()), SCAN_CHUNK)?;
+            let last = match live.last() {
+                Some((key, _)) if live.len() == SCAN_CHUNK => Some(key.clone()),
+                _ => None,
+            };
+            let upper = match &last {
+                Some(key) => Bound::Included(key.clone()),
+                None => end.clone(),
+            };
+            let mut entries: BTreeMap<_, _> = live.into_iter().collect();
+            entries.append(&mut self.range((start, upper), epoch));
+            let wanted = limit - found.len();
+            found.extend(
+                entries
+                    .into_iter()
+                    .filter_map(|(key, entry)| Some((key, entry?)))
+                    .filter(|(_, entry)| keep(entry))
+                    .take(wanted),
+            );
+            match last {
+                Some(key) => start = Bound::Excluded(key),
+                None => break,
+            }
+        }
+        Ok(found)
+    }
+}
+
+/// The first entry saved in `epoch` or later.
+fn seen_at<E: Clone>(entries: &[(u64, Option<E>)], epoch: u64) -> Option<Option<E>> {
+    entries
+        .iter()
+        .find(|(saved_in, _)| *saved_in >= epoch)
+        .map(|(_, entry)| entry.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Save `entry` for `key` if a snapshot needs it, as writers do.
+    fn write(preserved: &Preserved<i32>, key: &[u8], entry: Option<i32>) {
+        if let Some(epoch) = preserved.needs(key) {
+            preserved.save(key, epoch, entry);
+        }
+    }
+
+    #[test]
+    fn test_snapshots_see_the_entry_of_their_epoch() {
+        let preserved = Preserved::default();
+        write(&preserved, b"a", Some(0));
+        assert_eq!(preserved.get(b"a", 0), None);
+
+        let first = preserved.acquire();
+        write(&preserved, b"a", Some(1));
+        write(&preserved, b"a", Some(2));
+        let second = preserved.acquire();
+        write(&preserved, b"a", Some(3));
+        write(&preserved, b"b", None);
+
+        assert_eq!(preserved.get(b"a", first), Some(Some(1)));
+        assert_eq!(preserved.get(b"a", second), Some(Some(3)));
+        assert_eq!(preserved.get(b"b", first), Some(None));
+
+        preserved.release(first);
+        assert_eq!(preserved.get(b"a", first), Some(Some(3)));
+        preserved.release(second);
+        assert_eq!(preserved.get(b"a", second), None);
+    }
+
+    #[test]
+    fn test_scans_prefer_saved_entries() {
+        let preserved = Preserved::default();
+        let epoch = preserved.acquire();
+        write(&preserved, b"b", Some(2));
+        write(&preserved, b"c", None);
+        write(&preserved, b"d", Some(4));
+
+        let live: Vec<(Vec<u8>, Option<i32>)> = (0..3000)
+            .map(|i| (format!("a{:04}", i).into_bytes(), Some(i)))
+            .chain([(b"b".to_vec(), Some(20)), (b"c".to_vec(), Some(30))])
+            .chain([(b"e".to_vec(), None)])
+            .collect();
+        let read_live = |range: KeyRange, limit: usize| {
+            Ok(live
+                .iter()
+                .filter(|(key, _)| std::ops::RangeBounds::contains(&range, key))
+                .take(limit)
+                .cloned()
+                .collect())
+        };
+        let scanned = preserved
+            .scan(
+                epoch,
+                (Bound::Unbounded, Bound::Unbounded),
+                usize::MAX,
+                read_live,
+                |_| true,
+            )
+            .unwrap();
+        assert_eq!(scanned.len(), 3002);
+        assert_eq!(scanned[3000..], [(b"b".to_vec(), 2), (b"d".to_vec(), 4)]);
+
+        let scanned = preserved
+            .scan(
+                epoch,
+                (Bound::Unbounded, Bound::Unbounded),
+                5,
+                read_live,
+                |entry| entry % 2 == 1,
+            )
+            .unwrap();
+        assert_eq!(
+            scanned.iter().map(|(_, entry)| *entry).collect::<Vec<_>>(),
+            [1, 3, 5, 7, 9]
+        );
+    }
+}
@@ -0,0 +1,92 @@
+//! On-disk framing for log records.
+//!
+//! Every record is written as a little-endian `u32` payload length, a
+//! little-endian `u32` CRC32 of the payload, and then the payload itself.
+
+use std::io::{self, ErrorKind, Read, Write};
+
+/// Size of the length and checksum that precede every payload.
+pub const HEADER_LEN: u64 = 8;
+
+/// Write `payload` as a single framed record.
+pub fn write_record<W: Write>(writer: &mut W, payload: &[u8]) -> io::Result<()> {
+    let len = u32::try_from(payload.len())
+        .map_err(|_| io::Error::new(ErrorKind::InvalidInput, "record too large"))?;
+    let mut header = [0; HEADER_LEN as usize];
+    header[..4].copy_from_slice(&len.to_le_bytes());
+    header[4..].copy_from_slice(&crc32fast::hash(payload).to_le_bytes());
+    writer.write_all(&header)?;
+    writer.write_all(payload)
+}
+
+/// Read the next framed record.
+///
+/// Returns `Ok(None)` on a clean end of file. A record that is cut short or
+/// fails its checksum is reported as `UnexpectedEof` or `InvalidData`.
+pub fn read_record<R: Read>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut header = [0; HEADER_LEN as usize];
+    let mut filled = 0;
+    while filled < header.len() {
+        match reader.read(&mut header[filled..]) {
+            Ok(0) if filled == 0 => return Ok(None),
+            Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    let len = u32::from_le_bytes(header[..4].try_into().unwrap());
+    let crc = u32::from_le_bytes(header[4..].try_into().unwrap());
+
+    let mut payload = Vec::new();
+    reader.take(len as u64).read_to_end(&mut payload)?;
+    if payload.len() != len as usize {
+        return Err(ErrorKind::UnexpectedEof.into());
+    }
+    if crc32fast::hash(&payload) != crc {
+        return Err(io::Error::new(ErrorKind::InvalidData, "checksum mismatch"));
+    }
+    Ok(Some(payload))
+}
+
+/// Whether an error from `read_record` means the record is torn or corrupt
+/// rather than unreadable.
+pub fn is_corruption(e: &io::Error) -> bool {
+    matches!(e.kind(), ErrorKind::UnexpectedEof | ErrorKind::InvalidData)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_round_trip() {
+        let mut buf = Vec::new();
+        write_record(&mut buf, b"hello").unwrap();
+        write_record(&mut buf, b"").unwrap();
+        let mut reader = Cursor::new(buf);
+        assert_eq!(read_record(&mut reader).unwrap(), Some(b"hello".to_vec()));
+        assert_eq!(read_record(&mut reader).unwrap(), Some(Vec::new()));
+        assert_eq!(read_record(&mut reader).unwrap(), None);
+    }
+
+    #[test]
+    fn test_torn_and_corrupt_records() {
+        let mut buf = Vec::new();
+        write_record(&mut buf, b"hello").unwrap();
+        let torn = &buf[..buf.len() - 1];
+        assert!(is_corruption(
+            &read_record(&mut Cursor::new(torn)).unwrap_err()
+        ));
+        assert!(is_corruption(
+            &read_record(&mut Cursor::new(&buf[..3])).unwrap_err()
+        ));
+
+        let last = buf.len() - 1;
+        buf[last] ^= 0xff;
+        assert!(is_corruption(
+            &read_record(&mut Cursor::new(buf)).unwrap_err()
+        ));
+    }
+}
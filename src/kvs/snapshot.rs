@@ -0,0 +1,59 @@
+use std::ops::RangeBounds;
+
+use super::bytes::{pair_to_strings, range_to_bytes};
+use crate::Result;
+
+/// A key, its value and when it expires, in milliseconds since the Unix
+/// epoch, if it does.
+pub type ExpiringPair = (Vec<u8>, Vec<u8>, Option<u64>);
+
+/// A read-only view of a `KvsEngine` frozen at the moment it was taken by
+/// `KvsEngine::snapshot`.
+///
+/// Writes made to the engine afterwards are invisible to it, and keys that
+/// were live when it was taken stay live in it even once they expire.
+pub trait KvsSnapshot: Send + 'static {
+    /// The value of `key` and the version it had when the snapshot was taken.
+    fn get_with_version_bytes(&self, key: Vec<u8>) -> Result<Option<(Vec<u8>, u64)>>;
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        Ok(self.get_with_version_bytes(key)?.map(|(value, _)| value))
+    }
+    /// Like `scan_bytes`, along with when each key expires, in milliseconds
+    /// since the Unix epoch, if it does.
+    fn scan_with_expiry_bytes<R: RangeBounds<Vec<u8>>>(
+        &self,
+        range: R,
+        limit: usize,
+    ) -> Result<Vec<ExpiringPair>>;
+    /// Up to `limit` key/value pairs whose keys fall in `range`, in ascending
+    /// byte order.
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(
+        &self,
+        range: R,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        Ok(self
+            .scan_with_expiry_bytes(range, limit)?
+            .into_iter()
+            .map(|(key, value, _)| (key, value))
+            .collect())
+    }
+    fn get(&self, key: String) -> Result<Option<String>> {
+        Ok(self
+            .get_bytes(key.into_bytes())?
+            .map(String::from_utf8)
+            .transpose()?)
+    }
+    /// Up to `limit` key/value pairs whose keys fall in `range`, in ascending
+    /// key order.
+    fn scan<R: RangeBounds<String>>(
+        &self,
+        range: R,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>> {
+        self.scan_bytes(range_to_bytes(&range), limit)?
+            .into_iter()
+            .map(pair_to_strings)
+            .collect()
+    }
+}
@@ -0,0 +1,88 @@
+use std::collections::BTreeMap;
+
+use super::kvs_engine::KvsEngine;
+use super::snapshot::KvsSnapshot;
+use super::write_batch::WriteBatch;
+use crate::Result;
+
+/// An optimistic transaction over a `KvsEngine`, started with
+/// `KvsEngine::begin`.
+///
+/// Reads go to a snapshot taken at the first read, so they all see the same
+/// state, and remember the version they saw; writes are buffered and visible
+/// to later reads in the same transaction. `commit`
+/// applies the writes atomically, but only if none of the keys read has been
+/// written since. Otherwise it fails with `KvsError::TransactionConflict` and
+/// nothing is written. Dropping a transaction discards it.
+pub struct Transaction<E: KvsEngine> {
+    engine: E,
+    snapshot: Option<E::Snapshot>,
+    /// Version of every key read, `None` if it did not exist.
+    reads: BTreeMap<Vec<u8>, Option<u64>>,
+    /// Buffered writes, `None` for a removal.
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl<E: KvsEngine> Transaction<E> {
+    pub(crate) fn new(engine: E) -> Transaction<E> {
+        Transaction {
+            engine,
+            snapshot: None,
+            reads: BTreeMap::new(),
+            writes: BTreeMap::new(),
+        }
+    }
+
+    pub fn get_bytes(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = self.writes.get(&key) {
+            return Ok(value.clone());
+        }
+        let snapshot = match &mut self.snapshot {
+            Some(snapshot) => snapshot,
+            None => self.snapshot.insert(self.engine.snapshot()?),
+        };
+        let current = snapshot.get_with_version_bytes(key.clone())?;
+        self.reads
+            .insert(key, current.as_ref().map(|(_, version)| *version));
+        Ok(current.map(|(value, _)| value))
+    }
+
+    pub fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.writes.insert(key, Some(value));
+    }
+
+    /// Remove a key. Removing a key that does not exist is not an error.
+    pub fn remove_bytes(&mut self, key: Vec<u8>) {
+        self.writes.insert(key, None);
+    }
+
+    /// Get the value of `key`, failing with `KvsError::Utf8` if it is not
+    /// valid UTF-8.
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        Ok(self
+            .get_bytes(key.into_bytes())?
+            .map(String::from_utf8)
+            .transpose()?)
+    }
+
+    pub fn set(&mut self, key: String, value: String) {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    /// Remove a key. Removing a key that does not exist is not an error.
+    pub fn remove(&mut self, key: String) {
+        self.remove_bytes(key.into_bytes())
+    }
+
+    /// Apply the buffered writes if no key read has changed since.
+    pub fn commit(self) -> Result<()> {
+        let batch = self
+            .writes
+            .into_iter()
+            .fold(WriteBatch::new(), |batch, (key, value)| match value {
+                Some(value) => batch.set(key, value),
+                None => batch.remove(key),
+            });
+        self.engine.commit_transaction(&self.reads, batch)
+    }
+}
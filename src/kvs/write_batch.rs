@@ -0,0 +1,82 @@
+use serde::{Deserialize, Serialize};
+
+use super::bytes;
+
+/// One write in a `WriteBatch`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BatchOp {
+    Set {
+        #[serde(with = "bytes")]
+        key: Vec<u8>,
+        #[serde(with = "bytes")]
+        value: Vec<u8>,
+    },
+    Remove {
+        #[serde(with = "bytes")]
+        key: Vec<u8>,
+    },
+}
+
+impl BatchOp {
+    /// The key the write touches.
+    pub fn key(&self) -> &[u8] {
+        match self {
+            BatchOp::Set { key, .. } | BatchOp::Remove { key } => key,
+        }
+    }
+}
+
+/// Writes applied together by `KvsEngine::apply_batch`: either every write
+/// in the batch takes effect or none does.
+///
+/// Writes apply in the order they were added, so a later write to a key wins.
+/// Unlike `KvsEngine::remove`, removing a missing key is not an error.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+impl WriteBatch {
+    pub fn new() -> WriteBatch {
+        WriteBatch::default()
+    }
+
+    /// Set a key to a value. Both can be strings or raw bytes.
+    #[must_use]
+    pub fn set(mut self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> WriteBatch {
+        self.ops.push(BatchOp::Set {
+            key: key.into(),
+            value: value.into(),
+        });
+        self
+    }
+
+    /// Remove a key.
+    #[must_use]
+    pub fn remove(mut self, key: impl Into<Vec<u8>>) -> WriteBatch {
+        self.ops.push(BatchOp::Remove { key: key.into() });
+        self
+    }
+
+    /// The writes in the order they apply.
+    pub fn ops(&self) -> &[BatchOp] {
+        &self.ops
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
+
+impl IntoIterator for WriteBatch {
+    type Item = BatchOp;
+    type IntoIter = std::vec::IntoIter<BatchOp>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.ops.into_iter()
+    }
+}
@@ -1,43 +1,16 @@
+mod error;
 pub mod kvs;
 pub mod lock_free;
 pub mod net;
 pub mod thread_pool;
 
-pub use kvs::kv_store::KvStore;
-pub use kvs::kvs_engine::{KvsEngine, SledKvsEngine};
-use serde::{de::DeserializeOwned, Serialize};
+pub use error::KvsError;
+pub use kvs::durability::Durability;
+pub use kvs::kv_store::{KvStore, KvStoreConfig, KvStoreSnapshot};
+pub use kvs::kvs_engine::{KvsEngine, SledKvsEngine, SledKvsSnapshot};
+pub use kvs::lock_free_engine::{LockFreeKvsEngine, LockFreeKvsSnapshot};
+pub use kvs::snapshot::KvsSnapshot;
+pub use kvs::transaction::Transaction;
+pub use kvs::write_batch::{BatchOp, WriteBatch};
 
-pub type Result<T> = std::result::Result<T, String>;
-
-pub trait ToString {
-    fn to_string(&self) -> String;
-}
-
-pub trait ToResult {
-    fn to_result<T: DeserializeOwned + Clone>(&self) -> Result<T>;
-}
-
-impl<T: Serialize> ToString for Result<T> {
-    fn to_string(&self) -> String {
-        match self {
-            Ok(i) => format!("Ok {}", serde_json::to_string(i).unwrap()),
-            Err(e) => format!("Err {}", e),
-        }
-    }
-}
-
-impl ToResult for String {
-    fn to_result<T: DeserializeOwned + Clone>(&self) -> Result<T> {
-        if self.is_empty() {
-            return Err("Empty result".to_string());
-        }
-        let mut parts = self.splitn(2, ' ');
-        let result = parts.next().unwrap();
-        let value = parts.next().unwrap();
-        match result {
-            "Ok" => Ok(serde_json::from_str::<T>(value).map_err(|e| e.to_string())?),
-            "Err" => Err(value.to_string()),
-            _ => Err("Invalid result".to_string()),
-        }
-    }
-}
+pub type Result<T> = std::result::Result<T, KvsError>;
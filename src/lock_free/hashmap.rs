@@ -1,60 +1,85 @@
 use std::hash::{DefaultHasher, Hash, Hasher};
-use std::sync::atomic::Ordering;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
-use crossbeam::epoch::{self, Atomic, Owned};
+use crossbeam::epoch::{self, Atomic, Guard, Owned, Shared};
 
-use super::map::Map;
+use super::map::{mark, Value};
 
-pub struct HashMapNode<K: Clone + PartialOrd + Hash, V: Clone> {
-    map: Map<K, V>,
-    hash: u64,
+/// Average number of pairs per bucket above which the buckets double.
+const LOAD_FACTOR: usize = 2;
+
+/// Number of segments of the bucket array. Segment 0 holds bucket 0 and
+/// segment `s` holds buckets `2^(s-1)..2^s`, so existing buckets never move
+/// as the array grows.
+const SEGMENTS: usize = 48;
+
+/// Most buckets a `HashMap` grows to.
+const MAX_BUCKETS: usize = 1 << (SEGMENTS - 1);
+
+/// A node of a `HashMap`: either a pair, or the sentinel a bucket starts at.
+///
+/// Nodes are removed like those of a `Map`: the value is taken, `next` is
+/// tagged, then the node is unlinked. Sentinels are never removed.
+pub struct HashMapNode<K: Clone + PartialOrd + Hash + Send + 'static, V: Clone + Send + 'static> {
+    /// Where the node sorts: the bit-reversed hash, odd for pairs and even
+    /// for sentinels.
+    order: u64,
+    /// `None` for sentinels.
+    key: Option<K>,
+    value: Value<V>,
     next: Atomic<HashMapNode<K, V>>,
 }
 
-impl<K: Clone + PartialOrd + Hash, V: Clone> HashMapNode<K, V> {
-    pub fn new(key: &K, value: &V, hash: u64) -> Owned<HashMapNode<K, V>> {
-        let map = Map::new();
-        map.add(key, value);
+impl<K: Clone + PartialOrd + Hash + Send + 'static, V: Clone + Send + 'static> HashMapNode<K, V> {
+    fn pair(order: u64, key: &K, value: &V) -> Owned<HashMapNode<K, V>> {
         Owned::new(HashMapNode {
-            map,
-            hash,
+            order,
+            key: Some(key.clone()),
+            value: Value::new(value),
             next: Atomic::null(),
         })
     }
 
-    pub fn new_head(
-        old_head_ptr: Atomic<HashMapNode<K, V>>,
-        key: &K,
-        value: &V,
-        hash: u64,
-    ) -> Owned<HashMapNode<K, V>> {
-        let map = Map::new();
-        map.add(key, value);
+    fn sentinel(bucket: usize) -> Owned<HashMapNode<K, V>> {
         Owned::new(HashMapNode {
-            map,
-            hash: hash,
-            next: old_head_ptr,
+            order: sentinel_order(bucket),
+            key: None,
+            value: Value::none(),
+            next: Atomic::null(),
         })
     }
 
-    pub fn new_insert(
-        old: &HashMapNode<K, V>,
-        key: &K,
-        value: &V,
-        hash: u64,
-    ) -> Owned<HashMapNode<K, V>> {
-        let map = Map::new();
-        map.add(key, value);
-        Owned::new(HashMapNode {
-            map: old.map.copy(),
-            hash: old.hash.clone(),
-            next: Atomic::new(HashMapNode {
-                map,
-                hash,
-                next: old.next.clone(),
-            }),
-        })
+    /// Whether the node sorts before `key`, which sorts at `order`.
+    fn precedes(&self, order: u64, key: Option<&K>) -> bool {
+        self.order < order || (self.order == order && self.key.as_ref() < key)
     }
+
+    fn holds(&self, order: u64, key: &K) -> bool {
+        self.order == order && self.key.as_ref() == Some(key)
+    }
+}
+
+/// A pointer to the next node of a `HashMap`.
+type Link<K, V> = Atomic<HashMapNode<K, V>>;
+
+/// Where a pair with `hash` sorts. Setting the top bit makes it odd once
+/// reversed, so it sorts after the sentinel of its bucket.
+fn pair_order(hash: u64) -> u64 {
+    (hash | 1 << 63).reverse_bits()
+}
+
+fn sentinel_order(bucket: usize) -> u64 {
+    (bucket as u64).reverse_bits()
+}
+
+/// The segment `bucket` is in, and its index in that segment.
+fn locate(bucket: usize) -> (usize, usize) {
+    let segment = (usize::BITS - bucket.leading_zeros()) as usize;
+    (segment, bucket - (1 << segment >> 1))
+}
+
+fn segment_len(segment: usize) -> usize {
+    (1 << segment >> 1).max(1)
 }
 
 fn default_hasher<K: Clone + PartialOrd + Hash>(key: &K) -> u64 {
@@ -63,126 +88,298 @@ fn default_hasher<K: Clone + PartialOrd + Hash>(key: &K) -> u64 {
     hasher.finish()
 }
 
-pub struct HashMap<K: Clone + PartialOrd + Hash, V: Clone> {
-    head: Atomic<HashMapNode<K, V>>,
+/// A lock-free hash map, kept as a split-ordered list.
+///
+/// Every pair is in one linked list, sorted by bit-reversed hash, and each
+/// bucket points at a sentinel node in that list. Doubling the number of
+/// buckets moves nothing: a new bucket splits its parent by linking a
+/// sentinel into the parent's part of the list the first time it is used.
+///
+/// Buckets are never removed. The bucket array does not shrink, and the
+/// sentinel of a bucket stays in the list once the bucket is empty.
+///
+/// Keys and values must be `Send + 'static`, as for a `Map`.
+pub struct HashMap<K: Clone + PartialOrd + Hash + Send + 'static, V: Clone + Send + 'static> {
+    /// The bucket array, allocated a segment at a time as it grows.
+    segments: Vec<Atomic<Vec<Link<K, V>>>>,
+    /// Number of buckets, a power of two.
+    buckets: AtomicUsize,
+    len: AtomicUsize,
     hasher: fn(&K) -> u64,
 }
 
-impl<K: Clone + PartialOrd + Hash, V: Clone> HashMap<K, V> {
+impl<K: Clone + PartialOrd + Hash + Send + 'static, V: Clone + Send + 'static> Default
+    for HashMap<K, V>
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Clone + PartialOrd + Hash + Send + 'static, V: Clone + Send + 'static> HashMap<K, V> {
     pub fn new() -> Self {
-        HashMap {
-            head: Atomic::null(),
-            hasher: default_hasher,
-        }
+        Self::new_with_hasher(default_hasher)
     }
 
     pub fn new_with_hasher(hasher: fn(&K) -> u64) -> Self {
+        let mut segments: Vec<_> = (0..SEGMENTS).map(|_| Atomic::null()).collect();
+        segments[0] = Atomic::new(vec![Atomic::from(HashMapNode::sentinel(0))]);
         HashMap {
-            head: Atomic::null(),
+            segments,
+            buckets: AtomicUsize::new(1),
+            len: AtomicUsize::new(0),
             hasher,
         }
     }
 
+    /// Number of pairs in the map.
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of pairs the map holds before its buckets double.
+    pub fn capacity(&self) -> usize {
+        self.buckets.load(Ordering::Relaxed) * LOAD_FACTOR
+    }
+
+    /// Set `key` to `value`, returning the value it replaced.
     pub fn add(&self, key: &K, value: &V) -> Option<V> {
         let guard = &epoch::pin();
         let hash = (self.hasher)(key);
+        let order = pair_order(hash);
+        let start = self.bucket_of(hash, guard);
+        let mut new = HashMapNode::pair(order, key, value);
         loop {
-            let mut prev_ptr = &self.head;
-            let mut prev = prev_ptr.load(Ordering::Acquire, guard);
-            loop {
-                if prev.is_null() {
-                    let new_prev = HashMapNode::new(key, value, hash);
-                    match prev_ptr.compare_exchange_weak(
-                        prev,
-                        new_prev,
-                        Ordering::Acquire,
-                        Ordering::Relaxed,
-                        guard,
-                    ) {
-                        Ok(_) => return None,
-                        Err(_) => continue,
+            let (prev, cur) = find(start, order, Some(key), guard);
+            if let Some(node) = unsafe { cur.as_ref() } {
+                if node.holds(order, key) {
+                    match node.value.replace(value, guard) {
+                        Some(old) => return Some(old),
+                        // Removed since `find`. Help the removal along so
+                        // that `find` unlinks it, and link a new node.
+                        None => {
+                            mark(&node.next, guard);
+                            continue;
+                        }
                     }
                 }
-                let prev_inner = unsafe { prev.deref() };
-                if prev_inner.hash > hash {
-                    let new_prev = HashMapNode::new_insert(prev_inner, key, value, hash);
-                    match prev_ptr.compare_exchange_weak(
-                        prev,
-                        new_prev,
-                        Ordering::Acquire,
-                        Ordering::Relaxed,
-                        guard,
-                    ) {
-                        Ok(_) => return None,
-                        Err(_) => continue,
-                    }
-                } else if prev_inner.hash == hash {
-                    return prev_inner.map.add(key, value);
-                }
-                prev_ptr = &prev_inner.next;
-                prev = prev_ptr.load(Ordering::Acquire, guard);
+            }
+            new.next.store(cur, Ordering::Relaxed);
+            match prev.compare_exchange(cur, new, Ordering::Release, Ordering::Relaxed, guard) {
+                Ok(_) => break,
+                Err(e) => new = e.new,
             }
         }
+        let len = self.len.fetch_add(1, Ordering::Relaxed) + 1;
+        let buckets = self.buckets.load(Ordering::Relaxed);
+        if len > buckets * LOAD_FACTOR && buckets < MAX_BUCKETS {
+            // If this fails, another thread has just doubled it.
+            let _ = self.buckets.compare_exchange(
+                buckets,
+                buckets * 2,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            );
+        }
+        None
     }
 
     pub fn get(&self, key: &K) -> Option<(K, V)> {
         let guard = &epoch::pin();
         let hash = (self.hasher)(key);
-        let mut prev_ptr = &self.head;
-        let mut prev = prev_ptr.load(Ordering::Acquire, guard);
-        loop {
-            if prev.is_null() {
+        let order = pair_order(hash);
+        let mut cur = self
+            .bucket_of(hash, guard)
+            .next
+            .load(Ordering::Acquire, guard);
+        while let Some(node) = unsafe { cur.as_ref() } {
+            if !node.precedes(order, Some(key)) && !node.holds(order, key) {
                 return None;
             }
-            let prev_inner = unsafe { prev.deref() };
-            if prev_inner.hash == hash {
-                return prev_inner.map.get(key);
-            } else if prev_inner.hash > hash {
-                return None;
+            if node.holds(order, key) {
+                if let Some(value) = node.value.get(guard) {
+                    return Some((key.clone(), value));
+                }
             }
-            prev_ptr = &prev_inner.next;
-            prev = prev_ptr.load(Ordering::Acquire, guard);
+            cur = node.next.load(Ordering::Acquire, guard).with_tag(0);
         }
+        None
     }
 
+    /// Remove `key`, returning its value.
     pub fn remove(&self, key: &K) -> Option<V> {
         let guard = &epoch::pin();
         let hash = (self.hasher)(key);
-        let mut prev_ptr = &self.head;
-        let mut prev = prev_ptr.load(Ordering::Acquire, guard);
+        let order = pair_order(hash);
+        let start = self.bucket_of(hash, guard);
         loop {
-            if prev.is_null() {
-                return None;
+            let (prev, cur) = find(start, order, Some(key), guard);
+            let node = match unsafe { cur.as_ref() } {
+                Some(node) if node.holds(order, key) => node,
+                _ => return None,
+            };
+            let Some(value) = node.value.take(guard) else {
+                // Removed by another thread since `find`; look again.
+                mark(&node.next, guard);
+                continue;
+            };
+            self.len.fetch_sub(1, Ordering::Relaxed);
+            mark(&node.next, guard);
+            let next = node.next.load(Ordering::Acquire, guard).with_tag(0);
+            match prev.compare_exchange(cur, next, Ordering::Release, Ordering::Relaxed, guard) {
+                Ok(_) => unsafe { guard.defer_destroy(cur) },
+                // Leave the unlinking to `find`.
+                Err(_) => {
+                    find(start, order, Some(key), guard);
+                }
+            }
+            return Some(value);
+        }
+    }
+
+    /// Call `f` on every pair, in no particular order.
+    pub fn for_each<F: FnMut(&K, &V)>(&self, mut f: F) {
+        let guard = &epoch::pin();
+        let mut cur = self.bucket(0, guard).next.load(Ordering::Acquire, guard);
+        while let Some(node) = unsafe { cur.as_ref() } {
+            if let (Some(key), Some(value)) = (&node.key, node.value.get(guard)) {
+                f(key, &value);
+            }
+            cur = node.next.load(Ordering::Acquire, guard).with_tag(0);
+        }
+    }
+
+    /// The sentinel of the bucket a pair with `hash` belongs in.
+    fn bucket_of<'g>(&'g self, hash: u64, guard: &'g Guard) -> &'g HashMapNode<K, V> {
+        let buckets = self.buckets.load(Ordering::Relaxed);
+        self.bucket(hash as usize & (buckets - 1), guard)
+    }
+
+    /// The sentinel of `bucket`, which is linked in on first use.
+    fn bucket<'g>(&'g self, bucket: usize, guard: &'g Guard) -> &'g HashMapNode<K, V> {
+        let slot = self.slot(bucket, guard);
+        if let Some(sentinel) = unsafe { slot.load(Ordering::Acquire, guard).as_ref() } {
+            return sentinel;
+        }
+        // A bucket splits off from its parent, the bucket without its top
+        // bit. Bucket 0 has a sentinel from the start.
+        let parent = bucket & !(1 << (usize::BITS - 1 - bucket.leading_zeros()));
+        let start = self.bucket(parent, guard);
+        let order = sentinel_order(bucket);
+        let mut new = HashMapNode::sentinel(bucket);
+        let sentinel = loop {
+            let (prev, cur) = find(start, order, None, guard);
+            match unsafe { cur.as_ref() } {
+                // Linked in by another thread.
+                Some(node) if node.order == order => break cur,
+                _ => {}
             }
-            let prev_inner = unsafe { prev.deref() };
-            if prev_inner.hash == hash {
-                let ret = prev_inner.map.remove(key);
-                if prev_inner.map.is_null(){
-                    match prev_ptr.compare_exchange_weak(
-                        prev,
-                        prev_inner.next.load(Ordering::Relaxed, guard),
-                        Ordering::Acquire,
-                        Ordering::Relaxed,
-                        guard,
-                    ) {
-                        Ok(_) => return ret,
-                        Err(_) => continue,
+            new.next.store(cur, Ordering::Relaxed);
+            match prev.compare_exchange(cur, new, Ordering::Release, Ordering::Relaxed, guard) {
+                Ok(sentinel) => break sentinel,
+                Err(e) => new = e.new,
+            }
+        };
+        // Every thread that gets here stores the same sentinel.
+        slot.store(sentinel, Ordering::Release);
+        unsafe { sentinel.deref() }
+    }
+
+    /// The entry of the bucket array for `bucket`, allocating its segment if
+    /// need be.
+    fn slot<'g>(&'g self, bucket: usize, guard: &'g Guard) -> &'g Link<K, V> {
+        let (segment, index) = locate(bucket);
+        let slots = &self.segments[segment];
+        let mut segment_ptr = slots.load(Ordering::Acquire, guard);
+        if segment_ptr.is_null() {
+            let new = (0..segment_len(segment)).map(|_| Atomic::null()).collect();
+            segment_ptr = match slots.compare_exchange(
+                Shared::null(),
+                Owned::new(new),
+                Ordering::AcqRel,
+                Ordering::Acquire,
+                guard,
+            ) {
+                Ok(segment_ptr) => segment_ptr,
+                Err(e) => e.current,
+            };
+        }
+        // Segments are only freed with the map.
+        let segment = unsafe { segment_ptr.deref() };
+        &segment[index]
+    }
+}
+
+impl<K: Clone + PartialOrd + Hash + Send + 'static, V: Clone + Send + 'static> Drop
+    for HashMap<K, V>
+{
+    fn drop(&mut self) {
+        // Nothing else can reach the map anymore. Every node still linked,
+        // sentinels included, hangs off the sentinel of bucket 0.
+        unsafe {
+            let guard = epoch::unprotected();
+            let head = self.segments[0].load(Ordering::Relaxed, guard).deref();
+            let mut cur = head[0].load(Ordering::Relaxed, guard);
+            while let Some(node) = cur.as_ref() {
+                let next = node.next.load(Ordering::Relaxed, guard).with_tag(0);
+                drop(cur.into_owned());
+                cur = next;
+            }
+            for segment in &self.segments {
+                let segment = segment.load(Ordering::Relaxed, guard);
+                if !segment.is_null() {
+                    drop(segment.into_owned());
+                }
+            }
+        }
+    }
+}
+
+/// The first node from `start` on that does not sort before `key`, or null,
+/// and the pointer to it. Removed nodes on the way are unlinked.
+fn find<'g, K: Clone + PartialOrd + Hash + Send + 'static, V: Clone + Send + 'static>(
+    start: &'g HashMapNode<K, V>,
+    order: u64,
+    key: Option<&K>,
+    guard: &'g Guard,
+) -> (&'g Link<K, V>, Shared<'g, HashMapNode<K, V>>) {
+    'retry: loop {
+        let mut prev = &start.next;
+        let mut cur = prev.load(Ordering::Acquire, guard);
+        while let Some(node) = unsafe { cur.as_ref() } {
+            let next = node.next.load(Ordering::Acquire, guard);
+            if next.tag() == 1 {
+                let next = next.with_tag(0);
+                match prev.compare_exchange(cur, next, Ordering::Release, Ordering::Relaxed, guard)
+                {
+                    Ok(_) => {
+                        unsafe { guard.defer_destroy(cur) };
+                        cur = next;
+                        continue;
                     }
-                } else {
-                    return ret;
+                    // `prev` was removed or changed too.
+                    Err(_) => continue 'retry,
                 }
-            } else if prev_inner.hash > hash {
-                return None;
             }
-            prev_ptr = &prev_inner.next;
-            prev = prev_ptr.load(Ordering::Acquire, guard);
+            if !node.precedes(order, key) {
+                break;
+            }
+            prev = &node.next;
+            cur = next;
         }
+        return (prev, cur);
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::lock_free::map::tests::{check_add_remove_race, stress, Counted};
+    use std::sync::Arc;
 
     fn my_hasher(key: &i32) -> u64 {
         (key % 10) as u64
@@ -193,8 +390,90 @@ mod tests {
 
     #[test]
     fn test_send_sync() {
-        is_send::<Map<i32, i32>>();
-        is_sync::<Map<i32, i32>>();
+        is_send::<HashMap<i32, i32>>();
+        is_sync::<HashMap<i32, i32>>();
+    }
+
+    #[test]
+    fn test_locate() {
+        assert_eq!(locate(0), (0, 0));
+        assert_eq!(locate(1), (1, 0));
+        assert_eq!(locate(2), (2, 0));
+        assert_eq!(locate(3), (2, 1));
+        assert_eq!(locate(6), (3, 2));
+        assert_eq!(segment_len(3), 4);
+    }
+
+    #[test]
+    fn test_hashmap_add_remove_race() {
+        check_add_remove_race(
+            HashMap::new(),
+            |map, value| map.add(&1, &value),
+            |map| map.remove(&1),
+            |map| map.get(&1).map(|(_, value)| value),
+        );
+    }
+
+    #[test]
+    fn test_hashmap_stress() {
+        // Few distinct hashes, so that pairs share buckets.
+        stress(
+            HashMap::new_with_hasher(|key: &Counted| key.id() % 5),
+            |map, key, value| {
+                map.add(key, value);
+            },
+            |map, key| map.get(key).map(|(_, value)| value.id()),
+            |map, key| {
+                map.remove(key);
+            },
+        );
+        stress(
+            HashMap::new(),
+            |map, key, value| {
+                map.add(key, value);
+            },
+            |map, key| map.get(key).map(|(_, value)| value.id()),
+            |map, key| {
+                map.remove(key);
+            },
+        );
+    }
+
+    #[test]
+    fn test_hashmap_drop_frees_nodes() {
+        let live = Arc::new(AtomicUsize::new(0));
+        let map = HashMap::new();
+        for id in 0..100 {
+            map.add(&Counted::new(id, &live), &Counted::new(id, &live));
+        }
+        assert_eq!(live.load(Ordering::SeqCst), 200);
+        drop(map);
+        assert_eq!(live.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn test_hashmap_grows() {
+        let map = HashMap::new();
+        assert!(map.is_empty());
+        assert_eq!(map.capacity(), LOAD_FACTOR);
+        for key in 0..1000 {
+            map.add(&key, &key);
+        }
+        assert_eq!(map.len(), 1000);
+        assert!(map.capacity() >= 1000);
+        for key in 0..1000 {
+            assert_eq!(map.get(&key), Some((key, key)));
+        }
+        for key in (0..1000).step_by(2) {
+            assert_eq!(map.remove(&key), Some(key));
+        }
+        assert_eq!(map.len(), 500);
+        let mut count = 0;
+        map.for_each(|key, _| {
+            assert_eq!(key % 2, 1);
+            count += 1;
+        });
+        assert_eq!(count, 500);
     }
 
     #[test]
@@ -222,6 +501,47 @@ mod tests {
         assert_eq!(map.get(&2), Some((2, 6)));
     }
 
+    #[test]
+    fn test_hashmap_buckets_out_of_order() {
+        let map = HashMap::new_with_hasher(my_hasher);
+        for key in [9, 3, 13, 1] {
+            assert_eq!(map.add(&key, &key), None);
+        }
+        for key in [1, 3, 9, 13] {
+            assert_eq!(map.get(&key), Some((key, key)));
+        }
+        assert_eq!(map.remove(&3), Some(3));
+        assert_eq!(map.get(&13), Some((13, 13)));
+        let mut keys = Vec::new();
+        map.for_each(|key, _| keys.push(*key));
+        keys.sort();
+        assert_eq!(keys, [1, 9, 13]);
+    }
+
+    #[test]
+    fn test_hashmap_concurrent_writers() {
+        let rounds = if cfg!(miri) { 50 } else { 1000 };
+        let map = HashMap::new();
+        std::thread::scope(|scope| {
+            for thread in 0..4 {
+                let map = &map;
+                scope.spawn(move || {
+                    for i in 0..rounds {
+                        map.add(&(i * 4 + thread), &i);
+                    }
+                    for i in (0..rounds).step_by(2) {
+                        assert_eq!(map.remove(&(i * 4 + thread)), Some(i));
+                    }
+                });
+            }
+        });
+        for key in 0..rounds * 4 {
+            let expected = (key / 4 % 2 == 1).then_some((key, key / 4));
+            assert_eq!(map.get(&key), expected);
+        }
+        assert_eq!(map.len(), rounds as usize * 2);
+    }
+
     #[test]
     fn test_hashmap_remove() {
         let map = HashMap::new_with_hasher(my_hasher);
@@ -1,188 +1,247 @@
 use std::sync::atomic::Ordering;
 
-use crossbeam::epoch::{self, Atomic, Owned};
+use crossbeam::epoch::{self, Atomic, Guard, Owned, Shared};
 
-pub struct MapNode<K: Clone + PartialOrd, V: Clone> {
-    key: K,
-    value: V,
-    next: Atomic<MapNode<K, V>>,
+/// A value in a `Map`. The alignment leaves the low bit of a pointer to it
+/// free to mark it taken by `remove`.
+#[repr(align(2))]
+struct Slot<V>(V);
+
+/// The value of a node, which `remove` marks taken so that no `add` can
+/// replace it afterwards. Shared with `HashMap`.
+pub(super) struct Value<V: Clone + Send + 'static> {
+    slot: Atomic<Slot<V>>,
 }
 
-impl<K: Clone + PartialOrd, V: Clone> MapNode<K, V> {
-    pub fn new(key: &K, value: &V) -> Owned<MapNode<K, V>> {
-        Owned::new(MapNode {
-            key: key.clone(),
-            value: value.clone(),
-            next: Atomic::null(),
-        })
+impl<V: Clone + Send + 'static> Value<V> {
+    pub(super) fn new(value: &V) -> Value<V> {
+        Value {
+            slot: Atomic::new(Slot(value.clone())),
+        }
     }
 
-    pub fn new_head(
-        old_head_ptr: Atomic<MapNode<K, V>>,
-        key: &K,
-        value: &V,
-    ) -> Owned<MapNode<K, V>> {
-        Owned::new(MapNode {
-            key: key.clone(),
-            value: value.clone(),
-            next: old_head_ptr,
-        })
+    /// No value, for nodes that never hold one.
+    pub(super) fn none() -> Value<V> {
+        Value {
+            slot: Atomic::null(),
+        }
     }
 
-    pub fn new_insert(old: &MapNode<K, V>, key: &K, value: &V) -> Owned<MapNode<K, V>> {
-        Owned::new(MapNode {
-            key: old.key.clone(),
-            value: old.value.clone(),
-            next: Atomic::new(MapNode {
-                key: key.clone(),
-                value: value.clone(),
-                next: old.next.clone(),
-            }),
-        })
+    /// The value, unless it has been taken.
+    pub(super) fn get(&self, guard: &Guard) -> Option<V> {
+        let slot = self.slot.load(Ordering::Acquire, guard);
+        // Slots are only destroyed through the epoch, or with the node.
+        (slot.tag() == 0 && !slot.is_null()).then(|| unsafe { slot.deref() }.0.clone())
+    }
+
+    /// Replace the value, returning the old one, unless it has been taken.
+    pub(super) fn replace(&self, value: &V, guard: &Guard) -> Option<V> {
+        let mut new = Owned::new(Slot(value.clone()));
+        loop {
+            let old = self.slot.load(Ordering::Acquire, guard);
+            if old.tag() == 1 || old.is_null() {
+                return None;
+            }
+            match self
+                .slot
+                .compare_exchange(old, new, Ordering::AcqRel, Ordering::Acquire, guard)
+            {
+                Ok(_) => {
+                    let old_value = unsafe { old.deref() }.0.clone();
+                    unsafe { guard.defer_destroy(old) };
+                    return Some(old_value);
+                }
+                Err(e) => new = e.new,
+            }
+        }
+    }
+
+    /// Mark the value taken, returning it, unless another thread got there
+    /// first.
+    pub(super) fn take(&self, guard: &Guard) -> Option<V> {
+        loop {
+            let slot = self.slot.load(Ordering::Acquire, guard);
+            if slot.tag() == 1 || slot.is_null() {
+                return None;
+            }
+            if self
+                .slot
+                .compare_exchange(
+                    slot,
+                    slot.with_tag(1),
+                    Ordering::AcqRel,
+                    Ordering::Acquire,
+                    guard,
+                )
+                .is_ok()
+            {
+                return Some(unsafe { slot.deref() }.0.clone());
+            }
+        }
+    }
+}
+
+impl<V: Clone + Send + 'static> Drop for Value<V> {
+    fn drop(&mut self) {
+        // Dropped with its node, once nothing can reach it. A taken value is
+        // still owned here.
+        unsafe {
+            let slot = self.slot.load(Ordering::Relaxed, epoch::unprotected());
+            if !slot.is_null() {
+                drop(slot.with_tag(0).into_owned());
+            }
+        }
     }
+}
 
-    pub fn change_value(old: &MapNode<K, V>, value: &V) -> Owned<MapNode<K, V>> {
+/// Tag `next` so that nothing can be linked after its node anymore and
+/// `find` unlinks it. Shared with `HashMap`.
+pub(super) fn mark<T>(next: &Atomic<T>, guard: &Guard) {
+    loop {
+        let cur = next.load(Ordering::Acquire, guard);
+        if cur.tag() == 1
+            || next
+                .compare_exchange(
+                    cur,
+                    cur.with_tag(1),
+                    Ordering::AcqRel,
+                    Ordering::Acquire,
+                    guard,
+                )
+                .is_ok()
+        {
+            return;
+        }
+    }
+}
+
+/// A node of a `Map`.
+///
+/// Removing a node takes three steps. Its value is taken first, which is
+/// when the key leaves the map: a concurrent `add` can no longer replace the
+/// value and links a new node instead. Then its `next` pointer is tagged, so
+/// that nothing can be linked after it anymore, and then it is unlinked.
+/// Whoever unlinks a node destroys it once no thread can still be reading it.
+pub struct MapNode<K: Clone + PartialOrd + Send + 'static, V: Clone + Send + 'static> {
+    key: K,
+    value: Value<V>,
+    next: Atomic<MapNode<K, V>>,
+}
+
+impl<K: Clone + PartialOrd + Send + 'static, V: Clone + Send + 'static> MapNode<K, V> {
+    pub fn new(key: &K, value: &V) -> Owned<MapNode<K, V>> {
         Owned::new(MapNode {
-            key: old.key.clone(),
-            value: value.clone(),
-            next: old.next.clone(),
+            key: key.clone(),
+            value: Value::new(value),
+            next: Atomic::null(),
         })
     }
 }
 
-pub struct Map<K: Clone + PartialOrd, V: Clone> {
+/// A pointer to the next node of a `Map`.
+type Link<K, V> = Atomic<MapNode<K, V>>;
+
+/// A lock-free map kept as a linked list sorted by key.
+///
+/// Keys and values must be `Send + 'static`: removed nodes are destroyed by
+/// whichever thread next advances the epoch, possibly after the map is gone.
+pub struct Map<K: Clone + PartialOrd + Send + 'static, V: Clone + Send + 'static> {
     head: Atomic<MapNode<K, V>>,
 }
 
-impl<K: Clone + PartialOrd, V: Clone> Map<K, V> {
+impl<K: Clone + PartialOrd + Send + 'static, V: Clone + Send + 'static> Default for Map<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Clone + PartialOrd + Send + 'static, V: Clone + Send + 'static> Map<K, V> {
     pub fn new() -> Self {
         Map {
             head: Atomic::null(),
         }
     }
 
+    /// Set `key` to `value`, returning the value it replaced.
     pub fn add(&self, key: &K, value: &V) -> Option<V> {
         let guard = &epoch::pin();
+        let mut new = MapNode::new(key, value);
         loop {
-            let mut prev_ptr = &self.head;
-            let mut prev = prev_ptr.load(Ordering::Acquire, guard);
-            loop {
-                if prev.is_null() {
-                    let new_prev = MapNode::new(key, value);
-                    match prev_ptr.compare_exchange_weak(
-                        prev,
-                        new_prev,
-                        Ordering::Acquire,
-                        Ordering::Relaxed,
-                        guard,
-                    ) {
-                        Ok(_) => return None,
-                        Err(_) => continue,
-                    }
-                }
-                let prev_inner = unsafe { prev.deref() };
-                if &prev_inner.key >= key {
-                    let prev_inner = unsafe { prev.deref() };
-                    let mut ret = None;
-                    let new_prev = if &prev_inner.key == key {
-                        ret = Some(prev_inner.value.clone());
-                        MapNode::change_value(prev_inner, value)
-                    } else {
-                        MapNode::new_insert(prev_inner, key, value)
-                    };
-                    match prev_ptr.compare_exchange_weak(
-                        prev,
-                        new_prev,
-                        Ordering::Acquire,
-                        Ordering::Relaxed,
-                        guard,
-                    ) {
-                        Ok(_) => return ret,
-                        Err(_) => continue,
+            let (prev, cur) = self.find(key, guard);
+            if let Some(node) = unsafe { cur.as_ref() } {
+                if node.key == *key {
+                    match node.value.replace(value, guard) {
+                        Some(old) => return Some(old),
+                        // Removed since `find`. Help the removal along so
+                        // that `find` unlinks it, and link a new node.
+                        None => {
+                            mark(&node.next, guard);
+                            continue;
+                        }
                     }
                 }
-                prev_ptr = &prev_inner.next;
-                prev = prev_ptr.load(Ordering::Acquire, guard);
+            }
+            new.next.store(cur, Ordering::Relaxed);
+            match prev.compare_exchange(cur, new, Ordering::Release, Ordering::Relaxed, guard) {
+                Ok(_) => return None,
+                Err(e) => new = e.new,
             }
         }
     }
+
+    /// Remove `key`, returning its value.
     pub fn remove(&self, key: &K) -> Option<V> {
         let guard = &epoch::pin();
         loop {
-            let mut prev_ptr = &self.head;
-            let mut prev = prev_ptr.load(Ordering::Acquire, guard);
-            if prev.is_null() {
-                return None;
-            }
-            let mut prev_inner = unsafe { prev.deref() };
-            if &prev_inner.key == key {
-                match prev_ptr.compare_exchange_weak(
-                    prev,
-                    prev_inner.next.load(Ordering::Acquire, guard),
-                    Ordering::Acquire,
-                    Ordering::Relaxed,
-                    guard,
-                ) {
-                    Ok(_) => {
-                        let value = prev_inner.value.clone();
-                        let _ = unsafe { prev.into_owned() };
-                        return Some(value);
-                    }
-                    Err(_) => continue,
+            let (prev, cur) = self.find(key, guard);
+            let node = match unsafe { cur.as_ref() } {
+                Some(node) if node.key == *key => node,
+                _ => return None,
+            };
+            let Some(value) = node.value.take(guard) else {
+                // Removed by another thread since `find`; look again.
+                mark(&node.next, guard);
+                continue;
+            };
+            mark(&node.next, guard);
+            let next = node.next.load(Ordering::Acquire, guard).with_tag(0);
+            match prev.compare_exchange(cur, next, Ordering::Release, Ordering::Relaxed, guard) {
+                Ok(_) => unsafe { guard.defer_destroy(cur) },
+                // Leave the unlinking to `find`.
+                Err(_) => {
+                    self.find(key, guard);
                 }
             }
-            let mut cur_ptr = &prev_inner.next;
-            let mut cur = cur_ptr.load(Ordering::Acquire, guard);
-            loop {
-                if cur.is_null() {
-                    return None;
-                }
-                let cur_inner = unsafe { cur.deref() };
-                if &cur_inner.key == key {
-                    let new_prev = Owned::new(MapNode {
-                        key: prev_inner.key.clone(),
-                        value: prev_inner.value.clone(),
-                        next: cur_inner.next.clone(),
-                    });
-                    match prev_ptr.compare_exchange_weak(
-                        prev,
-                        new_prev,
-                        Ordering::Acquire,
-                        Ordering::Relaxed,
-                        guard,
-                    ) {
-                        Ok(_) => {
-                            let value = cur_inner.value.clone();
-                            let _ = unsafe { cur.into_owned() };
-                            return Some(value);
-                        }
-                        Err(_) => continue,
-                    }
-                }
-                prev_inner = cur_inner;
-                prev = cur;
-                prev_ptr = cur_ptr;
-                cur_ptr = &prev_inner.next;
-                cur = cur_ptr.load(Ordering::Acquire, guard);
-            }
+            return Some(value);
         }
     }
+
     pub fn get(&self, key: &K) -> Option<(K, V)> {
         let guard = &epoch::pin();
-        let mut prev_ptr = &self.head;
-        let mut prev = prev_ptr.load(Ordering::Acquire, guard);
-        loop {
-            if prev.is_null() {
+        let mut cur = self.head.load(Ordering::Acquire, guard);
+        while let Some(node) = unsafe { cur.as_ref() } {
+            if node.key > *key {
                 return None;
             }
-            let prev_inner = unsafe { prev.deref() };
-            if &prev_inner.key == key {
-                return Some((prev_inner.key.clone(), prev_inner.value.clone()));
-            } else if &prev_inner.key > key {
-                return None;
+            if node.key == *key {
+                if let Some(value) = node.value.get(guard) {
+                    return Some((node.key.clone(), value));
+                }
+            }
+            cur = node.next.load(Ordering::Acquire, guard).with_tag(0);
+        }
+        None
+    }
+
+    /// Call `f` on every pair, in ascending key order.
+    pub fn for_each<F: FnMut(&K, &V)>(&self, mut f: F) {
+        let guard = &epoch::pin();
+        let mut cur = self.head.load(Ordering::Acquire, guard);
+        while let Some(node) = unsafe { cur.as_ref() } {
+            if let Some(value) = node.value.get(guard) {
+                f(&node.key, &value);
             }
-            prev_ptr = &prev_inner.next;
-            prev = prev_ptr.load(Ordering::Acquire, guard);
+            cur = node.next.load(Ordering::Acquire, guard).with_tag(0);
         }
     }
 
@@ -191,9 +250,60 @@ impl<K: Clone + PartialOrd, V: Clone> Map<K, V> {
         self.head.load(Ordering::Acquire, guard).is_null()
     }
 
-    pub fn copy(&self) -> Self {
-        Map {
-            head: self.head.clone(),
+    /// The first node whose key is not below `key`, or null, and the pointer
+    /// to it. Removed nodes on the way are unlinked.
+    fn find<'g>(
+        &'g self,
+        key: &K,
+        guard: &'g Guard,
+    ) -> (&'g Link<K, V>, Shared<'g, MapNode<K, V>>) {
+        'retry: loop {
+            let mut prev = &self.head;
+            let mut cur = prev.load(Ordering::Acquire, guard);
+            while let Some(node) = unsafe { cur.as_ref() } {
+                let next = node.next.load(Ordering::Acquire, guard);
+                if next.tag() == 1 {
+                    let next = next.with_tag(0);
+                    match prev.compare_exchange(
+                        cur,
+                        next,
+                        Ordering::Release,
+                        Ordering::Relaxed,
+                        guard,
+                    ) {
+                        Ok(_) => {
+                            unsafe { guard.defer_destroy(cur) };
+                            cur = next;
+                            continue;
+                        }
+                        // `prev` was removed or changed too.
+                        Err(_) => continue 'retry,
+                    }
+                }
+                if node.key >= *key {
+                    break;
+                }
+                prev = &node.next;
+                cur = next;
+            }
+            return (prev, cur);
+        }
+    }
+}
+
+impl<K: Clone + PartialOrd + Send + 'static, V: Clone + Send + 'static> Drop for Map<K, V> {
+    fn drop(&mut self) {
+        // Nothing else can reach the list anymore. Nodes already unlinked
+        // belong to the epoch; the ones still linked, removed or not, are
+        // freed here.
+        unsafe {
+            let guard = epoch::unprotected();
+            let mut cur = self.head.load(Ordering::Relaxed, guard);
+            while let Some(node) = cur.as_ref() {
+                let next = node.next.load(Ordering::Relaxed, guard).with_tag(0);
+                drop(cur.into_owned());
+                cur = next;
+            }
         }
     }
 }
@@ -202,6 +312,190 @@ impl<K: Clone + PartialOrd, V: Clone> Map<K, V> {
 pub mod tests {
 
     use super::*;
+    use std::hash::{Hash, Hasher};
+    use std::sync::atomic::{AtomicBool, AtomicUsize};
+    use std::sync::Arc;
+    use std::thread;
+
+    /// A key or value that counts its live copies, so that a leak leaves the
+    /// count above zero and a double free takes it below.
+    pub struct Counted {
+        id: u64,
+        live: Arc<AtomicUsize>,
+    }
+
+    impl Counted {
+        pub fn new(id: u64, live: &Arc<AtomicUsize>) -> Counted {
+            live.fetch_add(1, Ordering::SeqCst);
+            Counted {
+                id,
+                live: live.clone(),
+            }
+        }
+
+        pub fn id(&self) -> u64 {
+            self.id
+        }
+    }
+
+    impl Clone for Counted {
+        fn clone(&self) -> Self {
+            Counted::new(self.id, &self.live)
+        }
+    }
+
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            let live = self.live.fetch_sub(1, Ordering::SeqCst);
+            assert!(live > 0, "Counted dropped twice");
+        }
+    }
+
+    impl PartialEq for Counted {
+        fn eq(&self, other: &Self) -> bool {
+            self.id == other.id
+        }
+    }
+
+    impl PartialOrd for Counted {
+        fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+            self.id.partial_cmp(&other.id)
+        }
+    }
+
+    impl Hash for Counted {
+        fn hash<H: Hasher>(&self, state: &mut H) {
+            self.id.hash(state);
+        }
+    }
+
+    /// Pin and flush until the epoch has freed everything deferred, and
+    /// return how many copies are still live.
+    pub fn reclaim(live: &AtomicUsize) -> usize {
+        for _ in 0..100_000 {
+            if live.load(Ordering::SeqCst) == 0 {
+                break;
+            }
+            epoch::pin().flush();
+            thread::yield_now();
+        }
+        live.load(Ordering::SeqCst)
+    }
+
+    /// Threads that add, overwrite, read and remove the same few keys.
+    /// `map` is dropped at the end, so every copy must be freed.
+    ///
+    /// Run these under Miri to catch a use after free as well:
+    ///
+    /// ```text
+    /// MIRIFLAGS="-Zmiri-tree-borrows -Zmiri-permissive-provenance -Zmiri-ignore-leaks" \
+    ///     cargo +nightly miri test --lib lock_free
+    /// ```
+    ///
+    /// crossbeam-epoch needs Tree Borrows, and leaves its global garbage
+    /// queue behind at exit; the `Counted` check covers our own leaks.
+    pub fn stress<M: Sync>(
+        map: M,
+        add: fn(&M, &Counted, &Counted),
+        get: fn(&M, &Counted) -> Option<u64>,
+        remove: fn(&M, &Counted),
+    ) {
+        // Small enough to finish under Miri, which catches a use after free.
+        let (threads, rounds) = if cfg!(miri) { (4, 60) } else { (8, 2000) };
+        let live = Arc::new(AtomicUsize::new(0));
+        thread::scope(|scope| {
+            for thread in 0..threads {
+                let (map, live) = (&map, &live);
+                scope.spawn(move || {
+                    for i in 0..rounds {
+                        let key = Counted::new((i * 7 + thread) % 32, live);
+                        match i % 3 {
+                            0 => add(map, &key, &Counted::new(key.id() * 10, live)),
+                            1 => {
+                                if let Some(value) = get(map, &key) {
+                                    assert_eq!(value, key.id() * 10);
+                                }
+                            }
+                            _ => remove(map, &key),
+                        }
+                    }
+                });
+            }
+        });
+        drop(map);
+        assert_eq!(reclaim(&live), 0);
+    }
+
+    /// One thread keeps setting key 1 to fresh values while another keeps
+    /// removing it. Every value must come back exactly once: replaced by a
+    /// later `add`, removed, or still in the map at the end.
+    pub fn check_add_remove_race<M: Sync>(
+        map: M,
+        add: fn(&M, u64) -> Option<u64>,
+        remove: fn(&M) -> Option<u64>,
+        get: fn(&M) -> Option<u64>,
+    ) {
+        let rounds: u64 = if cfg!(miri) { 50 } else { 20_000 };
+        let done = AtomicBool::new(false);
+        let (replaced, removed) = thread::scope(|scope| {
+            let adder = scope.spawn(|| {
+                let replaced: Vec<u64> = (0..rounds).filter_map(|i| add(&map, i)).collect();
+                done.store(true, Ordering::SeqCst);
+                replaced
+            });
+            let remover = scope.spawn(|| {
+                let mut removed = Vec::new();
+                while !done.load(Ordering::SeqCst) {
+                    removed.extend(remove(&map));
+                }
+                removed
+            });
+            (adder.join().unwrap(), remover.join().unwrap())
+        });
+        let mut seen: Vec<u64> = replaced
+            .into_iter()
+            .chain(removed)
+            .chain(get(&map))
+            .collect();
+        seen.sort_unstable();
+        assert_eq!(seen, (0..rounds).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_map_add_remove_race() {
+        check_add_remove_race(
+            Map::new(),
+            |map, value| map.add(&1, &value),
+            |map| map.remove(&1),
+            |map| map.get(&1).map(|(_, value)| value),
+        );
+    }
+
+    #[test]
+    fn test_map_stress() {
+        stress(
+            Map::new(),
+            |map, key, value| {
+                map.add(key, value);
+            },
+            |map, key| map.get(key).map(|(_, value)| value.id()),
+            |map, key| {
+                map.remove(key);
+            },
+        );
+    }
+
+    #[test]
+    fn test_map_drop_frees_nodes() {
+        let live = Arc::new(AtomicUsize::new(0));
+        let map = Map::new();
+        for id in 0..100 {
+            map.add(&Counted::new(id, &live), &Counted::new(id, &live));
+        }
+        assert_eq!(live.load(Ordering::SeqCst), 200);
+        drop(map);
+        assert_eq!(live.load(Ordering::SeqCst), 0);
+    }
 
     #[test]
     fn test_map_add() {
@@ -225,14 +519,14 @@ pub mod tests {
     #[test]
     fn test_map_null() {
         let list = Map::new();
-        assert_eq!(list.is_null(), true);
+        assert!(list.is_null());
         assert_eq!(list.get(&0), None);
         assert_eq!(list.remove(&0), None);
         assert_eq!(list.add(&1, &1), None);
-        assert_eq!(list.is_null(), false);
+        assert!(!list.is_null());
         assert_eq!(list.get(&1), Some((1, 1)));
         assert_eq!(list.remove(&1), Some(1));
-        assert_eq!(list.is_null(), true);
+        assert!(list.is_null());
     }
     #[test]
     fn test_map_remove() {
@@ -251,6 +545,21 @@ pub mod tests {
         assert_eq!(list.get(&2), None);
     }
 
+    #[test]
+    fn test_map_keeps_keys_sorted() {
+        let list = Map::new();
+        for key in [3, 1, 2, 0] {
+            assert_eq!(list.add(&key, &(key * 10)), None);
+        }
+        for key in 0..4 {
+            assert_eq!(list.get(&key), Some((key, key * 10)));
+        }
+        assert_eq!(list.remove(&2), Some(20));
+        let mut pairs = Vec::new();
+        list.for_each(|key, value| pairs.push((*key, *value)));
+        assert_eq!(pairs, [(0, 0), (1, 10), (3, 30)]);
+    }
+
     fn is_send<T: Send>() {}
     fn is_sync<T: Sync>() {}
 
@@ -1,51 +1,289 @@
 use std::{
-    io::{Read, Write},
+    io::{BufReader, BufWriter, Write},
     net::TcpStream,
+    ops::RangeBounds,
+    time::Duration,
 };
 
-use crate::{Result, ToResult};
+use serde::de::DeserializeOwned;
 
+use super::protocol::{read_response, Request, DEFAULT_MAX_FRAME_SIZE};
+use crate::kvs::bytes::{pair_to_strings, range_to_bytes, Blob};
+use crate::{KvsError, Result, WriteBatch};
+
+/// A connection to a `KvServer`.
+///
+/// The connection is kept open across requests. Requests can be pipelined
+/// with `send` and `recv`: queue several requests, then read their responses
+/// in the same order.
 pub struct KvClient {
-    stream: TcpStream,
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+    max_frame_size: u32,
+    pending: usize,
 }
 
 impl KvClient {
-    pub fn new(addr: String) -> KvClient {
-        KvClient {
-            stream: TcpStream::connect(addr).unwrap(),
-        }
+    pub fn new(addr: String) -> Result<KvClient> {
+        let stream = TcpStream::connect(addr)?;
+        Ok(KvClient {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: BufWriter::new(stream),
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            pending: 0,
+        })
     }
 
+    /// Set the largest frame the client will send or accept.
+    pub fn set_max_frame_size(&mut self, max_frame_size: u32) {
+        self.max_frame_size = max_frame_size;
+    }
+
+    pub fn get_bytes(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let value: Option<Blob> = self.call(Request::Get { key })?;
+        Ok(value.map(|Blob(value)| value))
+    }
+
+    pub fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.call(Request::Set { key, value })
+    }
+
+    pub fn remove_bytes(&mut self, key: Vec<u8>) -> Result<()> {
+        self.call(Request::Remove { key })
+    }
+
+    /// Up to `limit` key/value pairs whose keys fall in `range`, in ascending
+    /// byte order. The server returns at most `MAX_SCAN_LIMIT` pairs at a
+    /// time.
+    pub fn scan_bytes<R: RangeBounds<Vec<u8>>>(
+        &mut self,
+        range: R,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let pairs = self.call(Request::Scan {
+            start: range.start_bound().cloned(),
+            end: range.end_bound().cloned(),
+            limit,
+        })?;
+        Ok(unblob_pairs(pairs))
+    }
+
+    /// Up to `limit` key/value pairs whose keys start with `prefix`, in
+    /// ascending byte order. The server returns at most `MAX_SCAN_LIMIT`
+    /// pairs at a time.
+    pub fn scan_prefix_bytes(
+        &mut self,
+        prefix: Vec<u8>,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let pairs = self.call(Request::ScanPrefix { prefix, limit })?;
+        Ok(unblob_pairs(pairs))
+    }
+
+    /// Get the value of `key`, failing with `KvsError::Utf8` if it is not
+    /// valid UTF-8.
     pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        self.stream
-            .write_all(format!("GET {}\n", key).as_bytes())
-            .map_err(|e| e.to_string())?;
-        let mut buf = String::new();
-        self.stream
-            .read_to_string(&mut buf)
-            .map_err(|e| e.to_string())?;
-        buf.to_result()
+        Ok(self
+            .get_bytes(key.into_bytes())?
+            .map(String::from_utf8)
+            .transpose()?)
     }
 
     pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        self.stream
-            .write_all(format!("SET {} {}\n", key, value).as_bytes())
-            .map_err(|e| e.to_string())?;
-        let mut buf = String::new();
-        self.stream
-            .read_to_string(&mut buf)
-            .map_err(|e| e.to_string())?;
-        buf.to_result()
+        self.set_bytes(key.into_bytes(), value.into_bytes())
     }
 
     pub fn remove(&mut self, key: String) -> Result<()> {
-        self.stream
-            .write_all(format!("REMOVE {}\n", key).as_bytes())
-            .map_err(|e| e.to_string())?;
-        let mut buf = String::new();
-        self.stream
-            .read_to_string(&mut buf)
-            .map_err(|e| e.to_string())?;
-        buf.to_result()
+        self.remove_bytes(key.into_bytes())
+    }
+
+    /// Up to `limit` key/value pairs whose keys fall in `range`, in ascending
+    /// key order.
+    pub fn scan<R: RangeBounds<String>>(
+        &mut self,
+        range: R,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>> {
+        self.scan_bytes(range_to_bytes(&range), limit)?
+            .into_iter()
+            .map(pair_to_strings)
+            .collect()
+    }
+
+    /// Up to `limit` key/value pairs whose keys start with `prefix`, in
+    /// ascending key order.
+    pub fn scan_prefix(&mut self, prefix: String, limit: usize) -> Result<Vec<(String, String)>> {
+        self.scan_prefix_bytes(prefix.into_bytes(), limit)?
+            .into_iter()
+            .map(pair_to_strings)
+            .collect()
+    }
+
+    /// Apply every write in `batch` on the server, or none of them.
+    pub fn batch(&mut self, batch: WriteBatch) -> Result<()> {
+        self.call(Request::Batch(batch))
+    }
+
+    /// Set `key` to `new`, or remove it if `new` is `None`, but only if its
+    /// value is `expected`. On a mismatch the error holds the current value.
+    pub fn compare_and_swap_bytes(
+        &mut self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<()> {
+        self.call(Request::CompareAndSwap { key, expected, new })
+    }
+
+    /// Get the value of `key` along with its version.
+    pub fn get_with_version_bytes(&mut self, key: Vec<u8>) -> Result<Option<(Vec<u8>, u64)>> {
+        let found: Option<(Blob, u64)> = self.call(Request::GetWithVersion { key })?;
+        Ok(found.map(|(Blob(value), version)| (value, version)))
+    }
+
+    /// Set `key` only if it is still at `version`.
+    pub fn set_if_version_bytes(
+        &mut self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        version: u64,
+    ) -> Result<()> {
+        self.call(Request::SetIfVersion {
+            key,
+            value,
+            version,
+        })
+    }
+
+    /// Set `key` so that it disappears once `ttl` has passed.
+    pub fn set_with_ttl_bytes(
+        &mut self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> Result<()> {
+        self.call(Request::SetWithTtl { key, value, ttl })
+    }
+
+    /// Make an existing key disappear once `ttl` has passed.
+    pub fn expire_bytes(&mut self, key: Vec<u8>, ttl: Duration) -> Result<()> {
+        self.call(Request::Expire { key, ttl })
+    }
+
+    /// Time left before `key` expires, or `None` if it never does.
+    pub fn ttl_bytes(&mut self, key: Vec<u8>) -> Result<Option<Duration>> {
+        self.call(Request::Ttl { key })
+    }
+
+    /// Make an existing key never expire.
+    pub fn persist_bytes(&mut self, key: Vec<u8>) -> Result<()> {
+        self.call(Request::Persist { key })
     }
+
+    pub fn compare_and_swap(
+        &mut self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<()> {
+        self.compare_and_swap_bytes(
+            key.into_bytes(),
+            expected.map(String::into_bytes),
+            new.map(String::into_bytes),
+        )
+    }
+
+    /// Set `key` only if it does not exist yet.
+    pub fn set_if_absent(&mut self, key: String, value: String) -> Result<()> {
+        self.compare_and_swap(key, None, Some(value))
+    }
+
+    pub fn get_with_version(&mut self, key: String) -> Result<Option<(String, u64)>> {
+        self.get_with_version_bytes(key.into_bytes())?
+            .map(|(value, version)| Ok((String::from_utf8(value)?, version)))
+            .transpose()
+    }
+
+    pub fn set_if_version(&mut self, key: String, value: String, version: u64) -> Result<()> {
+        self.set_if_version_bytes(key.into_bytes(), value.into_bytes(), version)
+    }
+
+    pub fn set_with_ttl(&mut self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.set_with_ttl_bytes(key.into_bytes(), value.into_bytes(), ttl)
+    }
+
+    pub fn expire(&mut self, key: String, ttl: Duration) -> Result<()> {
+        self.expire_bytes(key.into_bytes(), ttl)
+    }
+
+    pub fn ttl(&mut self, key: String) -> Result<Option<Duration>> {
+        self.ttl_bytes(key.into_bytes())
+    }
+
+    pub fn persist(&mut self, key: String) -> Result<()> {
+        self.persist_bytes(key.into_bytes())
+    }
+
+    /// Open a transaction on the server. Until `commit` or `discard`, `get`,
+    /// `set` and `remove` run inside it: reads are checked for changes when
+    /// it commits and writes are applied together.
+    pub fn begin(&mut self) -> Result<()> {
+        self.call(Request::Begin)
+    }
+
+    /// Commit the open transaction. Fails with
+    /// `KvsError::TransactionConflict`, writing nothing, if a key it read
+    /// has changed since.
+    pub fn commit(&mut self) -> Result<()> {
+        self.call(Request::Commit)
+    }
+
+    /// Drop the open transaction without writing anything.
+    pub fn discard(&mut self) -> Result<()> {
+        self.call(Request::Discard)
+    }
+
+    /// Have the server write a backup of the store to `path`, a relative path
+    /// inside its `--backup-dir`. Returns the number of pairs written.
+    pub fn backup(&mut self, path: String) -> Result<u64> {
+        self.call(Request::Backup { path })
+    }
+
+    /// Queue a request without waiting for its response.
+    pub fn send(&mut self, request: &Request) -> Result<()> {
+        request.write_to(&mut self.writer, self.max_frame_size)?;
+        self.pending += 1;
+        Ok(())
+    }
+
+    /// Read the response to the oldest request that has not been answered,
+    /// sending any queued requests first.
+    pub fn recv<T: DeserializeOwned>(&mut self) -> Result<T> {
+        if self.pending == 0 {
+            return Err(KvsError::Protocol(
+                "No request is waiting for a response".to_string(),
+            ));
+        }
+        self.writer.flush()?;
+        self.pending -= 1;
+        read_response(&mut self.reader, self.max_frame_size)
+    }
+
+    fn call<T: DeserializeOwned>(&mut self, request: Request) -> Result<T> {
+        if self.pending > 0 {
+            return Err(KvsError::Protocol(format!(
+                "{} pipelined responses have not been read",
+                self.pending
+            )));
+        }
+        self.send(&request)?;
+        self.recv()
+    }
+}
+
+fn unblob_pairs(pairs: Vec<(Blob, Blob)>) -> Vec<(Vec<u8>, Vec<u8>)> {
+    pairs
+        .into_iter()
+        .map(|(Blob(key), Blob(value))| (key, value))
+        .collect()
 }
@@ -0,0 +1,499 @@
+//! The framed wire protocol spoken by `KvServer` and `KvClient`.
+//!
+//! Every message is a frame: a big-endian `u32` payload length, a one-byte
+//! opcode, and the payload. Request payloads hold the request's arguments and
+//! response payloads hold the result, both encoded with serde. Keys and
+//! values that are valid UTF-8 are encoded as strings and any other bytes as
+//! base64, so text is encoded the same way it always was.
+//!
+//! A connection carries any number of requests, and responses come back in
+//! the order the requests were sent. Frames are not flushed as they are
+//! written, so a client can pipeline several requests in one write.
+
+use std::{
+    io::{self, ErrorKind, Read, Write},
+    ops::Bound,
+    time::Duration,
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::kvs::bytes::Blob;
+use crate::{KvsError, Result, WriteBatch};
+
+/// Largest payload accepted unless configured otherwise.
+pub const DEFAULT_MAX_FRAME_SIZE: u32 = 64 * 1024 * 1024;
+
+/// Most pairs one scan returns, whatever limit the client asks for.
+pub const MAX_SCAN_LIMIT: usize = 1000;
+
+const HEADER_LEN: usize = 5;
+
+/// The opcode that starts every frame.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum OpCode {
+    Get = 0x01,
+    Set = 0x02,
+    Remove = 0x03,
+    Scan = 0x04,
+    ScanPrefix = 0x05,
+    Batch = 0x06,
+    CompareAndSwap = 0x07,
+    GetWithVersion = 0x08,
+    SetIfVersion = 0x09,
+    SetWithTtl = 0x0a,
+    Expire = 0x0b,
+    Ttl = 0x0c,
+    Persist = 0x0d,
+    Begin = 0x0e,
+    Commit = 0x0f,
+    Discard = 0x10,
+    Backup = 0x11,
+    Ok = 0x80,
+    Err = 0x81,
+}
+
+impl TryFrom<u8> for OpCode {
+    type Error = KvsError;
+
+    fn try_from(byte: u8) -> Result<Self> {
+        Ok(match byte {
+            0x01 => OpCode::Get,
+            0x02 => OpCode::Set,
+            0x03 => OpCode::Remove,
+            0x04 => OpCode::Scan,
+            0x05 => OpCode::ScanPrefix,
+            0x06 => OpCode::Batch,
+            0x07 => OpCode::CompareAndSwap,
+            0x08 => OpCode::GetWithVersion,
+            0x09 => OpCode::SetIfVersion,
+            0x0a => OpCode::SetWithTtl,
+            0x0b => OpCode::Expire,
+            0x0c => OpCode::Ttl,
+            0x0d => OpCode::Persist,
+            0x0e => OpCode::Begin,
+            0x0f => OpCode::Commit,
+            0x10 => OpCode::Discard,
+            0x11 => OpCode::Backup,
+            0x80 => OpCode::Ok,
+            0x81 => OpCode::Err,
+            _ => return Err(KvsError::Protocol(format!("Unknown opcode {:#04x}", byte))),
+        })
+    }
+}
+
+/// A request sent by a client.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Request {
+    Get {
+        key: Vec<u8>,
+    },
+    Set {
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    Remove {
+        key: Vec<u8>,
+    },
+    Scan {
+        start: Bound<Vec<u8>>,
+        end: Bound<Vec<u8>>,
+        limit: usize,
+    },
+    ScanPrefix {
+        prefix: Vec<u8>,
+        limit: usize,
+    },
+    Batch(WriteBatch),
+    CompareAndSwap {
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    },
+    GetWithVersion {
+        key: Vec<u8>,
+    },
+    SetIfVersion {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        version: u64,
+    },
+    SetWithTtl {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    },
+    Expire {
+        key: Vec<u8>,
+        ttl: Duration,
+    },
+    Ttl {
+        key: Vec<u8>,
+    },
+    Persist {
+        key: Vec<u8>,
+    },
+    /// Open a transaction on the connection. Until it is committed or
+    /// discarded, `Get`, `Set` and `Remove` run inside it and nothing else is
+    /// allowed.
+    Begin,
+    Commit,
+    Discard,
+    /// Write a backup of the store to `path` inside the server's backup
+    /// directory, which answers with the number of pairs written.
+    Backup {
+        path: String,
+    },
+}
+
+impl Request {
+    /// Write the request as one frame.
+    pub fn write_to<W: Write>(&self, writer: &mut W, max_frame_size: u32) -> Result<()> {
+        let (opcode, payload) = match self {
+            Request::Get { key } => (OpCode::Get, serde_json::to_vec(&(Blob(key),))?),
+            Request::Set { key, value } => {
+                (OpCode::Set, serde_json::to_vec(&(Blob(key), Blob(value)))?)
+            }
+            Request::Remove { key } => (OpCode::Remove, serde_json::to_vec(&(Blob(key),))?),
+            Request::Scan { start, end, limit } => (
+                OpCode::Scan,
+                serde_json::to_vec(&(start.as_ref().map(Blob), end.as_ref().map(Blob), limit))?,
+            ),
+            Request::ScanPrefix { prefix, limit } => (
+                OpCode::ScanPrefix,
+                serde_json::to_vec(&(Blob(prefix), limit))?,
+            ),
+            Request::Batch(batch) => (OpCode::Batch, serde_json::to_vec(batch)?),
+            Request::CompareAndSwap { key, expected, new } => (
+                OpCode::CompareAndSwap,
+                serde_json::to_vec(&(
+                    Blob(key),
+                    expected.as_ref().map(Blob),
+                    new.as_ref().map(Blob),
+                ))?,
+            ),
+            Request::GetWithVersion { key } => {
+                (OpCode::GetWithVersion, serde_json::to_vec(&(Blob(key),))?)
+            }
+            Request::SetIfVersion {
+                key,
+                value,
+                version,
+            } => (
+                OpCode::SetIfVersion,
+                serde_json::to_vec(&(Blob(key), Blob(value), version))?,
+            ),
+            Request::SetWithTtl { key, value, ttl } => (
+                OpCode::SetWithTtl,
+                serde_json::to_vec(&(Blob(key), Blob(value), ttl))?,
+            ),
+            Request::Expire { key, ttl } => {
+                (OpCode::Expire, serde_json::to_vec(&(Blob(key), ttl))?)
+            }
+            Request::Ttl { key } => (OpCode::Ttl, serde_json::to_vec(&(Blob(key),))?),
+            Request::Persist { key } => (OpCode::Persist, serde_json::to_vec(&(Blob(key),))?),
+            Request::Begin => (OpCode::Begin, Vec::new()),
+            Request::Commit => (OpCode::Commit, Vec::new()),
+            Request::Discard => (OpCode::Discard, Vec::new()),
+            Request::Backup { path } => (OpCode::Backup, serde_json::to_vec(&(path,))?),
+        };
+        write_frame(writer, opcode, &payload, max_frame_size)
+    }
+
+    /// Read the next request, or `None` if the peer closed the connection.
+    pub fn read_from<R: Read>(reader: &mut R, max_frame_size: u32) -> Result<Option<Request>> {
+        let (opcode, payload) = match read_frame(reader, max_frame_size)? {
+            Some(frame) => frame,
+            None => return Ok(None),
+        };
+        Ok(Some(match opcode {
+            OpCode::Get => {
+                let (Blob(key),) = serde_json::from_slice(&payload)?;
+                Request::Get { key }
+            }
+            OpCode::Set => {
+                let (Blob(key), Blob(value)) = serde_json::from_slice(&payload)?;
+                Request::Set { key, value }
+            }
+            OpCode::Remove => {
+                let (Blob(key),) = serde_json::from_slice(&payload)?;
+                Request::Remove { key }
+            }
+            OpCode::Scan => {
+                let (start, end, limit): (Bound<Blob>, Bound<Blob>, _) =
+                    serde_json::from_slice(&payload)?;
+                Request::Scan {
+                    start: start.map(|Blob(key)| key),
+                    end: end.map(|Blob(key)| key),
+                    limit,
+                }
+            }
+            OpCode::ScanPrefix => {
+                let (Blob(prefix), limit) = serde_json::from_slice(&payload)?;
+                Request::ScanPrefix { prefix, limit }
+            }
+            OpCode::Batch => Request::Batch(serde_json::from_slice(&payload)?),
+            OpCode::CompareAndSwap => {
+                let (Blob(key), expected, new): (_, Option<Blob>, Option<Blob>) =
+                    serde_json::from_slice(&payload)?;
+                Request::CompareAndSwap {
+                    key,
+                    expected: expected.map(|Blob(value)| value),
+                    new: new.map(|Blob(value)| value),
+                }
+            }
+            OpCode::GetWithVersion => {
+                let (Blob(key),) = serde_json::from_slice(&payload)?;
+                Request::GetWithVersion { key }
+            }
+            OpCode::SetIfVersion => {
+                let (Blob(key), Blob(value), version) = serde_json::from_slice(&payload)?;
+                Request::SetIfVersion {
+                    key,
+                    value,
+                    version,
+                }
+            }
+            OpCode::SetWithTtl => {
+                let (Blob(key), Blob(value), ttl) = serde_json::from_slice(&payload)?;
+                Request::SetWithTtl { key, value, ttl }
+            }
+            OpCode::Expire => {
+                let (Blob(key), ttl) = serde_json::from_slice(&payload)?;
+                Request::Expire { key, ttl }
+            }
+            OpCode::Ttl => {
+                let (Blob(key),) = serde_json::from_slice(&payload)?;
+                Request::Ttl { key }
+            }
+            OpCode::Persist => {
+                let (Blob(key),) = serde_json::from_slice(&payload)?;
+                Request::Persist { key }
+            }
+            OpCode::Begin => Request::Begin,
+            OpCode::Commit => Request::Commit,
+            OpCode::Discard => Request::Discard,
+            OpCode::Backup => {
+                let (path,) = serde_json::from_slice(&payload)?;
+                Request::Backup { path }
+            }
+            OpCode::Ok | OpCode::Err => {
+                return Err(KvsError::Protocol(format!(
+                    "Expected a request, got {:?}",
+                    opcode
+                )))
+            }
+        }))
+    }
+}
+
+/// Write the result of a request as one frame.
+pub fn write_response<W: Write, T: Serialize>(
+    writer: &mut W,
+    response: &Result<T>,
+    max_frame_size: u32,
+) -> Result<()> {
+    match response {
+        Ok(value) => write_frame(
+            writer,
+            OpCode::Ok,
+            &serde_json::to_vec(value)?,
+            max_frame_size,
+        ),
+        Err(e) => write_frame(writer, OpCode::Err, &serde_json::to_vec(e)?, max_frame_size),
+    }
+}
+
+/// Read the result of a request.
+pub fn read_response<R: Read, T: DeserializeOwned>(
+    reader: &mut R,
+    max_frame_size: u32,
+) -> Result<T> {
+    match read_frame(reader, max_frame_size)? {
+        Some((OpCode::Ok, payload)) => Ok(serde_json::from_slice(&payload)?),
+        Some((OpCode::Err, payload)) => Err(serde_json::from_slice::<KvsError>(&payload)?),
+        Some((opcode, _)) => Err(KvsError::Protocol(format!(
+            "Expected a response, got {:?}",
+            opcode
+        ))),
+        None => Err(KvsError::Protocol(
+            "Connection closed before the response".to_string(),
+        )),
+    }
+}
+
+fn write_frame<W: Write>(
+    writer: &mut W,
+    opcode: OpCode,
+    payload: &[u8],
+    max_frame_size: u32,
+) -> Result<()> {
+    let len = u32::try_from(payload.len())
+        .ok()
+        .filter(|len| *len <= max_frame_size)
+        .ok_or_else(|| frame_too_large(payload.len(), max_frame_size))?;
+    let mut header = [0; HEADER_LEN];
+    header[..4].copy_from_slice(&len.to_be_bytes());
+    header[4] = opcode as u8;
+    writer.write_all(&header)?;
+    writer.write_all(payload)?;
+    Ok(())
+}
+
+fn read_frame<R: Read>(reader: &mut R, max_frame_size: u32) -> Result<Option<(OpCode, Vec<u8>)>> {
+    let mut header = [0; HEADER_LEN];
+    let mut filled = 0;
+    while filled < HEADER_LEN {
+        match reader.read(&mut header[filled..]) {
+            Ok(0) if filled == 0 => return Ok(None),
+            Ok(0) => return Err(io::Error::from(ErrorKind::UnexpectedEof).into()),
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    let len = u32::from_be_bytes(header[..4].try_into().unwrap());
+    if len > max_frame_size {
+        return Err(frame_too_large(len as usize, max_frame_size));
+    }
+    let opcode = OpCode::try_from(header[4])?;
+    // Grow the buffer as bytes arrive rather than trusting the header.
+    let mut payload = Vec::new();
+    reader.take(len as u64).read_to_end(&mut payload)?;
+    if payload.len() < len as usize {
+        return Err(io::Error::from(ErrorKind::UnexpectedEof).into());
+    }
+    Ok(Some((opcode, payload)))
+}
+
+fn frame_too_large(len: usize, max_frame_size: u32) -> KvsError {
+    KvsError::Protocol(format!(
+        "Frame of {} bytes exceeds the limit of {} bytes",
+        len, max_frame_size
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_request_round_trip() {
+        let requests = vec![
+            Request::Get {
+                key: b"key with spaces\n".to_vec(),
+            },
+            Request::Set {
+                key: b"k".to_vec(),
+                value: "v ".repeat(1000).into_bytes(),
+            },
+            Request::Set {
+                key: vec![0xff, 0],
+                value: vec![0xc3],
+            },
+            Request::Remove { key: Vec::new() },
+            Request::Scan {
+                start: Bound::Excluded(b"a".to_vec()),
+                end: Bound::Included(vec![0xff]),
+                limit: 10,
+            },
+            Request::ScanPrefix {
+                prefix: b"user:".to_vec(),
+                limit: 1,
+            },
+            Request::Batch(
+                WriteBatch::new()
+                    .set("a", "1")
+                    .set(vec![0x80], vec![0x81])
+                    .remove("b"),
+            ),
+            Request::CompareAndSwap {
+                key: b"k".to_vec(),
+                expected: None,
+                new: Some(vec![0xfe]),
+            },
+            Request::SetIfVersion {
+                key: b"k".to_vec(),
+                value: b"v".to_vec(),
+                version: u64::MAX,
+            },
+            Request::SetWithTtl {
+                key: vec![0xff],
+                value: b"v".to_vec(),
+                ttl: Duration::from_millis(1500),
+            },
+            Request::Begin,
+            Request::Commit,
+            Request::Backup {
+                path: "backup".to_string(),
+            },
+        ];
+        let mut buf = Vec::new();
+        for request in &requests {
+            request.write_to(&mut buf, DEFAULT_MAX_FRAME_SIZE).unwrap();
+        }
+        let mut reader = Cursor::new(buf);
+        for request in requests {
+            assert_eq!(
+                Request::read_from(&mut reader, DEFAULT_MAX_FRAME_SIZE).unwrap(),
+                Some(request)
+            );
+        }
+        assert_eq!(
+            Request::read_from(&mut reader, DEFAULT_MAX_FRAME_SIZE).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_response_round_trip() {
+        let mut buf = Vec::new();
+        write_response(&mut buf, &Ok(Some("v".to_string())), DEFAULT_MAX_FRAME_SIZE).unwrap();
+        write_response::<_, ()>(
+            &mut buf,
+            &Err(KvsError::KeyNotFound),
+            DEFAULT_MAX_FRAME_SIZE,
+        )
+        .unwrap();
+        let mut reader = Cursor::new(buf);
+        assert_eq!(
+            read_response::<_, Option<String>>(&mut reader, DEFAULT_MAX_FRAME_SIZE).unwrap(),
+            Some("v".to_string())
+        );
+        assert!(matches!(
+            read_response::<_, ()>(&mut reader, DEFAULT_MAX_FRAME_SIZE),
+            Err(KvsError::KeyNotFound)
+        ));
+    }
+
+    #[test]
+    fn test_max_frame_size() {
+        let request = Request::Set {
+            key: b"k".to_vec(),
+            value: "v".repeat(100).into_bytes(),
+        };
+        assert!(matches!(
+            request.write_to(&mut Vec::new(), 16),
+            Err(KvsError::Protocol(_))
+        ));
+        let mut buf = Vec::new();
+        request.write_to(&mut buf, DEFAULT_MAX_FRAME_SIZE).unwrap();
+        assert!(matches!(
+            Request::read_from(&mut Cursor::new(buf), 16),
+            Err(KvsError::Protocol(_))
+        ));
+    }
+
+    #[test]
+    fn test_truncated_frame() {
+        // A header promising more than arrives must not be trusted up front.
+        let mut buf = u32::MAX.to_be_bytes().to_vec();
+        buf.extend_from_slice(&[OpCode::Get as u8, 1, 2, 3]);
+        assert!(matches!(
+            Request::read_from(&mut Cursor::new(buf), u32::MAX),
+            Err(KvsError::Io(e)) if e.kind() == ErrorKind::UnexpectedEof
+        ));
+    }
+}
@@ -0,0 +1,629 @@
+//! A RESP2 front end, so Redis clients can talk to a `KvServer`.
+//!
+//! Supported commands are GET, SET, DEL, EXISTS, PING, KEYS, SCAN, MGET and
+//! MSET, plus QUIT and an empty COMMAND reply for `redis-cli`. The admin
+//! command `BACKUP name` writes a backup into the server's backup directory.
+
+use std::io::{BufRead, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::ops::Bound;
+use std::path::Path;
+
+use super::protocol::MAX_SCAN_LIMIT;
+use super::server::{on_pool, ConnectionReader};
+use crate::{kvs::backup, thread_pool::ThreadPool, KvsEngine, KvsError, Result, WriteBatch};
+
+/// Longest line accepted for inline commands and frame headers.
+const MAX_LINE: usize = 64 * 1024;
+/// How deeply arrays may nest inside a request.
+const MAX_DEPTH: usize = 8;
+/// Number of keys SCAN returns when no COUNT is given.
+const DEFAULT_SCAN_COUNT: usize = 10;
+
+/// A RESP2 value.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RespValue {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Option<Vec<RespValue>>),
+}
+
+impl RespValue {
+    fn ok() -> RespValue {
+        RespValue::Simple("OK".to_string())
+    }
+
+    fn bulk(value: Option<impl Into<Vec<u8>>>) -> RespValue {
+        RespValue::Bulk(value.map(Into::into))
+    }
+
+    fn error(msg: impl Into<String>) -> RespValue {
+        RespValue::Error(msg.into())
+    }
+
+    /// Write the value in RESP2 encoding.
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        match self {
+            RespValue::Simple(s) => write!(writer, "+{}\r\n", s)?,
+            RespValue::Error(s) => write!(writer, "-{}\r\n", s)?,
+            RespValue::Integer(i) => write!(writer, ":{}\r\n", i)?,
+            RespValue::Bulk(None) => writer.write_all(b"$-1\r\n")?,
+            RespValue::Bulk(Some(bytes)) => {
+                write!(writer, "${}\r\n", bytes.len())?;
+                writer.write_all(bytes)?;
+                writer.write_all(b"\r\n")?;
+            }
+            RespValue::Array(None) => writer.write_all(b"*-1\r\n")?,
+            RespValue::Array(Some(values)) => {
+                write!(writer, "*{}\r\n", values.len())?;
+                for value in values {
+                    value.write_to(writer)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Read one value, or `None` if the peer closed the connection.
+    pub fn read_from<R: BufRead>(reader: &mut R, max_bulk_size: u32) -> Result<Option<RespValue>> {
+        RespValue::read_nested(reader, max_bulk_size, 0)
+    }
+
+    /// Read one value found `depth` arrays deep.
+    fn read_nested<R: BufRead>(
+        reader: &mut R,
+        max_bulk_size: u32,
+        depth: usize,
+    ) -> Result<Option<RespValue>> {
+        let line = match read_line(reader)? {
+            Some(line) => line,
+            None => return Ok(None),
+        };
+        let (kind, rest) = match line.split_first() {
+            Some(split) => split,
+            None => return Ok(Some(RespValue::Array(Some(Vec::new())))),
+        };
+        let text = || String::from_utf8_lossy(rest).into_owned();
+        Ok(Some(match kind {
+            b'+' => RespValue::Simple(text()),
+            b'-' => RespValue::Error(text()),
+            b':' => RespValue::Integer(parse_int(rest)?),
+            b'$' => match parse_int(rest)? {
+                -1 => RespValue::Bulk(None),
+                len if len >= 0 && len <= max_bulk_size as i64 => {
+                    // Grow the buffer as bytes arrive rather than trusting the
+                    // length.
+                    let mut bytes = Vec::new();
+                    reader
+                        .by_ref()
+                        .take(len as u64 + 2)
+                        .read_to_end(&mut bytes)?;
+                    if bytes.len() < len as usize + 2 {
+                        return Err(std::io::Error::from(ErrorKind::UnexpectedEof).into());
+                    }
+                    if !bytes.ends_with(b"\r\n") {
+                        return Err(KvsError::Protocol("Bulk string not terminated".to_string()));
+                    }
+                    bytes.truncate(len as usize);
+                    RespValue::Bulk(Some(bytes))
+                }
+                len => return Err(KvsError::Protocol(format!("Invalid bulk length {}", len))),
+            },
+            b'*' => match parse_int(rest)? {
+                -1 => RespValue::Array(None),
+                _ if depth >= MAX_DEPTH => {
+                    return Err(KvsError::Protocol("Arrays nested too deeply".to_string()))
+                }
+                len if len >= 0 => {
+                    let mut values = Vec::with_capacity(len.min(1024) as usize);
+                    for _ in 0..len {
+                        values.push(
+                            RespValue::read_nested(reader, max_bulk_size, depth + 1)?.ok_or_else(
+                                || {
+                                    KvsError::Protocol(
+                                        "Connection closed inside an array".to_string(),
+                                    )
+                                },
+                            )?,
+                        );
+                    }
+                    RespValue::Array(Some(values))
+                }
+                len => return Err(KvsError::Protocol(format!("Invalid array length {}", len))),
+            },
+            // Anything else is an inline command such as `PING` typed by hand.
+            _ => RespValue::Array(Some(
+                line.split(|b| b.is_ascii_whitespace())
+                    .filter(|word| !word.is_empty())
+                    .map(|word| RespValue::Bulk(Some(word.to_vec())))
+                    .collect(),
+            )),
+        }))
+    }
+}
+
+/// Read a `\r\n` terminated line without the terminator.
+fn read_line<R: BufRead>(reader: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    let len = reader.take(MAX_LINE as u64).read_until(b'\n', &mut line)?;
+    if len == 0 {
+        return Ok(None);
+    }
+    if !line.ends_with(b"\n") {
+        return Err(if len == MAX_LINE {
+            KvsError::Protocol("Line too long".to_string())
+        } else {
+            std::io::Error::from(ErrorKind::UnexpectedEof).into()
+        });
+    }
+    line.pop();
+    if line.ends_with(b"\r") {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn parse_int(bytes: &[u8]) -> Result<i64> {
+    std::str::from_utf8(bytes)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| KvsError::Protocol("Invalid integer".to_string()))
+}
+
+/// Serve RESP commands from one connection until the client hangs up or the
+/// server shuts down.
+pub(crate) fn handle_connection<T: KvsEngine, R: ThreadPool>(
+    pool: &R,
+    store: &T,
+    reader: ConnectionReader,
+    stream: TcpStream,
+    max_bulk_size: u32,
+    backup_dir: Option<&Path>,
+) -> Result<()> {
+    let mut reader = BufReader::new(reader);
+    let mut writer = BufWriter::new(stream);
+    loop {
+        if reader.buffer().is_empty() {
+            reader.get_mut().expect_request();
+        }
+        let (reply, quit) = match RespValue::read_from(&mut reader, max_bulk_size) {
+            Ok(Some(RespValue::Array(Some(args)))) if !args.is_empty() => {
+                match args
+                    .into_iter()
+                    .map(|arg| match arg {
+                        RespValue::Bulk(Some(bytes)) => Some(bytes),
+                        _ => None,
+                    })
+                    .collect::<Option<Vec<Vec<u8>>>>()
+                {
+                    Some(args) => {
+                        let quit = args[0].eq_ignore_ascii_case(b"QUIT");
+                        let store = store.clone();
+                        let backup_dir = backup_dir.map(Path::to_path_buf);
+                        let reply =
+                            on_pool(pool, move || execute(&store, &args, backup_dir.as_deref()))?;
+                        (reply, quit)
+                    }
+                    None => (
+                        RespValue::error("ERR arguments must be bulk strings"),
+                        false,
+                    ),
+                }
+            }
+            Ok(Some(RespValue::Array(Some(_)))) => continue,
+            Ok(Some(_)) => (
+                RespValue::error("ERR expected an array of bulk strings"),
+                false,
+            ),
+            Ok(None) => return Ok(()),
+            Err(e) => (RespValue::error(format!("ERR {}", e)), true),
+        };
+        reply.write_to(&mut writer)?;
+        if quit {
+            writer.flush()?;
+            return Ok(());
+        }
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+    }
+}
+
+/// Run one command against the engine and build its reply. `BACKUP` writes
+/// into `backup_dir`, and is refused without one.
+pub fn execute<T: KvsEngine>(store: &T, args: &[Vec<u8>], backup_dir: Option<&Path>) -> RespValue {
+    match run(store, args, backup_dir) {
+        Ok(reply) => reply,
+        Err(e) => RespValue::error(format!("ERR {}", e)),
+    }
+}
+
+fn run<T: KvsEngine>(store: &T, args: &[Vec<u8>], backup_dir: Option<&Path>) -> Result<RespValue> {
+    let command = String::from_utf8_lossy(&args[0]).to_ascii_uppercase();
+    let args = &args[1..];
+    let wrong_arity = || {
+        Ok(RespValue::error(format!(
+            "ERR wrong number of arguments for '{}' command",
+            command.to_ascii_lowercase()
+        )))
+    };
+    match command.as_str() {
+        "PING" => match args {
+            [] => Ok(RespValue::Simple("PONG".to_string())),
+            [message] => Ok(RespValue::bulk(Some(message.clone()))),
+            _ => wrong_arity(),
+        },
+        "QUIT" => Ok(RespValue::ok()),
+        "COMMAND" => Ok(RespValue::Array(Some(Vec::new()))),
+        "GET" => match args {
+            [key] => Ok(RespValue::Bulk(store.get_bytes(key.clone())?)),
+            _ => wrong_arity(),
+        },
+        "SET" => match args {
+            [key, value] => {
+                store.set_bytes(key.clone(), value.clone())?;
+                Ok(RespValue::ok())
+            }
+            [_, _, ..] => Ok(RespValue::error("ERR syntax error")),
+            _ => wrong_arity(),
+        },
+        "DEL" if !args.is_empty() => {
+            let mut removed = 0;
+            for key in args {
+                match store.remove_bytes(key.clone()) {
+                    Ok(()) => removed += 1,
+                    Err(KvsError::KeyNotFound) => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            Ok(RespValue::Integer(removed))
+        }
+        "EXISTS" if !args.is_empty() => {
+            let mut found = 0;
+            for key in args {
+                if store.get_bytes(key.clone())?.is_some() {
+                    found += 1;
+                }
+            }
+            Ok(RespValue::Integer(found))
+        }
+        "MGET" if !args.is_empty() => Ok(RespValue::Array(Some(
+            args.iter()
+                .map(|key| Ok(RespValue::Bulk(store.get_bytes(key.clone())?)))
+                .collect::<Result<_>>()?,
+        ))),
+        "MSET" if !args.is_empty() && args.len().is_multiple_of(2) => {
+            let batch = args.chunks(2).fold(WriteBatch::new(), |batch, pair| {
+                batch.set(pair[0].clone(), pair[1].clone())
+            });
+            store.apply_batch(batch)?;
+            Ok(RespValue::ok())
+        }
+        "KEYS" => match args {
+            [pattern] => Ok(RespValue::Array(Some(
+                store
+                    .keys_bytes()?
+                    .into_iter()
+                    .filter(|key| glob_match(pattern, key))
+                    .map(|key| RespValue::bulk(Some(key)))
+                    .collect(),
+            ))),
+            _ => wrong_arity(),
+        },
+        "SCAN" if !args.is_empty() => scan(store, args),
+        "BACKUP" => match args {
+            [name] => {
+                let name = String::from_utf8(name.clone())?;
+                Ok(RespValue::Integer(
+                    backup::backup_in(store, backup_dir, &name)? as i64,
+                ))
+            }
+            _ => wrong_arity(),
+        },
+        "DEL" | "EXISTS" | "MGET" | "MSET" | "SCAN" => wrong_arity(),
+        _ => Ok(RespValue::error(format!(
+            "ERR unknown command '{}'",
+            command.to_ascii_lowercase()
+        ))),
+    }
+}
+
+/// `SCAN cursor [MATCH pattern] [COUNT count]`.
+///
+/// The cursor encodes the last key returned, so keys removed in the middle of
+/// a scan do not make it skip others. COUNT is capped at `MAX_SCAN_LIMIT`.
+fn scan<T: KvsEngine>(store: &T, args: &[Vec<u8>]) -> Result<RespValue> {
+    let parse = |arg: &[u8]| std::str::from_utf8(arg).ok()?.parse::<usize>().ok();
+    let Some(start) = decode_cursor(&args[0]) else {
+        return Ok(RespValue::error("ERR invalid cursor"));
+    };
+    let mut pattern: &[u8] = b"*";
+    let mut count = DEFAULT_SCAN_COUNT;
+    for option in args[1..].chunks(2) {
+        match option {
+            [name, value] if name.eq_ignore_ascii_case(b"MATCH") => pattern = value,
+            [name, value] if name.eq_ignore_ascii_case(b"COUNT") => match parse(value) {
+                Some(n) if n > 0 => count = n,
+                _ => {
+                    return Ok(RespValue::error(
+                        "ERR value is not an integer or out of range",
+                    ))
+                }
+            },
+            _ => return Ok(RespValue::error("ERR syntax error")),
+        }
+    }
+
+    let count = count.min(MAX_SCAN_LIMIT);
+    let keys: Vec<Vec<u8>> = store
+        .scan_bytes((start, Bound::Unbounded), count)?
+        .into_iter()
+        .map(|(key, _)| key)
+        .collect();
+    let next = match keys.last() {
+        Some(last) if keys.len() == count => encode_cursor(last),
+        _ => "0".to_string(),
+    };
+    let page = keys
+        .into_iter()
+        .filter(|key| glob_match(pattern, key))
+        .map(|key| RespValue::bulk(Some(key)))
+        .collect();
+    Ok(RespValue::Array(Some(vec![
+        RespValue::bulk(Some(next)),
+        RespValue::Array(Some(page)),
+    ])))
+}
+
+/// A SCAN cursor resuming after `key`: a `1` followed by every byte of the
+/// key as three decimal digits, so clients that expect a number still get
+/// one.
+fn encode_cursor(key: &[u8]) -> String {
+    std::iter::once("1".to_string())
+        .chain(key.iter().map(|byte| format!("{:03}", byte)))
+        .collect()
+}
+
+/// Where the scan with `cursor` resumes, or `None` if the cursor is invalid.
+fn decode_cursor(cursor: &[u8]) -> Option<Bound<Vec<u8>>> {
+    match cursor {
+        b"0" => Some(Bound::Unbounded),
+        [b'1', digits @ ..] if digits.len() % 3 == 0 => digits
+            .chunks(3)
+            .map(|byte| std::str::from_utf8(byte).ok()?.parse::<u8>().ok())
+            .collect::<Option<Vec<u8>>>()
+            .map(Bound::Excluded),
+        _ => None,
+    }
+}
+
+/// Redis-style glob matching with `*`, `?`, `[...]` and `\` escapes.
+///
+/// On a mismatch only the last `*` is retried, one byte further on, so
+/// matching takes at most `pattern.len() * text.len()` steps.
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // The pattern after the last `*` and the text it has swallowed up to.
+    let mut star = None;
+    while t < text.len() {
+        if pattern.get(p) == Some(&b'*') {
+            p += 1;
+            star = Some((p, t));
+        } else if let Some((true, len)) = match_one(&pattern[p..], text[t]) {
+            p += len;
+            t += 1;
+        } else if let Some((star_p, star_t)) = star {
+            p = star_p;
+            t = star_t + 1;
+            star = Some((star_p, t));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&b| b == b'*')
+}
+
+/// Whether the first element of `pattern`, which is not `*`, matches `c`,
+/// and how many bytes of the pattern it takes up. `None` if the pattern is
+/// empty.
+fn match_one(pattern: &[u8], c: u8) -> Option<(bool, usize)> {
+    match pattern {
+        [] => None,
+        [b'?', ..] => Some((true, 1)),
+        [b'\\', x, ..] => Some((*x == c, 2)),
+        [b'[', rest @ ..] => {
+            let (negate, mut class) = match rest.split_first() {
+                Some((b'^', class)) => (true, class),
+                _ => (false, rest),
+            };
+            let mut matched = false;
+            loop {
+                match class {
+                    // An unterminated class matches nothing.
+                    [] => return Some((false, pattern.len())),
+                    [b']', tail @ ..] => {
+                        class = tail;
+                        break;
+                    }
+                    [b'\\', x, tail @ ..] => {
+                        matched |= *x == c;
+                        class = tail;
+                    }
+                    [lo, b'-', hi, tail @ ..] if *hi != b']' => {
+                        matched |= (*lo.min(hi)..=*lo.max(hi)).contains(&c);
+                        class = tail;
+                    }
+                    [x, tail @ ..] => {
+                        matched |= *x == c;
+                        class = tail;
+                    }
+                }
+            }
+            Some((matched != negate, pattern.len() - class.len()))
+        }
+        [x, ..] => Some((*x == c, 1)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::KvStore;
+    use std::io::Cursor;
+    use tempfile::TempDir;
+
+    fn command(args: &[&str]) -> Vec<Vec<u8>> {
+        args.iter().map(|arg| arg.as_bytes().to_vec()).collect()
+    }
+
+    #[test]
+    fn test_read_array_and_inline() {
+        let mut reader = Cursor::new(b"*2\r\n$3\r\nGET\r\n$1\r\na\r\nPING hi\r\n".to_vec());
+        assert_eq!(
+            RespValue::read_from(&mut reader, 1024).unwrap(),
+            Some(RespValue::Array(Some(vec![
+                RespValue::Bulk(Some(b"GET".to_vec())),
+                RespValue::Bulk(Some(b"a".to_vec())),
+            ])))
+        );
+        assert_eq!(
+            RespValue::read_from(&mut reader, 1024).unwrap(),
+            Some(RespValue::Array(Some(vec![
+                RespValue::Bulk(Some(b"PING".to_vec())),
+                RespValue::Bulk(Some(b"hi".to_vec())),
+            ])))
+        );
+        assert_eq!(RespValue::read_from(&mut reader, 1024).unwrap(), None);
+    }
+
+    #[test]
+    fn test_truncated_bulk_string() {
+        let mut reader = Cursor::new(b"*1\r\n$67108864\r\nabc".to_vec());
+        assert!(matches!(
+            RespValue::read_from(&mut reader, 64 * 1024 * 1024),
+            Err(KvsError::Io(e)) if e.kind() == ErrorKind::UnexpectedEof
+        ));
+    }
+
+    #[test]
+    fn test_nesting_limit() {
+        let nested = |depth: usize| {
+            let mut bytes = b"*1\r\n".repeat(depth);
+            bytes.extend_from_slice(b"$1\r\na\r\n");
+            Cursor::new(bytes)
+        };
+        assert!(RespValue::read_from(&mut nested(MAX_DEPTH), 1024).is_ok());
+        assert!(matches!(
+            RespValue::read_from(&mut nested(MAX_DEPTH + 1), 1024),
+            Err(KvsError::Protocol(_))
+        ));
+        assert!(matches!(
+            RespValue::read_from(&mut nested(1_000_000), 1024),
+            Err(KvsError::Protocol(_))
+        ));
+    }
+
+    #[test]
+    fn test_commands() {
+        let dir = TempDir::new().unwrap();
+        let store = KvStore::open(dir.path()).unwrap();
+        assert_eq!(
+            execute(
+                &store,
+                &command(&["MSET", "a", "1", "b", "2", "c", "3"]),
+                None
+            ),
+            RespValue::ok()
+        );
+        assert_eq!(
+            execute(&store, &command(&["get", "a"]), None),
+            RespValue::bulk(Some("1".to_string()))
+        );
+        assert_eq!(
+            execute(&store, &command(&["EXISTS", "a", "x", "b"]), None),
+            RespValue::Integer(2)
+        );
+        assert_eq!(
+            execute(&store, &command(&["DEL", "a", "x"]), None),
+            RespValue::Integer(1)
+        );
+        assert_eq!(
+            execute(&store, &command(&["MGET", "a", "b"]), None),
+            RespValue::Array(Some(vec![
+                RespValue::Bulk(None),
+                RespValue::bulk(Some("2".to_string()))
+            ]))
+        );
+        assert_eq!(
+            execute(&store, &command(&["KEYS", "*"]), None),
+            RespValue::Array(Some(vec![
+                RespValue::bulk(Some("b".to_string())),
+                RespValue::bulk(Some("c".to_string()))
+            ]))
+        );
+        assert_eq!(
+            execute(&store, &command(&["SCAN", "0", "COUNT", "1"]), None),
+            RespValue::Array(Some(vec![
+                RespValue::bulk(Some("1098".to_string())),
+                RespValue::Array(Some(vec![RespValue::bulk(Some("b".to_string()))]))
+            ]))
+        );
+        assert!(matches!(
+            execute(&store, &command(&["GET"]), None),
+            RespValue::Error(_)
+        ));
+    }
+
+    #[test]
+    fn test_scan_survives_removals() {
+        let dir = TempDir::new().unwrap();
+        let store = KvStore::open(dir.path()).unwrap();
+        for key in ["a", "b", "c", "d", "e"] {
+            store.set(key.to_string(), "1".to_string()).unwrap();
+        }
+        let page =
+            |cursor: &str| match execute(&store, &command(&["SCAN", cursor, "COUNT", "2"]), None) {
+                RespValue::Array(Some(reply)) => match &reply[..] {
+                    [RespValue::Bulk(Some(next)), RespValue::Array(Some(keys))] => {
+                        (String::from_utf8(next.clone()).unwrap(), keys.len())
+                    }
+                    _ => panic!("unexpected reply {:?}", reply),
+                },
+                reply => panic!("unexpected reply {:?}", reply),
+            };
+        let (cursor, seen) = page("0");
+        assert_eq!(seen, 2);
+        store.remove("a".to_string()).unwrap();
+        let (cursor, seen) = page(&cursor);
+        assert_eq!(seen, 2);
+        let (cursor, seen) = page(&cursor);
+        assert_eq!((cursor.as_str(), seen), ("0", 1));
+        assert!(matches!(
+            execute(&store, &command(&["SCAN", "12"]), None),
+            RespValue::Error(_)
+        ));
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match(b"*", b"anything"));
+        assert!(glob_match(b"user:*", b"user:42"));
+        assert!(!glob_match(b"user:*", b"session:42"));
+        assert!(glob_match(b"h?llo", b"hello"));
+        assert!(glob_match(b"h[ae]llo", b"hallo"));
+        assert!(!glob_match(b"h[^e]llo", b"hello"));
+        assert!(glob_match(b"h[a-c]llo", b"hbllo"));
+        assert!(glob_match(b"a\\*", b"a*"));
+        assert!(!glob_match(b"a\\*", b"ab"));
+        assert!(glob_match(b"*a*b", b"xxaxxb"));
+        assert!(!glob_match(b"a*", b""));
+        assert!(glob_match(b"**", b""));
+        assert!(!glob_match(b"h[ae", b"ha"));
+
+        // Backtracking on every `*` would take exponential time here.
+        let text = vec![b'a'; 100];
+        assert!(!glob_match(b"*a*a*a*a*a*a*a*a*a*a*b", &text));
+    }
+}
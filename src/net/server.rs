@@ -1,69 +1,756 @@
-use crate::{thread_pool::ThreadPool, KvsEngine, Result, ToString};
+use super::{
+    protocol::{write_response, Request, DEFAULT_MAX_FRAME_SIZE, MAX_SCAN_LIMIT},
+    resp,
+};
+use crate::kvs::{
+    backup,
+    bytes::{prefix_range, Blob},
+};
+use crate::{thread_pool::ThreadPool, KvsEngine, KvsError, Result, Transaction};
 use std::{
-    io::{Read, Write},
-    net::TcpListener,
+    fmt,
+    io::{self, BufReader, BufWriter, ErrorKind, Read, Write},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc,
+    },
+    thread,
+    time::Duration,
 };
 
+/// How often a connection waiting for its next request checks whether the
+/// server is shutting down.
+const SHUTDOWN_POLL: Duration = Duration::from_millis(100);
+
+/// The wire protocol a `KvServer` speaks.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Protocol {
+    /// The framed protocol used by `KvClient`.
+    #[default]
+    Kvs,
+    /// RESP2, for Redis clients.
+    Resp,
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Protocol::Kvs => write!(f, "kvs"),
+            Protocol::Resp => write!(f, "resp"),
+        }
+    }
+}
+
+impl FromStr for Protocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "kvs" => Ok(Protocol::Kvs),
+            "resp" => Ok(Protocol::Resp),
+            _ => Err(format!("Invalid protocol: {}", s)),
+        }
+    }
+}
+
 pub struct KvServer<T: KvsEngine, R: ThreadPool> {
     store: T,
     listener: TcpListener,
     thread_pool: R,
+    max_frame_size: u32,
+    protocol: Protocol,
+    /// Where clients may have backups written, if anywhere.
+    backup_dir: Option<PathBuf>,
+    state: Arc<ShutdownState>,
+}
+
+/// Asks a running `KvServer` to shut down.
+///
+/// Shutting down stops the server from accepting connections, answers the
+/// requests clients have already sent, flushes the engine and makes `run`
+/// return.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    addr: SocketAddr,
+    state: Arc<ShutdownState>,
+}
+
+#[derive(Default)]
+struct ShutdownState {
+    shutting_down: AtomicBool,
+}
+
+impl ShutdownHandle {
+    /// Start shutting the server down. Returns without waiting for `run` to
+    /// finish.
+    pub fn shutdown(&self) {
+        if self.state.shutting_down.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        // Connections notice on their own once they run out of requests.
+        // Wake the accept loop so it notices.
+        let _ = TcpStream::connect(self.addr);
+    }
+
+    /// Whether a shutdown was requested.
+    pub fn is_shutting_down(&self) -> bool {
+        self.state.shutting_down.load(Ordering::SeqCst)
+    }
 }
 
 impl<T: KvsEngine, R: ThreadPool> KvServer<T, R> {
-    pub fn new(store: T, addr: String, thread_pool: R) -> KvServer<T, R> {
-        KvServer {
+    pub fn new(store: T, addr: String, thread_pool: R) -> Result<KvServer<T, R>> {
+        Ok(KvServer {
             store,
-            listener: TcpListener::bind(addr.to_string())
-                .map_err(|e| e.to_string())
-                .unwrap(),
+            listener: TcpListener::bind(addr)?,
             thread_pool,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            protocol: Protocol::default(),
+            backup_dir: None,
+            state: Arc::default(),
+        })
+    }
+
+    /// A handle that can stop the server from another thread.
+    pub fn shutdown_handle(&self) -> Result<ShutdownHandle> {
+        let mut addr = self.local_addr()?;
+        if addr.ip().is_unspecified() {
+            addr.set_ip(match addr {
+                SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+            });
         }
+        Ok(ShutdownHandle {
+            addr,
+            state: self.state.clone(),
+        })
+    }
+
+    /// The address the server is listening on.
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
     }
 
-    pub fn run(&self) -> Result<()> {
-        let listener = self.listener.try_clone().map_err(|e| e.to_string())?;
-        for stream in listener.incoming() {
-            let store = self.store.clone();
-            self.thread_pool.spawn(move || match stream {
-                Ok(stream) => {
-                    if let Err(e) = handle_connection(&store, stream) {
-                        eprintln!("Error on serving client: {}", e);
+    /// Choose the wire protocol spoken to clients.
+    pub fn set_protocol(&mut self, protocol: Protocol) {
+        self.protocol = protocol;
+    }
+
+    /// Set the largest frame the server will accept or send. In RESP mode
+    /// this limits bulk strings instead.
+    pub fn set_max_frame_size(&mut self, max_frame_size: u32) {
+        self.max_frame_size = max_frame_size;
+    }
+
+    /// Let clients have backups written to the directory `dir`, under names
+    /// they choose. Backups are refused until this is set.
+    pub fn set_backup_dir(&mut self, dir: impl Into<PathBuf>) {
+        self.backup_dir = Some(dir.into());
+    }
+
+    /// Serve clients until a `ShutdownHandle` asks the server to stop, then
+    /// wait for open connections to finish, join the thread pool and flush
+    /// the engine.
+    ///
+    /// Each connection waits for requests on a thread of its own, and hands
+    /// them to the thread pool to be served, so idle clients do not hold on
+    /// to pool threads.
+    pub fn run(&self) -> Result<()>
+    where
+        R: Sync,
+    {
+        let listener = self.listener.try_clone()?;
+        thread::scope(|scope| {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        eprintln!("Connection failed: {}", e);
+                        continue;
                     }
+                };
+                if self.state.shutting_down.load(Ordering::SeqCst) {
+                    break;
                 }
-                Err(e) => eprintln!("Connection failed: {}", e),
-            });
+                let store = self.store.clone();
+                let pool = &self.thread_pool;
+                let max_frame_size = self.max_frame_size;
+                let protocol = self.protocol;
+                let backup_dir = self.backup_dir.as_deref();
+                let state = self.state.clone();
+                let spawned = thread::Builder::new()
+                    .name("kvs-connection".to_string())
+                    .spawn_scoped(scope, move || {
+                        let served =
+                            ConnectionReader::new(&stream, state).and_then(
+                                |reader| match protocol {
+                                    Protocol::Kvs => handle_connection(
+                                        pool,
+                                        &store,
+                                        reader,
+                                        stream,
+                                        max_frame_size,
+                                        backup_dir,
+                                    ),
+                                    Protocol::Resp => resp::handle_connection(
+                                        pool,
+                                        &store,
+                                        reader,
+                                        stream,
+                                        max_frame_size,
+                                        backup_dir,
+                                    ),
+                                },
+                            );
+                        if let Err(e) = served {
+                            eprintln!("Error on serving client: {}", e);
+                        }
+                    });
+                if let Err(e) = spawned {
+                    eprintln!("Cannot serve a connection: {}", e);
+                }
+            }
+            // Leaving the scope waits for every connection to finish.
+        });
+        self.thread_pool.join();
+        self.store.flush()
+    }
+}
+
+/// Run `job` on `pool` and wait for what it returns.
+pub(crate) fn on_pool<R: ThreadPool, T: Send + 'static>(
+    pool: &R,
+    job: impl FnOnce() -> T + Send + 'static,
+) -> Result<T> {
+    let (sender, receiver) = mpsc::sync_channel(1);
+    pool.spawn(move || {
+        let _ = sender.send(job());
+    });
+    receiver
+        .recv()
+        .map_err(|_| KvsError::ThreadPool("The request was dropped".to_string()))
+}
+
+/// Reads from a connection, and ends it between two requests once the server
+/// shuts down.
+pub(crate) struct ConnectionReader {
+    stream: TcpStream,
+    state: Arc<ShutdownState>,
+    /// Whether the next byte read starts a new request.
+    between_requests: bool,
+}
+
+impl ConnectionReader {
+    fn new(stream: &TcpStream, state: Arc<ShutdownState>) -> Result<ConnectionReader> {
+        let stream = stream.try_clone()?;
+        stream.set_read_timeout(Some(SHUTDOWN_POLL))?;
+        Ok(ConnectionReader {
+            stream,
+            state,
+            between_requests: false,
+        })
+    }
+
+    /// Note that the previous request has been read in full, so a shutdown
+    /// may end the connection here.
+    pub(crate) fn expect_request(&mut self) {
+        self.between_requests = true;
+    }
+}
+
+impl Read for ConnectionReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.stream.read(buf) {
+                Ok(n) => {
+                    if n > 0 {
+                        self.between_requests = false;
+                    }
+                    return Ok(n);
+                }
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    // Stopping mid-request would lose it, so keep waiting for
+                    // the rest.
+                    if self.between_requests && self.state.shutting_down.load(Ordering::SeqCst) {
+                        return Ok(0);
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// Serve requests from one connection until the client hangs up or the
+/// server shuts down.
+fn handle_connection<T: KvsEngine, R: ThreadPool>(
+    pool: &R,
+    store: &T,
+    reader: ConnectionReader,
+    stream: TcpStream,
+    max_frame_size: u32,
+    backup_dir: Option<&Path>,
+) -> Result<()> {
+    let mut reader = BufReader::new(reader);
+    let mut writer = BufWriter::new(stream);
+    let mut transaction = None;
+    loop {
+        if reader.buffer().is_empty() {
+            reader.get_mut().expect_request();
+        }
+        let request = match Request::read_from(&mut reader, max_frame_size) {
+            Ok(Some(request)) => request,
+            Ok(None) => return Ok(()),
+            Err(e) => {
+                // The stream cannot be trusted after a bad frame, so tell the
+                // client what went wrong and hang up.
+                write_response::<_, ()>(&mut writer, &Err(e), max_frame_size)?;
+                writer.flush()?;
+                return Ok(());
+            }
+        };
+        let store = store.clone();
+        let backup_dir = backup_dir.map(Path::to_path_buf);
+        let mut open = transaction.take();
+        let (open, response) = on_pool(pool, move || -> Result<_> {
+            let mut response = Vec::new();
+            if open.is_some() || request == Request::Begin {
+                serve_transaction(&store, &mut open, request, &mut response, max_frame_size)?;
+            } else {
+                serve(
+                    &store,
+                    request,
+                    &mut response,
+                    max_frame_size,
+                    backup_dir.as_deref(),
+                )?;
+            }
+            Ok((open, response))
+        })??;
+        transaction = open;
+        writer.write_all(&response)?;
+        // Answer pipelined requests in one write.
+        if reader.buffer().is_empty() {
+            writer.flush()?;
         }
-        Ok(())
     }
 }
 
-fn handle_connection<T: KvsEngine>(store: &T, mut stream: std::net::TcpStream) -> Result<()> {
-    let mut buf = [0; 512];
-    let len = stream.read(&mut buf).map_err(|e| e.to_string())?;
-    let request = String::from_utf8(buf[0..len].to_vec()).map_err(|e| e.to_string())?;
-    let response = handle_request(store, request);
-    stream
-        .write(response.as_bytes())
-        .map_err(|e| e.to_string())?;
+/// Serve a request outside of any transaction.
+fn serve<T: KvsEngine, W: Write>(
+    store: &T,
+    request: Request,
+    mut writer: W,
+    max_frame_size: u32,
+    backup_dir: Option<&Path>,
+) -> Result<()> {
+    match request {
+        Request::Get { key } => write_response(
+            &mut writer,
+            &store.get_bytes(key).map(|value| value.map(Blob)),
+            max_frame_size,
+        )?,
+        Request::Set { key, value } => {
+            write_response(&mut writer, &store.set_bytes(key, value), max_frame_size)?
+        }
+        Request::Remove { key } => {
+            write_response(&mut writer, &store.remove_bytes(key), max_frame_size)?
+        }
+        Request::Scan { start, end, limit } => write_response(
+            &mut writer,
+            &store
+                .scan_bytes((start, end), limit.min(MAX_SCAN_LIMIT))
+                .map(blob_pairs),
+            max_frame_size,
+        )?,
+        Request::ScanPrefix { prefix, limit } => write_response(
+            &mut writer,
+            &store
+                .scan_bytes(prefix_range(prefix), limit.min(MAX_SCAN_LIMIT))
+                .map(blob_pairs),
+            max_frame_size,
+        )?,
+        Request::Batch(batch) => {
+            write_response(&mut writer, &store.apply_batch(batch), max_frame_size)?
+        }
+        Request::CompareAndSwap { key, expected, new } => write_response(
+            &mut writer,
+            &store.compare_and_swap_bytes(key, expected, new),
+            max_frame_size,
+        )?,
+        Request::GetWithVersion { key } => write_response(
+            &mut writer,
+            &store
+                .get_with_version_bytes(key)
+                .map(|found| found.map(|(value, version)| (Blob(value), version))),
+            max_frame_size,
+        )?,
+        Request::SetIfVersion {
+            key,
+            value,
+            version,
+        } => write_response(
+            &mut writer,
+            &store.set_if_version_bytes(key, value, version),
+            max_frame_size,
+        )?,
+        Request::SetWithTtl { key, value, ttl } => write_response(
+            &mut writer,
+            &store.set_with_ttl_bytes(key, value, ttl),
+            max_frame_size,
+        )?,
+        Request::Expire { key, ttl } => {
+            write_response(&mut writer, &store.expire_bytes(key, ttl), max_frame_size)?
+        }
+        Request::Ttl { key } => write_response(&mut writer, &store.ttl_bytes(key), max_frame_size)?,
+        Request::Persist { key } => {
+            write_response(&mut writer, &store.persist_bytes(key), max_frame_size)?
+        }
+        Request::Backup { path } => write_response(
+            &mut writer,
+            &backup::backup_in(store, backup_dir, &path),
+            max_frame_size,
+        )?,
+        Request::Begin => unreachable!("transactions are served by serve_transaction"),
+        Request::Commit | Request::Discard => write_response::<_, ()>(
+            &mut writer,
+            &Err(KvsError::Protocol("No transaction is open".to_string())),
+            max_frame_size,
+        )?,
+    }
     Ok(())
 }
 
-fn handle_request<T: KvsEngine>(store: &T, request: String) -> String {
-    let request = request.trim();
-    let mut parts = request.splitn(2, ' ');
-    let command = parts.next().unwrap();
-    let key = parts.next().unwrap();
-    match command {
-        "GET" => store.get(key.to_string()).to_string(),
-        "SET" => {
-            let kv: Vec<&str> = key.split(' ').collect();
-            if kv.len() != 2 {
-                return "Invalid input".to_string();
-            }
-            store.set(kv[0].to_string(), kv[1].to_string()).to_string()
+fn blob_pairs(pairs: Vec<(Vec<u8>, Vec<u8>)>) -> Vec<(Blob, Blob)> {
+    pairs
+        .into_iter()
+        .map(|(key, value)| (Blob(key), Blob(value)))
+        .collect()
+}
+
+/// Serve a request that opens a transaction or arrives while one is open.
+fn serve_transaction<T: KvsEngine, W: Write>(
+    store: &T,
+    transaction: &mut Option<Transaction<T>>,
+    request: Request,
+    mut writer: W,
+    max_frame_size: u32,
+) -> Result<()> {
+    let Some(open) = transaction.as_mut() else {
+        *transaction = Some(store.begin());
+        return write_response(&mut writer, &Ok(()), max_frame_size);
+    };
+    let done: Result<()> = Ok(());
+    match request {
+        Request::Get { key } => write_response(
+            &mut writer,
+            &open.get_bytes(key).map(|value| value.map(Blob)),
+            max_frame_size,
+        ),
+        Request::Set { key, value } => {
+            open.set_bytes(key, value);
+            write_response(&mut writer, &done, max_frame_size)
+        }
+        Request::Remove { key } => {
+            open.remove_bytes(key);
+            write_response(&mut writer, &done, max_frame_size)
+        }
+        Request::Commit => {
+            let committed = transaction.take().map_or(done, Transaction::commit);
+            write_response(&mut writer, &committed, max_frame_size)
         }
-        "REMOVE" => store.remove(key.to_string()).to_string(),
-        _ => "Invalid command".to_string(),
+        Request::Discard => {
+            *transaction = None;
+            write_response(&mut writer, &done, max_frame_size)
+        }
+        Request::Begin => write_response::<_, ()>(
+            &mut writer,
+            &Err(KvsError::Protocol(
+                "A transaction is already open".to_string(),
+            )),
+            max_frame_size,
+        ),
+        _ => write_response::<_, ()>(
+            &mut writer,
+            &Err(KvsError::Protocol(
+                "Only get, set and remove are allowed in a transaction".to_string(),
+            )),
+            max_frame_size,
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{net::client::KvClient, thread_pool::SharedQueueThreadPool, KvStore};
+    use std::{ops::Bound, thread, time::Duration};
+    use tempfile::TempDir;
+
+    /// Serve `store` on a free port in the background and return the address.
+    fn start(store: KvStore) -> String {
+        let server = KvServer::new(
+            store,
+            "127.0.0.1:0".to_string(),
+            SharedQueueThreadPool::new(2).unwrap(),
+        )
+        .unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || server.run().unwrap());
+        addr.to_string()
+    }
+
+    #[test]
+    fn test_pipelined_requests_share_a_connection() {
+        let dir = TempDir::new().unwrap();
+        let server = KvServer::new(
+            KvStore::open(dir.path()).unwrap(),
+            "127.0.0.1:0".to_string(),
+            SharedQueueThreadPool::new(1).unwrap(),
+        )
+        .unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || server.run().unwrap());
+
+        let mut client = KvClient::new(addr.to_string()).unwrap();
+        client.set("a".to_string(), "1".to_string()).unwrap();
+        assert_eq!(client.get("a".to_string()).unwrap(), Some("1".to_string()));
+
+        for i in 0..10 {
+            client
+                .send(&Request::Set {
+                    key: i.to_string().into_bytes(),
+                    value: (i * i).to_string().into_bytes(),
+                })
+                .unwrap();
+        }
+        client.send(&Request::Get { key: b"9".to_vec() }).unwrap();
+        for _ in 0..10 {
+            client.recv::<()>().unwrap();
+        }
+        assert_eq!(
+            client.recv::<Option<String>>().unwrap(),
+            Some("81".to_string())
+        );
+        assert!(client.recv::<()>().is_err());
+    }
+
+    #[test]
+    fn test_more_connections_than_pool_threads() {
+        let dir = TempDir::new().unwrap();
+        let server = KvServer::new(
+            KvStore::open(dir.path()).unwrap(),
+            "127.0.0.1:0".to_string(),
+            SharedQueueThreadPool::new(1).unwrap(),
+        )
+        .unwrap();
+        let addr = server.local_addr().unwrap().to_string();
+        thread::spawn(move || server.run().unwrap());
+
+        let mut clients: Vec<KvClient> = (0..4)
+            .map(|_| KvClient::new(addr.clone()).unwrap())
+            .collect();
+        for (i, client) in clients.iter_mut().enumerate().rev() {
+            client.set(i.to_string(), i.to_string()).unwrap();
+        }
+        for (i, client) in clients.iter_mut().enumerate() {
+            assert_eq!(client.get(i.to_string()).unwrap(), Some(i.to_string()));
+        }
+    }
+
+    #[test]
+    fn test_shutdown_drains_connections() {
+        let dir = TempDir::new().unwrap();
+        let server = KvServer::new(
+            KvStore::open(dir.path()).unwrap(),
+            "127.0.0.1:0".to_string(),
+            SharedQueueThreadPool::new(2).unwrap(),
+        )
+        .unwrap();
+        let addr = server.local_addr().unwrap();
+        let handle = server.shutdown_handle().unwrap();
+        let running = thread::spawn(move || server.run());
+
+        let mut idle = KvClient::new(addr.to_string()).unwrap();
+        let mut client = KvClient::new(addr.to_string()).unwrap();
+        client.set("a".to_string(), "1".to_string()).unwrap();
+        idle.get("a".to_string()).unwrap();
+
+        handle.shutdown();
+        running.join().unwrap().unwrap();
+        assert!(handle.is_shutting_down());
+        assert!(client.get("a".to_string()).is_err());
+        assert_eq!(
+            KvStore::open(dir.path())
+                .unwrap()
+                .get("a".to_string())
+                .unwrap(),
+            Some("1".to_string())
+        );
+    }
+
+    #[test]
+    fn test_scan_limit_is_capped() {
+        let dir = TempDir::new().unwrap();
+        let store = KvStore::open(dir.path()).unwrap();
+        for i in 0..MAX_SCAN_LIMIT + 5 {
+            store.set(format!("k{:05}", i), "v".to_string()).unwrap();
+        }
+        let mut client = KvClient::new(start(store)).unwrap();
+        let pairs = client.scan(.., usize::MAX).unwrap();
+        assert_eq!(pairs.len(), MAX_SCAN_LIMIT);
+        assert_eq!(pairs[0].0, "k00000");
+        let rest = client
+            .scan(
+                (
+                    Bound::Excluded(pairs[MAX_SCAN_LIMIT - 1].0.clone()),
+                    Bound::Unbounded,
+                ),
+                usize::MAX,
+            )
+            .unwrap();
+        assert_eq!(rest.len(), 5);
+        assert_eq!(
+            client
+                .scan_prefix("k".to_string(), usize::MAX)
+                .unwrap()
+                .len(),
+            MAX_SCAN_LIMIT
+        );
+        assert_eq!(client.scan(.., 3).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_transactions() {
+        let dir = TempDir::new().unwrap();
+        let addr = start(KvStore::open(dir.path()).unwrap());
+        let mut client = KvClient::new(addr.clone()).unwrap();
+        let mut other = KvClient::new(addr).unwrap();
+        client.set("a".to_string(), "1".to_string()).unwrap();
+
+        client.begin().unwrap();
+        assert!(matches!(client.begin(), Err(KvsError::Protocol(_))));
+        assert_eq!(client.get("a".to_string()).unwrap(), Some("1".to_string()));
+        client.set("b".to_string(), "2".to_string()).unwrap();
+        assert_eq!(client.get("b".to_string()).unwrap(), Some("2".to_string()));
+        assert_eq!(other.get("b".to_string()).unwrap(), None);
+        client.commit().unwrap();
+        assert_eq!(other.get("b".to_string()).unwrap(), Some("2".to_string()));
+
+        client.begin().unwrap();
+        client.get("a".to_string()).unwrap();
+        other.set("a".to_string(), "3".to_string()).unwrap();
+        client.set("a".to_string(), "4".to_string()).unwrap();
+        client.set("c".to_string(), "5".to_string()).unwrap();
+        assert!(matches!(
+            client.commit(),
+            Err(KvsError::TransactionConflict { key }) if key == b"a"
+        ));
+        assert_eq!(other.get("a".to_string()).unwrap(), Some("3".to_string()));
+        assert_eq!(other.get("c".to_string()).unwrap(), None);
+
+        client.begin().unwrap();
+        client.remove("a".to_string()).unwrap();
+        client.discard().unwrap();
+        assert_eq!(client.get("a".to_string()).unwrap(), Some("3".to_string()));
+    }
+
+    #[test]
+    fn test_expiry() {
+        let dir = TempDir::new().unwrap();
+        let mut client = KvClient::new(start(KvStore::open(dir.path()).unwrap())).unwrap();
+        client
+            .set_with_ttl("a".to_string(), "1".to_string(), Duration::from_millis(50))
+            .unwrap();
+        client
+            .set_with_ttl("b".to_string(), "2".to_string(), Duration::from_secs(60))
+            .unwrap();
+        client.set("c".to_string(), "3".to_string()).unwrap();
+
+        let ttl = client.ttl("b".to_string()).unwrap().unwrap();
+        assert!(ttl > Duration::from_secs(50) && ttl <= Duration::from_secs(60));
+        assert_eq!(client.ttl("c".to_string()).unwrap(), None);
+        client.persist("b".to_string()).unwrap();
+        assert_eq!(client.ttl("b".to_string()).unwrap(), None);
+        client
+            .expire("c".to_string(), Duration::from_millis(50))
+            .unwrap();
+
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(client.get("a".to_string()).unwrap(), None);
+        assert_eq!(client.get("b".to_string()).unwrap(), Some("2".to_string()));
+        assert_eq!(client.get("c".to_string()).unwrap(), None);
+    }
+
+    #[test]
+    fn test_versions() {
+        let dir = TempDir::new().unwrap();
+        let mut client = KvClient::new(start(KvStore::open(dir.path()).unwrap())).unwrap();
+        client
+            .set_if_absent("a".to_string(), "1".to_string())
+            .unwrap();
+        assert!(matches!(
+            client.set_if_absent("a".to_string(), "2".to_string()),
+            Err(KvsError::Conflict { current: Some(current) }) if current == b"1"
+        ));
+
+        let (value, version) = client.get_with_version("a".to_string()).unwrap().unwrap();
+        assert_eq!(value, "1");
+        client
+            .set_if_version("a".to_string(), "2".to_string(), version)
+            .unwrap();
+        assert!(matches!(
+            client.set_if_version("a".to_string(), "3".to_string(), version),
+            Err(KvsError::Conflict { current: Some(current) }) if current == b"2"
+        ));
+        let (value, newer) = client.get_with_version("a".to_string()).unwrap().unwrap();
+        assert_eq!(value, "2");
+        assert!(newer > version);
+
+        client
+            .compare_and_swap("a".to_string(), Some("2".to_string()), None)
+            .unwrap();
+        assert_eq!(client.get_with_version("a".to_string()).unwrap(), None);
+    }
+
+    #[test]
+    fn test_shutdown_answers_pipelined_requests() {
+        let dir = TempDir::new().unwrap();
+        let server = KvServer::new(
+            KvStore::open(dir.path()).unwrap(),
+            "127.0.0.1:0".to_string(),
+            SharedQueueThreadPool::new(1).unwrap(),
+        )
+        .unwrap();
+        let addr = server.local_addr().unwrap();
+        let handle = server.shutdown_handle().unwrap();
+        let running = thread::spawn(move || server.run());
+
+        let mut client = KvClient::new(addr.to_string()).unwrap();
+        for i in 0..100 {
+            client
+                .send(&Request::Set {
+                    key: i.to_string().into_bytes(),
+                    value: b"v".to_vec(),
+                })
+                .unwrap();
+        }
+        // Sends every request, then shuts down before reading the replies.
+        client.recv::<()>().unwrap();
+        handle.shutdown();
+        for _ in 1..100 {
+            client.recv::<()>().unwrap();
+        }
+        running.join().unwrap().unwrap();
+        assert_eq!(
+            KvStore::open(dir.path())
+                .unwrap()
+                .get("99".to_string())
+                .unwrap(),
+            Some("v".to_string())
+        );
     }
 }
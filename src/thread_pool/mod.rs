@@ -4,32 +4,166 @@ pub mod shared_queue_thread_pool;
 pub use naive_thread_pool::NaiveThreadPool;
 pub use shared_queue_thread_pool::SharedQueueThreadPool;
 
-use crate::Result;
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+
+use log::error;
+
+use crate::{KvsError, Result};
 pub trait ThreadPool {
     fn new(threads: u32) -> Result<Self>
     where
         Self: Sized;
+    /// Run a job on the pool. Jobs spawned after `shutdown` are dropped
+    /// without running.
     fn spawn<F>(&self, job: F)
     where
         F: FnOnce() + Send + 'static;
+    /// Stop accepting jobs. Jobs already spawned still run.
+    fn shutdown(&self);
+    /// Shut down, then wait for every spawned job to finish and every worker
+    /// thread to exit. Dropping a pool joins it.
+    fn join(&self);
 }
 
-pub struct RayonThreadPool(rayon::ThreadPool);
+pub struct RayonThreadPool {
+    pool: RwLock<Option<rayon::ThreadPool>>,
+    /// Jobs spawned but not yet finished.
+    pending: Arc<Counter>,
+    /// Worker threads that have not exited.
+    workers: Arc<Counter>,
+}
 
 impl ThreadPool for RayonThreadPool {
     fn new(threads: u32) -> Result<Self> {
-        Ok(RayonThreadPool(
-            rayon::ThreadPoolBuilder::new()
-                .num_threads(threads as usize)
-                .build()
-                .map_err(|err| err.to_string())?,
-        ))
+        let workers = Arc::new(Counter::default());
+        let exited = workers.clone();
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads as usize)
+            .exit_handler(move |_| exited.sub(1))
+            .build()
+            .map_err(|err| KvsError::ThreadPool(err.to_string()))?;
+        workers.add(pool.current_num_threads());
+        Ok(RayonThreadPool {
+            pool: RwLock::new(Some(pool)),
+            pending: Arc::new(Counter::default()),
+            workers,
+        })
     }
 
     fn spawn<F>(&self, job: F)
     where
         F: FnOnce() + Send + 'static,
     {
-        self.0.spawn(job);
+        match self.pool.read().unwrap().as_ref() {
+            Some(pool) => {
+                let pending = self.pending.clone();
+                pending.add(1);
+                pool.spawn(move || {
+                    job();
+                    pending.sub(1);
+                });
+            }
+            None => error!("Job spawned after shutdown"),
+        }
+    }
+
+    fn shutdown(&self) {
+        // Rayon keeps running spawned jobs after the pool is dropped and
+        // stops the workers once they are done.
+        self.pool.write().unwrap().take();
+    }
+
+    fn join(&self) {
+        self.shutdown();
+        self.pending.wait_for_zero();
+        self.workers.wait_for_zero();
+    }
+}
+
+impl Drop for RayonThreadPool {
+    fn drop(&mut self) {
+        self.join();
+    }
+}
+
+/// A count that can be waited on until it drops to zero.
+#[derive(Default)]
+struct Counter {
+    count: Mutex<usize>,
+    changed: Condvar,
+}
+
+impl Counter {
+    fn add(&self, n: usize) {
+        *self.count.lock().unwrap() += n;
+    }
+
+    fn sub(&self, n: usize) {
+        *self.count.lock().unwrap() -= n;
+        self.changed.notify_all();
+    }
+
+    /// Wait until the count is below `limit`, then add one to it.
+    fn acquire(&self, limit: usize) {
+        let count = self.count.lock().unwrap();
+        *self
+            .changed
+            .wait_while(count, |count| *count >= limit)
+            .unwrap() += 1;
+    }
+
+    fn wait_for_zero(&self) {
+        let count = self.count.lock().unwrap();
+        drop(self.changed.wait_while(count, |count| *count > 0).unwrap());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn check_join_runs_every_job<P: ThreadPool>() {
+        let pool = P::new(4).unwrap();
+        let done = Arc::new(AtomicUsize::new(0));
+        for _ in 0..100 {
+            let done = done.clone();
+            pool.spawn(move || {
+                done.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        pool.join();
+        assert_eq!(done.load(Ordering::SeqCst), 100);
+
+        let late = done.clone();
+        pool.spawn(move || {
+            late.fetch_add(1, Ordering::SeqCst);
+        });
+        pool.join();
+        assert_eq!(done.load(Ordering::SeqCst), 100);
+    }
+
+    #[test]
+    fn test_join_runs_every_job() {
+        check_join_runs_every_job::<NaiveThreadPool>();
+        check_join_runs_every_job::<SharedQueueThreadPool>();
+        check_join_runs_every_job::<RayonThreadPool>();
+    }
+
+    #[test]
+    fn test_naive_shutdown_does_not_wait_for_a_free_thread() {
+        let pool = NaiveThreadPool::new(1).unwrap();
+        let (release, released) = crossbeam::channel::bounded::<()>(0);
+        pool.spawn(move || {
+            let _ = released.recv();
+        });
+        std::thread::scope(|scope| {
+            // Blocks until the first job is released.
+            scope.spawn(|| pool.spawn(|| {}));
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            pool.shutdown();
+            release.send(()).unwrap();
+        });
+        pool.join();
     }
 }
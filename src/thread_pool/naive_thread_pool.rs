@@ -1,9 +1,21 @@
-use std::{sync::Mutex, thread};
+use log::error;
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    thread,
+};
 
+use super::Counter;
 use crate::{thread_pool::ThreadPool, Result};
 pub struct NaiveThreadPool {
     threads: u32,
     children: Mutex<Vec<thread::JoinHandle<()>>>,
+    /// Threads whose job has not finished.
+    running: Arc<Counter>,
+    /// Set under the `children` lock once the pool is shut down.
+    closed: AtomicBool,
 }
 
 impl ThreadPool for NaiveThreadPool {
@@ -11,6 +23,8 @@ impl ThreadPool for NaiveThreadPool {
         Ok(NaiveThreadPool {
             threads,
             children: Mutex::new(vec![]),
+            running: Arc::new(Counter::default()),
+            closed: AtomicBool::new(false),
         })
     }
 
@@ -18,29 +32,59 @@ impl ThreadPool for NaiveThreadPool {
     where
         F: FnOnce() + Send + 'static,
     {
-        loop {
-            match self.children.try_lock() {
-                Ok(mut children) => {
-                    if children.len() < self.threads as usize {
-                        children.push(thread::spawn(job));
-                        break;
-                    } else {
-                        let mut i = 0;
-                        while i < children.len() {
-                            match children[i].is_finished() {
-                                true => {
-                                    children.remove(i);
-                                }
-                                false => {
-                                    i += 1;
-                                }
-                            }
-                        }
-                    }
-                }
-                Err(std::sync::TryLockError::WouldBlock) => continue,
-                Err(_) => panic!("Poisoned lock"),
+        // Sleep until a thread is free rather than polling for one. This is
+        // done before taking the `children` lock, so `shutdown` and `join`
+        // never wait for a busy pool.
+        self.running.acquire(self.threads as usize);
+        let slot = Slot(self.running.clone());
+        let mut children = self.lock_children();
+        if self.closed.load(Ordering::SeqCst) {
+            error!("Job spawned after shutdown");
+            return;
+        }
+        children.retain(|child| !child.is_finished());
+        children.push(thread::spawn(move || {
+            let _slot = slot;
+            job();
+        }));
+    }
+
+    fn shutdown(&self) {
+        let _children = self.lock_children();
+        self.closed.store(true, Ordering::SeqCst);
+    }
+
+    fn join(&self) {
+        let children = {
+            let mut children = self.lock_children();
+            self.closed.store(true, Ordering::SeqCst);
+            std::mem::take(&mut *children)
+        };
+        for child in children {
+            if child.join().is_err() {
+                error!("Thread panicked");
             }
         }
     }
 }
+
+impl NaiveThreadPool {
+    fn lock_children(&self) -> MutexGuard<'_, Vec<thread::JoinHandle<()>>> {
+        self.children.lock().expect("Poisoned lock")
+    }
+}
+
+/// Frees the thread of a job once it is done, even if the job panics.
+struct Slot(Arc<Counter>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.sub(1);
+    }
+}
+
+impl Drop for NaiveThreadPool {
+    fn drop(&mut self) {
+        self.join();
+    }
+}
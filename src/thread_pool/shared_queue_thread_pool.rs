@@ -1,7 +1,7 @@
-#![allow(unused)]
 use log::error;
 use std::{
     panic::{catch_unwind, AssertUnwindSafe},
+    sync::{Mutex, RwLock},
     thread,
 };
 
@@ -9,48 +9,61 @@ use crossbeam::channel::Sender;
 
 use crate::{thread_pool::ThreadPool, Result};
 
-enum ThreadPoolMessage {
-    RunJob(Box<dyn FnOnce() + Send + 'static>),
-    Shutdown,
-}
+type Job = Box<dyn FnOnce() + Send + 'static>;
 
 pub struct SharedQueueThreadPool {
-    sender: Sender<ThreadPoolMessage>,
+    /// Dropped on shutdown, so workers stop once the queue is empty.
+    sender: RwLock<Option<Sender<Job>>>,
+    workers: Mutex<Vec<thread::JoinHandle<()>>>,
 }
 
 impl ThreadPool for SharedQueueThreadPool {
     fn new(threads: u32) -> Result<Self> {
-        let (sender, receiver) = crossbeam::channel::unbounded();
+        let (sender, receiver) = crossbeam::channel::unbounded::<Job>();
+        let mut workers = Vec::with_capacity(threads as usize);
         for _ in 0..threads {
             let receiver = receiver.clone();
-            thread::spawn(move || loop {
-                match receiver.recv() {
-                    Ok(ThreadPoolMessage::RunJob(job)) => {
-                        match catch_unwind(AssertUnwindSafe(|| job())) {
-                            Ok(_) => (),
-                            Err(err) => error!("Thread panicked: {:?}", err),
-                        }
+            workers.push(thread::spawn(move || {
+                for job in receiver {
+                    if let Err(err) = catch_unwind(AssertUnwindSafe(job)) {
+                        error!("Thread panicked: {:?}", err);
                     }
-                    Ok(ThreadPoolMessage::Shutdown) => break,
-                    Err(_) => break,
                 }
-            });
+            }));
         }
-        Ok(SharedQueueThreadPool { sender })
+        Ok(SharedQueueThreadPool {
+            sender: RwLock::new(Some(sender)),
+            workers: Mutex::new(workers),
+        })
     }
 
     fn spawn<F>(&self, job: F)
     where
         F: FnOnce() + Send + 'static,
     {
-        self.sender
-            .send(ThreadPoolMessage::RunJob(Box::new(job)))
-            .unwrap();
+        match self.sender.read().unwrap().as_ref() {
+            Some(sender) => sender.send(Box::new(job)).unwrap(),
+            None => error!("Job spawned after shutdown"),
+        }
     }
-}
 
-impl SharedQueueThreadPool {
     fn shutdown(&self) {
-        while self.sender.send(ThreadPoolMessage::Shutdown).is_ok() {}
+        self.sender.write().unwrap().take();
+    }
+
+    fn join(&self) {
+        self.shutdown();
+        let workers = std::mem::take(&mut *self.workers.lock().unwrap());
+        for worker in workers {
+            if worker.join().is_err() {
+                error!("Worker thread panicked");
+            }
+        }
+    }
+}
+
+impl Drop for SharedQueueThreadPool {
+    fn drop(&mut self) {
+        self.join();
     }
 }